@@ -0,0 +1,1829 @@
+//! Fixed-layout little-endian binary protocol for order entry and market
+//! data, in the style of SBE.
+//!
+//! Every frame starts with a 6-byte Simple Open Framing Header and an 8-byte
+//! message header, followed by the message's fixed block and, for depth
+//! messages, two repeating groups:
+//!
+//! | Offset | Type | Field |
+//! |--------|------|-------|
+//! | 0 | u32 | frame length, including this header |
+//! | 4 | u16 | encoding type, `0xEB50` |
+//! | 6 | u16 | block length |
+//! | 8 | u16 | template id |
+//! | 10 | u16 | schema id, `1` |
+//! | 12 | u16 | schema version |
+//!
+//! Blocks are listed below as `offset type name`. Symbols and tokens are
+//! ASCII, zero-padded; decimals use `rust_decimal`'s 16-byte encoding.
+//! Decoders read the fields they know and skip to the block length they
+//! were sent, so a later schema version may append fields.
+//!
+//! Client to server:
+//! - 1 Logon: `0 [u8; 32] token`
+//! - 2 NewOrder: `0 u64 client_order_id`, `8 u64 price`, `16 u64 quantity`,
+//!   `24 u64 display_quantity` (0 = fully displayed), `32 u64 expire_time`
+//!   (good-till-date only), `40 [u8; 16] symbol`, `56 u8 side`,
+//!   `57 u8 order_type`, `58 u8 time_in_force`, `59 u8 self_trade_prevention`
+//! - 3 CancelOrder: `0 u64 order_id`, `8 [u8; 16] symbol`
+//! - 4 AmendOrder: `0 u64 order_id`, `8 u64 quantity`, `16 [u8; 16] symbol`
+//! - 5 ReplaceOrder: `0 u64 order_id`, `8 u64 price`, `16 u64 quantity`,
+//!   `24 [u8; 16] symbol`
+//! - 6 Subscribe, 7 Unsubscribe: `0 [u8; 16] symbol`, `16 u8 channels`
+//!   (bit 0 trades, bit 1 depth, bit 2 mark price)
+//!
+//! Server to client:
+//! - 101 LogonAccepted: `0 u64 user_id`
+//! - 102 SessionReject: `0 u8 code`
+//! - 103 OrderAck: `0 u64 client_order_id`, `8 u64 order_id`,
+//!   `16 [u8; 16] symbol`
+//! - 104 OrderPlaced: `0 u64 order_id`, `8 u64 price`, `16 u64 quantity`,
+//!   `24 u64 timestamp`, `32 [u8; 16] symbol`, `48 u8 side`
+//! - 105 OrderRejected: `0 u64 order_id`, `8 u64 timestamp`,
+//!   `16 [u8; 16] symbol`, `32 u8 reason`
+//! - 106 OrderCancelled: `0 u64 order_id`, `8 u64 remaining_quantity`,
+//!   `16 u64 timestamp`, `24 [u8; 16] symbol`
+//! - 107 OrderAmended: `0 u64 order_id`, `8 u64 quantity`, `16 u64 timestamp`,
+//!   `24 [u8; 16] symbol`
+//! - 108 OrderReplaced: `0 u64 order_id`, `8 u64 price`, `16 u64 quantity`,
+//!   `24 u64 timestamp`, `32 [u8; 16] symbol`
+//! - 109 Fill: `0 u64 order_id`, `8 u64 trade_id`, `16 u64 price`,
+//!   `24 u64 quantity`, `32 u64 timestamp`, `40 decimal fee`,
+//!   `56 [u8; 16] symbol`, `72 u8 side`, `73 u8 liquidity`
+//! - 201 Trade: `0 u64 trade_id`, `8 u64 price`, `16 u64 quantity`,
+//!   `24 u64 timestamp`, `32 [u8; 16] symbol`, `48 u8 aggressor_side`
+//! - 202 MarkPrice: `0 u64 timestamp`, `8 decimal mark_price`,
+//!   `24 [u8; 16] symbol`
+//! - 203 DepthSnapshot, 204 DepthUpdate: `0 u64 sequence`, `8 u64 timestamp`,
+//!   `16 [u8; 16] symbol`, then bid and ask groups, each a `u16` entry
+//!   length and `u16` count followed by `0 u64 price`, `8 u64 quantity`
+//!   entries. A quantity of zero in an update removes the level.
+//!
+//! Both directions:
+//! - 8 Heartbeat: empty block
+//!
+//! Encoders write into a caller-supplied buffer and decoders borrow from
+//! the input, so neither allocates.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use metrics::{counter, gauge};
+use rust_decimal::Decimal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::market_data::{DepthSnapshot, DepthUpdate};
+use crate::market_data_server::{
+    Channel, Forwarded, MarketDataHub, ServerMessage as FeedMessage, forward,
+};
+use crate::order_gateway::{GatewayMessage, GatewaySession, Liquidity, OrderGateway};
+use crate::titan::OrderCommand;
+use crate::types::{Order, OrderType, RejectReason, SelfTradePrevention, Side, TimeInForce};
+
+pub const ENCODING_TYPE: u16 = 0xEB50;
+pub const SCHEMA_ID: u16 = 1;
+pub const SCHEMA_VERSION: u16 = 1;
+/// Framing header plus message header.
+pub const HEADER_LENGTH: usize = 14;
+/// Largest frame a server accepts from a client.
+pub const MAX_CLIENT_FRAME: usize = 1024;
+
+const GROUP_HEADER_LENGTH: usize = 4;
+const LEVEL_LENGTH: usize = 16;
+/// Per-connection queue of market data messages.
+const OUTBOUND_CAPACITY: usize = 1024;
+
+pub mod template {
+    pub const LOGON: u16 = 1;
+    pub const NEW_ORDER: u16 = 2;
+    pub const CANCEL_ORDER: u16 = 3;
+    pub const AMEND_ORDER: u16 = 4;
+    pub const REPLACE_ORDER: u16 = 5;
+    pub const SUBSCRIBE: u16 = 6;
+    pub const UNSUBSCRIBE: u16 = 7;
+    pub const HEARTBEAT: u16 = 8;
+    pub const LOGON_ACCEPTED: u16 = 101;
+    pub const SESSION_REJECT: u16 = 102;
+    pub const ORDER_ACK: u16 = 103;
+    pub const ORDER_PLACED: u16 = 104;
+    pub const ORDER_REJECTED: u16 = 105;
+    pub const ORDER_CANCELLED: u16 = 106;
+    pub const ORDER_AMENDED: u16 = 107;
+    pub const ORDER_REPLACED: u16 = 108;
+    pub const FILL: u16 = 109;
+    pub const TRADE: u16 = 201;
+    pub const MARK_PRICE: u16 = 202;
+    pub const DEPTH_SNAPSHOT: u16 = 203;
+    pub const DEPTH_UPDATE: u16 = 204;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    /// The output buffer cannot hold the message.
+    BufferTooSmall {
+        needed: usize,
+    },
+    /// Wrong encoding type or schema, or a frame length that cannot hold
+    /// its own header.
+    BadHeader,
+    /// The frame exceeds the receiver's limit.
+    FrameTooLarge(usize),
+    UnknownTemplate(u16),
+    /// The block or a group is shorter than the schema requires.
+    Truncated,
+    /// An enum code or string field holds an invalid value.
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::BufferTooSmall { needed } => {
+                write!(f, "buffer too small, {needed} bytes needed")
+            }
+            WireError::BadHeader => f.write_str("bad message header"),
+            WireError::FrameTooLarge(len) => write!(f, "frame of {len} bytes too large"),
+            WireError::UnknownTemplate(id) => write!(f, "unknown template {id}"),
+            WireError::Truncated => f.write_str("message truncated"),
+            WireError::InvalidField(field) => write!(f, "invalid value for {field}"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Zero-padded ASCII field of `N` bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FixedStr<const N: usize>([u8; N]);
+
+pub type Symbol = FixedStr<16>;
+pub type Token = FixedStr<32>;
+
+impl<const N: usize> FixedStr<N> {
+    pub fn new(value: &str) -> Result<Self, WireError> {
+        if value.len() > N || !value.is_ascii() || value.contains('\0') {
+            return Err(WireError::InvalidField("string"));
+        }
+        let mut bytes = [0; N];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+        Ok(Self(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(N);
+        // Only ASCII is ever stored; see `new` and `read`.
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+
+    fn read(bytes: &[u8]) -> Result<Self, WireError> {
+        let bytes: [u8; N] = bytes.try_into().map_err(|_| WireError::Truncated)?;
+        if !bytes.is_ascii() {
+            return Err(WireError::InvalidField("string"));
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl<const N: usize> fmt::Debug for FixedStr<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Market data channels as a bit set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Channels(pub u8);
+
+impl Channels {
+    pub const TRADES: Channels = Channels(1);
+    pub const DEPTH: Channels = Channels(2);
+    pub const MARK_PRICE: Channels = Channels(4);
+
+    pub fn contains(self, channel: Channel) -> bool {
+        self.0 & Self::of(channel).0 != 0
+    }
+
+    fn of(channel: Channel) -> Channels {
+        match channel {
+            Channel::Trades => Self::TRADES,
+            Channel::Depth => Self::DEPTH,
+            Channel::MarkPrice => Self::MARK_PRICE,
+        }
+    }
+}
+
+/// Why the server refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionError {
+    NotLoggedOn = 1,
+    AuthenticationFailed = 2,
+    AlreadyLoggedOn = 3,
+    UnknownSymbol = 4,
+    Malformed = 5,
+    /// The connection fell behind on its private messages.
+    SlowConsumer = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NewOrder {
+    /// The client's own reference, echoed in the `OrderAck`.
+    pub client_order_id: u64,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub price: u64,
+    pub quantity: u64,
+    pub display_quantity: Option<u64>,
+    pub self_trade_prevention: SelfTradePrevention,
+}
+
+impl NewOrder {
+    /// The order for `user_id`. The gateway assigns its id and timestamp.
+    pub fn to_order(&self, user_id: u64) -> Order {
+        Order {
+            order_id: 0,
+            user_id,
+            symbol: self.symbol.as_str().to_string(),
+            side: self.side,
+            price: self.price,
+            quantity: self.quantity,
+            timestamp: 0,
+            order_type: self.order_type,
+            time_in_force: self.time_in_force,
+            display_quantity: self.display_quantity,
+            self_trade_prevention: self.self_trade_prevention,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientMessage {
+    Logon {
+        token: Token,
+    },
+    NewOrder(NewOrder),
+    CancelOrder {
+        order_id: u64,
+        symbol: Symbol,
+    },
+    AmendOrder {
+        order_id: u64,
+        symbol: Symbol,
+        quantity: u64,
+    },
+    ReplaceOrder {
+        order_id: u64,
+        symbol: Symbol,
+        price: u64,
+        quantity: u64,
+    },
+    Subscribe {
+        symbol: Symbol,
+        channels: Channels,
+    },
+    Unsubscribe {
+        symbol: Symbol,
+        channels: Channels,
+    },
+    Heartbeat,
+}
+
+/// Price levels, either borrowed from the book being encoded or read in
+/// place from a received group.
+#[derive(Debug, Clone, Copy)]
+pub enum Levels<'a> {
+    Slice(&'a [(u64, u64)]),
+    Encoded {
+        bytes: &'a [u8],
+        stride: usize,
+        count: usize,
+    },
+}
+
+impl<'a> Levels<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Levels::Slice(levels) => levels.len(),
+            Levels::Encoded { count, .. } => *count,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let this = *self;
+        (0..this.len()).map(move |i| match this {
+            Levels::Slice(levels) => levels[i],
+            Levels::Encoded { bytes, stride, .. } => {
+                let entry = &bytes[i * stride..];
+                (get_u64(entry, 0), get_u64(entry, 8))
+            }
+        })
+    }
+}
+
+impl PartialEq for Levels<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Depth<'a> {
+    pub symbol: Symbol,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub bids: Levels<'a>,
+    pub asks: Levels<'a>,
+}
+
+impl<'a> Depth<'a> {
+    fn of(
+        symbol: &str,
+        sequence: u64,
+        timestamp: u64,
+        bids: &'a [(u64, u64)],
+        asks: &'a [(u64, u64)],
+    ) -> Option<Self> {
+        Some(Self {
+            symbol: Symbol::new(symbol).ok()?,
+            sequence,
+            timestamp,
+            bids: Levels::Slice(bids),
+            asks: Levels::Slice(asks),
+        })
+    }
+
+    pub fn from_snapshot(snapshot: &'a DepthSnapshot) -> Option<Self> {
+        Self::of(
+            &snapshot.symbol,
+            snapshot.sequence,
+            snapshot.timestamp,
+            &snapshot.bids,
+            &snapshot.asks,
+        )
+    }
+
+    pub fn from_update(update: &'a DepthUpdate) -> Option<Self> {
+        Self::of(
+            &update.symbol,
+            update.sequence,
+            update.timestamp,
+            &update.bids,
+            &update.asks,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerMessage<'a> {
+    LogonAccepted {
+        user_id: u64,
+    },
+    SessionReject {
+        code: SessionError,
+    },
+    OrderAck {
+        client_order_id: u64,
+        order_id: u64,
+        symbol: Symbol,
+    },
+    OrderPlaced {
+        order_id: u64,
+        symbol: Symbol,
+        side: Side,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    OrderRejected {
+        order_id: u64,
+        symbol: Symbol,
+        reason: RejectReason,
+        timestamp: u64,
+    },
+    OrderCancelled {
+        order_id: u64,
+        symbol: Symbol,
+        remaining_quantity: u64,
+        timestamp: u64,
+    },
+    OrderAmended {
+        order_id: u64,
+        symbol: Symbol,
+        quantity: u64,
+        timestamp: u64,
+    },
+    OrderReplaced {
+        order_id: u64,
+        symbol: Symbol,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    Fill {
+        order_id: u64,
+        trade_id: u64,
+        symbol: Symbol,
+        side: Side,
+        liquidity: Liquidity,
+        price: u64,
+        quantity: u64,
+        fee: Decimal,
+        timestamp: u64,
+    },
+    Trade {
+        trade_id: u64,
+        symbol: Symbol,
+        aggressor_side: Side,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    MarkPrice {
+        symbol: Symbol,
+        mark_price: Decimal,
+        timestamp: u64,
+    },
+    DepthSnapshot(Depth<'a>),
+    DepthUpdate(Depth<'a>),
+    Heartbeat,
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn get_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn get_decimal(buf: &[u8], offset: usize) -> Decimal {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&buf[offset..offset + 16]);
+    Decimal::deserialize(bytes)
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_str<const N: usize>(buf: &mut [u8], offset: usize, value: &FixedStr<N>) {
+    buf[offset..offset + N].copy_from_slice(&value.0);
+}
+
+fn side_code(side: Side) -> u8 {
+    match side {
+        Side::Buy => 0,
+        Side::Sell => 1,
+    }
+}
+
+fn side_from(code: u8) -> Result<Side, WireError> {
+    match code {
+        0 => Ok(Side::Buy),
+        1 => Ok(Side::Sell),
+        _ => Err(WireError::InvalidField("side")),
+    }
+}
+
+fn liquidity_code(liquidity: Liquidity) -> u8 {
+    match liquidity {
+        Liquidity::Maker => 0,
+        Liquidity::Taker => 1,
+    }
+}
+
+fn liquidity_from(code: u8) -> Result<Liquidity, WireError> {
+    match code {
+        0 => Ok(Liquidity::Maker),
+        1 => Ok(Liquidity::Taker),
+        _ => Err(WireError::InvalidField("liquidity")),
+    }
+}
+
+/// Time in force code and expiry time.
+fn time_in_force_code(time_in_force: TimeInForce) -> (u8, u64) {
+    match time_in_force {
+        TimeInForce::GoodTillCancel => (0, 0),
+        TimeInForce::GoodTillDate(expiry) => (1, expiry),
+        TimeInForce::ImmediateOrCancel => (2, 0),
+        TimeInForce::FillOrKill => (3, 0),
+        TimeInForce::PostOnly => (4, 0),
+        TimeInForce::PostOnlySlide => (5, 0),
+    }
+}
+
+fn time_in_force_from(code: u8, expiry: u64) -> Result<TimeInForce, WireError> {
+    Ok(match code {
+        0 => TimeInForce::GoodTillCancel,
+        1 => TimeInForce::GoodTillDate(expiry),
+        2 => TimeInForce::ImmediateOrCancel,
+        3 => TimeInForce::FillOrKill,
+        4 => TimeInForce::PostOnly,
+        5 => TimeInForce::PostOnlySlide,
+        _ => return Err(WireError::InvalidField("time_in_force")),
+    })
+}
+
+const SELF_TRADE_PREVENTION: [SelfTradePrevention; 5] = [
+    SelfTradePrevention::None,
+    SelfTradePrevention::CancelNewest,
+    SelfTradePrevention::CancelOldest,
+    SelfTradePrevention::CancelBoth,
+    SelfTradePrevention::DecrementAndCancel,
+];
+
+/// Reject reasons in code order, starting at 1.
+const REJECT_REASONS: [RejectReason; 20] = [
+    RejectReason::ZeroQuantity,
+    RejectReason::OffTick,
+    RejectReason::OffLot,
+    RejectReason::BelowMinNotional,
+    RejectReason::UnknownSymbol,
+    RejectReason::UnknownOrder,
+    RejectReason::InvalidQuantity,
+    RejectReason::InvalidTimeInForce,
+    RejectReason::PostOnlyWouldCross,
+    RejectReason::PriceOutOfBand,
+    RejectReason::FillOrKillUnfillable,
+    RejectReason::Expired,
+    RejectReason::InsufficientMargin,
+    RejectReason::MarketClosed,
+    RejectReason::TradingHalted,
+    RejectReason::NotAllowedInAuction,
+    RejectReason::RateLimited,
+    RejectReason::Unauthorized,
+    RejectReason::DuplicateOrderId,
+    RejectReason::Unavailable,
+];
+
+fn reject_reason_code(reason: RejectReason) -> u8 {
+    REJECT_REASONS
+        .iter()
+        .position(|&r| r == reason)
+        .map_or(0, |i| i as u8 + 1)
+}
+
+fn reject_reason_from(code: u8) -> Result<RejectReason, WireError> {
+    REJECT_REASONS
+        .get((code as usize).wrapping_sub(1))
+        .copied()
+        .ok_or(WireError::InvalidField("reason"))
+}
+
+fn session_error_from(code: u8) -> Result<SessionError, WireError> {
+    Ok(match code {
+        1 => SessionError::NotLoggedOn,
+        2 => SessionError::AuthenticationFailed,
+        3 => SessionError::AlreadyLoggedOn,
+        4 => SessionError::UnknownSymbol,
+        5 => SessionError::Malformed,
+        6 => SessionError::SlowConsumer,
+        _ => return Err(WireError::InvalidField("code")),
+    })
+}
+
+/// Writes the framing and message headers for a frame of `len` bytes.
+fn put_header(buf: &mut [u8], len: usize, block_length: usize, template_id: u16) {
+    put_u32(buf, 0, len as u32);
+    put_u16(buf, 4, ENCODING_TYPE);
+    put_u16(buf, 6, block_length as u16);
+    put_u16(buf, 8, template_id);
+    put_u16(buf, 10, SCHEMA_ID);
+    put_u16(buf, 12, SCHEMA_VERSION);
+}
+
+/// A complete frame split at its block.
+struct Frame<'a> {
+    template_id: u16,
+    block: &'a [u8],
+    /// Bytes after the block, i.e. any repeating groups.
+    groups: &'a [u8],
+    len: usize,
+}
+
+/// The first frame in `buf`, or `None` if it is not complete yet.
+fn frame(buf: &[u8], max_frame: usize) -> Result<Option<Frame<'_>>, WireError> {
+    if buf.len() < 6 {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len < HEADER_LENGTH || get_u16(buf, 4) != ENCODING_TYPE {
+        return Err(WireError::BadHeader);
+    }
+    if len > max_frame {
+        return Err(WireError::FrameTooLarge(len));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let block_length = get_u16(buf, 6) as usize;
+    if get_u16(buf, 10) != SCHEMA_ID || HEADER_LENGTH + block_length > len {
+        return Err(WireError::BadHeader);
+    }
+    Ok(Some(Frame {
+        template_id: get_u16(buf, 8),
+        block: &buf[HEADER_LENGTH..HEADER_LENGTH + block_length],
+        groups: &buf[HEADER_LENGTH + block_length..len],
+        len,
+    }))
+}
+
+/// Checks that a received block holds at least the `len` bytes this schema
+/// version defines.
+fn require(block: &[u8], len: usize) -> Result<&[u8], WireError> {
+    if block.len() < len {
+        return Err(WireError::Truncated);
+    }
+    Ok(block)
+}
+
+impl ClientMessage {
+    fn layout(&self) -> (u16, usize) {
+        match self {
+            ClientMessage::Logon { .. } => (template::LOGON, 32),
+            ClientMessage::NewOrder(_) => (template::NEW_ORDER, 60),
+            ClientMessage::CancelOrder { .. } => (template::CANCEL_ORDER, 24),
+            ClientMessage::AmendOrder { .. } => (template::AMEND_ORDER, 32),
+            ClientMessage::ReplaceOrder { .. } => (template::REPLACE_ORDER, 40),
+            ClientMessage::Subscribe { .. } => (template::SUBSCRIBE, 17),
+            ClientMessage::Unsubscribe { .. } => (template::UNSUBSCRIBE, 17),
+            ClientMessage::Heartbeat => (template::HEARTBEAT, 0),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LENGTH + self.layout().1
+    }
+
+    /// Writes the frame to the start of `buf` and returns its length.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let (template_id, block_length) = self.layout();
+        let len = HEADER_LENGTH + block_length;
+        if buf.len() < len {
+            return Err(WireError::BufferTooSmall { needed: len });
+        }
+        put_header(buf, len, block_length, template_id);
+        let block = &mut buf[HEADER_LENGTH..len];
+        match self {
+            ClientMessage::Logon { token } => put_str(block, 0, token),
+            ClientMessage::NewOrder(order) => {
+                let (time_in_force, expire_time) = time_in_force_code(order.time_in_force);
+                put_u64(block, 0, order.client_order_id);
+                put_u64(block, 8, order.price);
+                put_u64(block, 16, order.quantity);
+                put_u64(block, 24, order.display_quantity.unwrap_or(0));
+                put_u64(block, 32, expire_time);
+                put_str(block, 40, &order.symbol);
+                block[56] = side_code(order.side);
+                block[57] = match order.order_type {
+                    OrderType::Limit => 0,
+                    OrderType::Market => 1,
+                };
+                block[58] = time_in_force;
+                block[59] = SELF_TRADE_PREVENTION
+                    .iter()
+                    .position(|&mode| mode == order.self_trade_prevention)
+                    .unwrap_or(0) as u8;
+            }
+            ClientMessage::CancelOrder { order_id, symbol } => {
+                put_u64(block, 0, *order_id);
+                put_str(block, 8, symbol);
+            }
+            ClientMessage::AmendOrder {
+                order_id,
+                symbol,
+                quantity,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *quantity);
+                put_str(block, 16, symbol);
+            }
+            ClientMessage::ReplaceOrder {
+                order_id,
+                symbol,
+                price,
+                quantity,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *price);
+                put_u64(block, 16, *quantity);
+                put_str(block, 24, symbol);
+            }
+            ClientMessage::Subscribe { symbol, channels }
+            | ClientMessage::Unsubscribe { symbol, channels } => {
+                put_str(block, 0, symbol);
+                block[16] = channels.0;
+            }
+            ClientMessage::Heartbeat => {}
+        }
+        Ok(len)
+    }
+
+    /// Decodes the first frame in `buf`, returning the message and the
+    /// frame length, or `None` if the frame is not complete yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, WireError> {
+        let Some(Frame {
+            template_id,
+            block,
+            len,
+            ..
+        }) = frame(buf, MAX_CLIENT_FRAME)?
+        else {
+            return Ok(None);
+        };
+        let message = match template_id {
+            template::LOGON => ClientMessage::Logon {
+                token: Token::read(&require(block, 32)?[..32])?,
+            },
+            template::NEW_ORDER => {
+                let block = require(block, 60)?;
+                let display_quantity = get_u64(block, 24);
+                ClientMessage::NewOrder(NewOrder {
+                    client_order_id: get_u64(block, 0),
+                    price: get_u64(block, 8),
+                    quantity: get_u64(block, 16),
+                    display_quantity: (display_quantity != 0).then_some(display_quantity),
+                    symbol: Symbol::read(&block[40..56])?,
+                    side: side_from(block[56])?,
+                    order_type: match block[57] {
+                        0 => OrderType::Limit,
+                        1 => OrderType::Market,
+                        _ => return Err(WireError::InvalidField("order_type")),
+                    },
+                    time_in_force: time_in_force_from(block[58], get_u64(block, 32))?,
+                    self_trade_prevention: *SELF_TRADE_PREVENTION
+                        .get(block[59] as usize)
+                        .ok_or(WireError::InvalidField("self_trade_prevention"))?,
+                })
+            }
+            template::CANCEL_ORDER => {
+                let block = require(block, 24)?;
+                ClientMessage::CancelOrder {
+                    order_id: get_u64(block, 0),
+                    symbol: Symbol::read(&block[8..24])?,
+                }
+            }
+            template::AMEND_ORDER => {
+                let block = require(block, 32)?;
+                ClientMessage::AmendOrder {
+                    order_id: get_u64(block, 0),
+                    quantity: get_u64(block, 8),
+                    symbol: Symbol::read(&block[16..32])?,
+                }
+            }
+            template::REPLACE_ORDER => {
+                let block = require(block, 40)?;
+                ClientMessage::ReplaceOrder {
+                    order_id: get_u64(block, 0),
+                    price: get_u64(block, 8),
+                    quantity: get_u64(block, 16),
+                    symbol: Symbol::read(&block[24..40])?,
+                }
+            }
+            template::SUBSCRIBE | template::UNSUBSCRIBE => {
+                let block = require(block, 17)?;
+                let symbol = Symbol::read(&block[..16])?;
+                let channels = Channels(block[16]);
+                if template_id == template::SUBSCRIBE {
+                    ClientMessage::Subscribe { symbol, channels }
+                } else {
+                    ClientMessage::Unsubscribe { symbol, channels }
+                }
+            }
+            template::HEARTBEAT => ClientMessage::Heartbeat,
+            other => return Err(WireError::UnknownTemplate(other)),
+        };
+        Ok(Some((message, len)))
+    }
+}
+
+impl<'a> ServerMessage<'a> {
+    fn layout(&self) -> (u16, usize) {
+        match self {
+            ServerMessage::LogonAccepted { .. } => (template::LOGON_ACCEPTED, 8),
+            ServerMessage::SessionReject { .. } => (template::SESSION_REJECT, 1),
+            ServerMessage::OrderAck { .. } => (template::ORDER_ACK, 32),
+            ServerMessage::OrderPlaced { .. } => (template::ORDER_PLACED, 49),
+            ServerMessage::OrderRejected { .. } => (template::ORDER_REJECTED, 33),
+            ServerMessage::OrderCancelled { .. } => (template::ORDER_CANCELLED, 40),
+            ServerMessage::OrderAmended { .. } => (template::ORDER_AMENDED, 40),
+            ServerMessage::OrderReplaced { .. } => (template::ORDER_REPLACED, 48),
+            ServerMessage::Fill { .. } => (template::FILL, 74),
+            ServerMessage::Trade { .. } => (template::TRADE, 49),
+            ServerMessage::MarkPrice { .. } => (template::MARK_PRICE, 40),
+            ServerMessage::DepthSnapshot(_) => (template::DEPTH_SNAPSHOT, 32),
+            ServerMessage::DepthUpdate(_) => (template::DEPTH_UPDATE, 32),
+            ServerMessage::Heartbeat => (template::HEARTBEAT, 0),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        let groups = match self {
+            ServerMessage::DepthSnapshot(depth) | ServerMessage::DepthUpdate(depth) => {
+                2 * GROUP_HEADER_LENGTH + (depth.bids.len() + depth.asks.len()) * LEVEL_LENGTH
+            }
+            _ => 0,
+        };
+        HEADER_LENGTH + self.layout().1 + groups
+    }
+
+    /// Writes the frame to the start of `buf` and returns its length.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let (template_id, block_length) = self.layout();
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(WireError::BufferTooSmall { needed: len });
+        }
+        if let ServerMessage::DepthSnapshot(depth) | ServerMessage::DepthUpdate(depth) = self
+            && (depth.bids.len() > u16::MAX as usize || depth.asks.len() > u16::MAX as usize)
+        {
+            return Err(WireError::InvalidField("levels"));
+        }
+        put_header(buf, len, block_length, template_id);
+        let block = &mut buf[HEADER_LENGTH..len];
+        match self {
+            ServerMessage::LogonAccepted { user_id } => put_u64(block, 0, *user_id),
+            ServerMessage::SessionReject { code } => block[0] = *code as u8,
+            ServerMessage::OrderAck {
+                client_order_id,
+                order_id,
+                symbol,
+            } => {
+                put_u64(block, 0, *client_order_id);
+                put_u64(block, 8, *order_id);
+                put_str(block, 16, symbol);
+            }
+            ServerMessage::OrderPlaced {
+                order_id,
+                symbol,
+                side,
+                price,
+                quantity,
+                timestamp,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *price);
+                put_u64(block, 16, *quantity);
+                put_u64(block, 24, *timestamp);
+                put_str(block, 32, symbol);
+                block[48] = side_code(*side);
+            }
+            ServerMessage::OrderRejected {
+                order_id,
+                symbol,
+                reason,
+                timestamp,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *timestamp);
+                put_str(block, 16, symbol);
+                block[32] = reject_reason_code(*reason);
+            }
+            ServerMessage::OrderCancelled {
+                order_id,
+                symbol,
+                remaining_quantity: quantity,
+                timestamp,
+            }
+            | ServerMessage::OrderAmended {
+                order_id,
+                symbol,
+                quantity,
+                timestamp,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *quantity);
+                put_u64(block, 16, *timestamp);
+                put_str(block, 24, symbol);
+            }
+            ServerMessage::OrderReplaced {
+                order_id,
+                symbol,
+                price,
+                quantity,
+                timestamp,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *price);
+                put_u64(block, 16, *quantity);
+                put_u64(block, 24, *timestamp);
+                put_str(block, 32, symbol);
+            }
+            ServerMessage::Fill {
+                order_id,
+                trade_id,
+                symbol,
+                side,
+                liquidity,
+                price,
+                quantity,
+                fee,
+                timestamp,
+            } => {
+                put_u64(block, 0, *order_id);
+                put_u64(block, 8, *trade_id);
+                put_u64(block, 16, *price);
+                put_u64(block, 24, *quantity);
+                put_u64(block, 32, *timestamp);
+                block[40..56].copy_from_slice(&fee.serialize());
+                put_str(block, 56, symbol);
+                block[72] = side_code(*side);
+                block[73] = liquidity_code(*liquidity);
+            }
+            ServerMessage::Trade {
+                trade_id,
+                symbol,
+                aggressor_side,
+                price,
+                quantity,
+                timestamp,
+            } => {
+                put_u64(block, 0, *trade_id);
+                put_u64(block, 8, *price);
+                put_u64(block, 16, *quantity);
+                put_u64(block, 24, *timestamp);
+                put_str(block, 32, symbol);
+                block[48] = side_code(*aggressor_side);
+            }
+            ServerMessage::MarkPrice {
+                symbol,
+                mark_price,
+                timestamp,
+            } => {
+                put_u64(block, 0, *timestamp);
+                block[8..24].copy_from_slice(&mark_price.serialize());
+                put_str(block, 24, symbol);
+            }
+            ServerMessage::DepthSnapshot(depth) | ServerMessage::DepthUpdate(depth) => {
+                put_u64(block, 0, depth.sequence);
+                put_u64(block, 8, depth.timestamp);
+                put_str(block, 16, &depth.symbol);
+                let mut offset = block_length;
+                for levels in [&depth.bids, &depth.asks] {
+                    put_u16(block, offset, LEVEL_LENGTH as u16);
+                    put_u16(block, offset + 2, levels.len() as u16);
+                    offset += GROUP_HEADER_LENGTH;
+                    for (price, quantity) in levels.iter() {
+                        put_u64(block, offset, price);
+                        put_u64(block, offset + 8, quantity);
+                        offset += LEVEL_LENGTH;
+                    }
+                }
+            }
+            ServerMessage::Heartbeat => {}
+        }
+        Ok(len)
+    }
+
+    /// Decodes the first frame in `buf`, returning the message and the
+    /// frame length, or `None` if the frame is not complete yet. Depth
+    /// levels are read in place from `buf`.
+    pub fn decode(buf: &'a [u8]) -> Result<Option<(Self, usize)>, WireError> {
+        let Some(Frame {
+            template_id,
+            block,
+            groups,
+            len,
+        }) = frame(buf, u32::MAX as usize)?
+        else {
+            return Ok(None);
+        };
+        let message = match template_id {
+            template::LOGON_ACCEPTED => ServerMessage::LogonAccepted {
+                user_id: get_u64(require(block, 8)?, 0),
+            },
+            template::SESSION_REJECT => ServerMessage::SessionReject {
+                code: session_error_from(require(block, 1)?[0])?,
+            },
+            template::ORDER_ACK => {
+                let block = require(block, 32)?;
+                ServerMessage::OrderAck {
+                    client_order_id: get_u64(block, 0),
+                    order_id: get_u64(block, 8),
+                    symbol: Symbol::read(&block[16..32])?,
+                }
+            }
+            template::ORDER_PLACED => {
+                let block = require(block, 49)?;
+                ServerMessage::OrderPlaced {
+                    order_id: get_u64(block, 0),
+                    price: get_u64(block, 8),
+                    quantity: get_u64(block, 16),
+                    timestamp: get_u64(block, 24),
+                    symbol: Symbol::read(&block[32..48])?,
+                    side: side_from(block[48])?,
+                }
+            }
+            template::ORDER_REJECTED => {
+                let block = require(block, 33)?;
+                ServerMessage::OrderRejected {
+                    order_id: get_u64(block, 0),
+                    timestamp: get_u64(block, 8),
+                    symbol: Symbol::read(&block[16..32])?,
+                    reason: reject_reason_from(block[32])?,
+                }
+            }
+            template::ORDER_CANCELLED => {
+                let block = require(block, 40)?;
+                ServerMessage::OrderCancelled {
+                    order_id: get_u64(block, 0),
+                    remaining_quantity: get_u64(block, 8),
+                    timestamp: get_u64(block, 16),
+                    symbol: Symbol::read(&block[24..40])?,
+                }
+            }
+            template::ORDER_AMENDED => {
+                let block = require(block, 40)?;
+                ServerMessage::OrderAmended {
+                    order_id: get_u64(block, 0),
+                    quantity: get_u64(block, 8),
+                    timestamp: get_u64(block, 16),
+                    symbol: Symbol::read(&block[24..40])?,
+                }
+            }
+            template::ORDER_REPLACED => {
+                let block = require(block, 48)?;
+                ServerMessage::OrderReplaced {
+                    order_id: get_u64(block, 0),
+                    price: get_u64(block, 8),
+                    quantity: get_u64(block, 16),
+                    timestamp: get_u64(block, 24),
+                    symbol: Symbol::read(&block[32..48])?,
+                }
+            }
+            template::FILL => {
+                let block = require(block, 74)?;
+                ServerMessage::Fill {
+                    order_id: get_u64(block, 0),
+                    trade_id: get_u64(block, 8),
+                    price: get_u64(block, 16),
+                    quantity: get_u64(block, 24),
+                    timestamp: get_u64(block, 32),
+                    fee: get_decimal(block, 40),
+                    symbol: Symbol::read(&block[56..72])?,
+                    side: side_from(block[72])?,
+                    liquidity: liquidity_from(block[73])?,
+                }
+            }
+            template::TRADE => {
+                let block = require(block, 49)?;
+                ServerMessage::Trade {
+                    trade_id: get_u64(block, 0),
+                    price: get_u64(block, 8),
+                    quantity: get_u64(block, 16),
+                    timestamp: get_u64(block, 24),
+                    symbol: Symbol::read(&block[32..48])?,
+                    aggressor_side: side_from(block[48])?,
+                }
+            }
+            template::MARK_PRICE => {
+                let block = require(block, 40)?;
+                ServerMessage::MarkPrice {
+                    timestamp: get_u64(block, 0),
+                    mark_price: get_decimal(block, 8),
+                    symbol: Symbol::read(&block[24..40])?,
+                }
+            }
+            template::DEPTH_SNAPSHOT | template::DEPTH_UPDATE => {
+                let block = require(block, 32)?;
+                let (bids, rest) = read_levels(groups)?;
+                let (asks, _) = read_levels(rest)?;
+                let depth = Depth {
+                    sequence: get_u64(block, 0),
+                    timestamp: get_u64(block, 8),
+                    symbol: Symbol::read(&block[16..32])?,
+                    bids,
+                    asks,
+                };
+                if template_id == template::DEPTH_SNAPSHOT {
+                    ServerMessage::DepthSnapshot(depth)
+                } else {
+                    ServerMessage::DepthUpdate(depth)
+                }
+            }
+            template::HEARTBEAT => ServerMessage::Heartbeat,
+            other => return Err(WireError::UnknownTemplate(other)),
+        };
+        Ok(Some((message, len)))
+    }
+}
+
+/// Reads one level group and returns it with the bytes that follow it.
+fn read_levels(buf: &[u8]) -> Result<(Levels<'_>, &[u8]), WireError> {
+    if buf.len() < GROUP_HEADER_LENGTH {
+        return Err(WireError::Truncated);
+    }
+    let stride = get_u16(buf, 0) as usize;
+    let count = get_u16(buf, 2) as usize;
+    let end = GROUP_HEADER_LENGTH + stride * count;
+    if stride < LEVEL_LENGTH || buf.len() < end {
+        return Err(WireError::Truncated);
+    }
+    let levels = Levels::Encoded {
+        bytes: &buf[GROUP_HEADER_LENGTH..end],
+        stride,
+        count,
+    };
+    Ok((levels, &buf[end..]))
+}
+
+/// The binary form of a private gateway message, if it has one. Positions
+/// and account updates are only published over WebSocket.
+fn private_message(message: &GatewayMessage) -> Option<ServerMessage<'static>> {
+    let symbol = |symbol: &str| Symbol::new(symbol).ok();
+    Some(match message {
+        GatewayMessage::OrderPlaced(order) => ServerMessage::OrderPlaced {
+            order_id: order.order_id,
+            symbol: symbol(&order.symbol)?,
+            side: order.side,
+            price: order.price,
+            quantity: order.quantity,
+            timestamp: order.timestamp,
+        },
+        GatewayMessage::Rejected {
+            order_id,
+            symbol: s,
+            reason,
+            timestamp,
+        } => ServerMessage::OrderRejected {
+            order_id: *order_id,
+            symbol: symbol(s)?,
+            reason: *reason,
+            timestamp: *timestamp,
+        },
+        GatewayMessage::Cancelled {
+            order_id,
+            symbol: s,
+            remaining_quantity,
+            timestamp,
+        } => ServerMessage::OrderCancelled {
+            order_id: *order_id,
+            symbol: symbol(s)?,
+            remaining_quantity: *remaining_quantity,
+            timestamp: *timestamp,
+        },
+        GatewayMessage::Amended {
+            order_id,
+            symbol: s,
+            quantity,
+            timestamp,
+        } => ServerMessage::OrderAmended {
+            order_id: *order_id,
+            symbol: symbol(s)?,
+            quantity: *quantity,
+            timestamp: *timestamp,
+        },
+        GatewayMessage::Replaced {
+            order_id,
+            symbol: s,
+            price,
+            quantity,
+            timestamp,
+        } => ServerMessage::OrderReplaced {
+            order_id: *order_id,
+            symbol: symbol(s)?,
+            price: *price,
+            quantity: *quantity,
+            timestamp: *timestamp,
+        },
+        GatewayMessage::Fill(fill) => ServerMessage::Fill {
+            order_id: fill.order_id,
+            trade_id: fill.trade_id,
+            symbol: symbol(&fill.symbol)?,
+            side: fill.side,
+            liquidity: fill.liquidity,
+            price: fill.price,
+            quantity: fill.quantity,
+            fee: fill.fee,
+            timestamp: fill.timestamp,
+        },
+        _ => return None,
+    })
+}
+
+/// TCP server speaking the binary protocol. Order entry requires a Logon
+/// and goes through the order gateway; market data subscriptions do not,
+/// and follow the same snapshot-then-delta rules as the WebSocket feed.
+pub struct BinaryServer {
+    gateway: Arc<OrderGateway>,
+    hub: Arc<MarketDataHub>,
+    heartbeat_interval: Duration,
+}
+
+impl BinaryServer {
+    pub fn new(
+        gateway: Arc<OrderGateway>,
+        hub: Arc<MarketDataHub>,
+        heartbeat_interval: Duration,
+    ) -> Self {
+        Self {
+            gateway,
+            hub,
+            heartbeat_interval,
+        }
+    }
+
+    /// Accepts connections until the listener fails.
+    pub async fn serve(self, addr: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("[Binary] Server listening on {}", listener.local_addr()?);
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                gauge!("binary.connections").increment(1.0);
+                let mut connection = Connection::new(&server, stream);
+                if let Err(e) = connection.run().await {
+                    eprintln!("[Binary] Connection {peer} failed: {e}");
+                }
+                connection.close();
+                gauge!("binary.connections").decrement(1.0);
+            });
+        }
+    }
+}
+
+/// One connection's interest in one symbol's feed.
+struct Subscription {
+    channels: Channels,
+    /// Sequence of the last depth message sent.
+    depth_sequence: u64,
+    forwarder: JoinHandle<()>,
+}
+
+struct Connection<'a> {
+    server: &'a BinaryServer,
+    reader: Option<tokio::net::tcp::OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    /// Reused for every outgoing frame; grows only for large snapshots.
+    out: Vec<u8>,
+    session: Option<GatewaySession>,
+    subscriptions: HashMap<String, Subscription>,
+    feed: mpsc::Receiver<Forwarded>,
+    feed_tx: mpsc::Sender<Forwarded>,
+}
+
+impl<'a> Connection<'a> {
+    fn new(server: &'a BinaryServer, stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (feed_tx, feed) = mpsc::channel(OUTBOUND_CAPACITY);
+        Self {
+            server,
+            reader: Some(reader),
+            writer,
+            out: vec![0; 4096],
+            session: None,
+            subscriptions: HashMap::new(),
+            feed,
+            feed_tx,
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(session) = &self.session {
+            self.server.gateway.close_session(session);
+        }
+        for subscription in self.subscriptions.values() {
+            subscription.forwarder.abort();
+        }
+    }
+
+    async fn send(&mut self, message: &ServerMessage<'_>) -> std::io::Result<()> {
+        let len = message.encoded_len();
+        if self.out.len() < len {
+            self.out.resize(len, 0);
+        }
+        message
+            .encode(&mut self.out)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.writer.write_all(&self.out[..len]).await
+    }
+
+    async fn reject(&mut self, code: SessionError) -> std::io::Result<()> {
+        counter!("binary.session_rejects").increment(1);
+        self.send(&ServerMessage::SessionReject { code }).await
+    }
+
+    async fn run(&mut self) -> std::io::Result<()> {
+        let Some(mut reader) = self.reader.take() else {
+            return Ok(());
+        };
+        let mut buffer = Vec::with_capacity(MAX_CLIENT_FRAME * 4);
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.server.heartbeat_interval,
+            self.server.heartbeat_interval,
+        );
+        loop {
+            let mut consumed = 0;
+            loop {
+                match ClientMessage::decode(&buffer[consumed..]) {
+                    Ok(Some((message, len))) => {
+                        consumed += len;
+                        self.on_request(message).await?;
+                    }
+                    Ok(None) => break,
+                    // Framing is lost; nothing after this can be trusted.
+                    Err(_) => return self.reject(SessionError::Malformed).await,
+                }
+            }
+            buffer.drain(..consumed);
+
+            let private = async {
+                match &mut self.session {
+                    Some(session) => session.messages.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                read = reader.read_buf(&mut buffer) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+                message = private => match message {
+                    Some(message) => {
+                        if let Some(reply) = private_message(&message) {
+                            self.send(&reply).await?;
+                        }
+                    }
+                    None => return self.reject(SessionError::SlowConsumer).await,
+                },
+                Some(forwarded) = self.feed.recv() => self.on_feed(forwarded).await?,
+                _ = heartbeat.tick() => self.send(&ServerMessage::Heartbeat).await?,
+            }
+        }
+    }
+
+    async fn on_request(&mut self, message: ClientMessage) -> std::io::Result<()> {
+        counter!("binary.messages_received").increment(1);
+        match message {
+            ClientMessage::Heartbeat => Ok(()),
+            ClientMessage::Logon { token } => {
+                if self.session.is_some() {
+                    return self.reject(SessionError::AlreadyLoggedOn).await;
+                }
+                let Some(user_id) = self.server.gateway.authenticate(token.as_str()) else {
+                    return self.reject(SessionError::AuthenticationFailed).await;
+                };
+                self.session = Some(self.server.gateway.open_session(user_id));
+                self.send(&ServerMessage::LogonAccepted { user_id }).await
+            }
+            ClientMessage::Subscribe { symbol, channels } => self.subscribe(symbol, channels).await,
+            ClientMessage::Unsubscribe { symbol, channels } => {
+                if let Some(subscription) = self.subscriptions.get_mut(symbol.as_str()) {
+                    subscription.channels.0 &= !channels.0;
+                    if subscription.channels.0 == 0 {
+                        subscription.forwarder.abort();
+                        self.subscriptions.remove(symbol.as_str());
+                    }
+                }
+                Ok(())
+            }
+            ClientMessage::NewOrder(order) => {
+                let Some(user_id) = self.session.as_ref().map(|s| s.user_id) else {
+                    return self.reject(SessionError::NotLoggedOn).await;
+                };
+                let reply = match self.server.gateway.submit(user_id, order.to_order(user_id)) {
+                    Ok(order_id) => ServerMessage::OrderAck {
+                        client_order_id: order.client_order_id,
+                        order_id,
+                        symbol: order.symbol,
+                    },
+                    Err(reason) => ServerMessage::OrderRejected {
+                        order_id: order.client_order_id,
+                        symbol: order.symbol,
+                        reason,
+                        timestamp: now_ms(),
+                    },
+                };
+                self.send(&reply).await
+            }
+            ClientMessage::CancelOrder { .. }
+            | ClientMessage::AmendOrder { .. }
+            | ClientMessage::ReplaceOrder { .. } => {
+                let Some(user_id) = self.session.as_ref().map(|s| s.user_id) else {
+                    return self.reject(SessionError::NotLoggedOn).await;
+                };
+                let (command, order_id, symbol) = command(message, user_id, now_ms());
+                // Titan answers accepted commands through the private events.
+                match self.server.gateway.send(user_id, command) {
+                    Ok(()) => Ok(()),
+                    Err(reason) => {
+                        let rejected = ServerMessage::OrderRejected {
+                            order_id,
+                            symbol,
+                            reason,
+                            timestamp: now_ms(),
+                        };
+                        self.send(&rejected).await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Subscribes to the symbol's stream first, then sends the state the
+    /// client starts from, as the WebSocket feed does.
+    async fn subscribe(&mut self, symbol: Symbol, channels: Channels) -> std::io::Result<()> {
+        let name = symbol.as_str();
+        if !self.subscriptions.contains_key(name) {
+            let Some(updates) = self.server.hub.subscribe(name) else {
+                return self.reject(SessionError::UnknownSymbol).await;
+            };
+            let subscription = Subscription {
+                channels: Channels::default(),
+                depth_sequence: 0,
+                forwarder: forward(name.to_string(), updates, self.feed_tx.clone()),
+            };
+            self.subscriptions.insert(name.to_string(), subscription);
+        }
+        let Some(subscription) = self.subscriptions.get_mut(name) else {
+            return Ok(());
+        };
+        let added = Channels(channels.0 & !subscription.channels.0);
+        subscription.channels.0 |= channels.0;
+
+        if added.contains(Channel::Depth)
+            && let Some(snapshot) = self.server.hub.depth_snapshot(name)
+        {
+            subscription.depth_sequence = snapshot.sequence;
+            if let Some(depth) = Depth::from_snapshot(&snapshot) {
+                self.send(&ServerMessage::DepthSnapshot(depth)).await?;
+            }
+        }
+        if added.contains(Channel::MarkPrice)
+            && let Some(update) = self.server.hub.mark_price(name)
+        {
+            let message = ServerMessage::MarkPrice {
+                symbol,
+                mark_price: update.mark_price,
+                timestamp: update.timestamp,
+            };
+            self.send(&message).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a feed message if this connection subscribed to it.
+    async fn on_feed(&mut self, forwarded: Forwarded) -> std::io::Result<()> {
+        let message = match forwarded {
+            Forwarded::Message(message) => message,
+            Forwarded::Lagged(symbol) => {
+                counter!("binary.resyncs").increment(1);
+                let Some(subscription) = self.subscriptions.get_mut(&symbol) else {
+                    return Ok(());
+                };
+                if !subscription.channels.contains(Channel::Depth) {
+                    return Ok(());
+                }
+                let Some(snapshot) = self.server.hub.depth_snapshot(&symbol) else {
+                    return Ok(());
+                };
+                subscription.depth_sequence = snapshot.sequence;
+                return match Depth::from_snapshot(&snapshot) {
+                    Some(depth) => self.send(&ServerMessage::DepthSnapshot(depth)).await,
+                    None => Ok(()),
+                };
+            }
+        };
+        let Some((channel, symbol)) = message.route() else {
+            return Ok(());
+        };
+        let Some(subscription) = self.subscriptions.get_mut(symbol) else {
+            return Ok(());
+        };
+        if !subscription.channels.contains(channel) {
+            return Ok(());
+        }
+        let encoded = match &*message {
+            FeedMessage::DepthUpdate(update) => {
+                if update.sequence <= subscription.depth_sequence {
+                    return Ok(());
+                }
+                subscription.depth_sequence = update.sequence;
+                Depth::from_update(update).map(ServerMessage::DepthUpdate)
+            }
+            FeedMessage::DepthSnapshot(snapshot) => {
+                subscription.depth_sequence = snapshot.sequence;
+                Depth::from_snapshot(snapshot).map(ServerMessage::DepthSnapshot)
+            }
+            FeedMessage::Trade(trade) => {
+                Symbol::new(&trade.symbol)
+                    .ok()
+                    .map(|symbol| ServerMessage::Trade {
+                        trade_id: trade.trade_id,
+                        symbol,
+                        aggressor_side: trade.aggressor_side,
+                        price: trade.price,
+                        quantity: trade.quantity,
+                        timestamp: trade.timestamp,
+                    })
+            }
+            FeedMessage::MarkPrice(update) => {
+                Symbol::new(&update.symbol)
+                    .ok()
+                    .map(|symbol| ServerMessage::MarkPrice {
+                        symbol,
+                        mark_price: update.mark_price,
+                        timestamp: update.timestamp,
+                    })
+            }
+            _ => None,
+        };
+        match encoded {
+            Some(encoded) => self.send(&encoded).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// The Titan command for a cancel, amend or replace, with the order id and
+/// symbol to report if it is refused.
+fn command(message: ClientMessage, user_id: u64, timestamp: u64) -> (OrderCommand, u64, Symbol) {
+    match message {
+        ClientMessage::CancelOrder { order_id, symbol } => (
+            OrderCommand::Cancel {
+                symbol: symbol.as_str().to_string(),
+                order_id,
+                user_id,
+                timestamp,
+            },
+            order_id,
+            symbol,
+        ),
+        ClientMessage::AmendOrder {
+            order_id,
+            symbol,
+            quantity,
+        } => (
+            OrderCommand::Amend {
+                symbol: symbol.as_str().to_string(),
+                order_id,
+                user_id,
+                quantity,
+                timestamp,
+            },
+            order_id,
+            symbol,
+        ),
+        ClientMessage::ReplaceOrder {
+            order_id,
+            symbol,
+            price,
+            quantity,
+        } => (
+            OrderCommand::Replace {
+                symbol: symbol.as_str().to_string(),
+                order_id,
+                user_id,
+                price,
+                quantity,
+                timestamp,
+            },
+            order_id,
+            symbol,
+        ),
+        _ => unreachable!("only order commands are translated"),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol() -> Symbol {
+        Symbol::new("BTC-USDT").unwrap()
+    }
+
+    fn new_order() -> NewOrder {
+        NewOrder {
+            client_order_id: 77,
+            symbol: symbol(),
+            side: Side::Sell,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillDate(1_234),
+            price: 100,
+            quantity: 5,
+            display_quantity: Some(2),
+            self_trade_prevention: SelfTradePrevention::CancelOldest,
+        }
+    }
+
+    /// Encodes and decodes `message`, checking the reported lengths.
+    fn client_round_trip(message: ClientMessage) -> ClientMessage {
+        let mut buf = [0; 128];
+        let len = message.encode(&mut buf).unwrap();
+        assert_eq!(len, message.encoded_len());
+        assert_eq!(ClientMessage::decode(&buf[..len - 1]).unwrap(), None);
+        let (decoded, used) = ClientMessage::decode(&buf[..len]).unwrap().unwrap();
+        assert_eq!(used, len);
+        decoded
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        let messages = [
+            ClientMessage::Logon {
+                token: Token::new("secret").unwrap(),
+            },
+            ClientMessage::NewOrder(new_order()),
+            ClientMessage::CancelOrder {
+                order_id: 5,
+                symbol: symbol(),
+            },
+            ClientMessage::AmendOrder {
+                order_id: 5,
+                symbol: symbol(),
+                quantity: 3,
+            },
+            ClientMessage::ReplaceOrder {
+                order_id: 5,
+                symbol: symbol(),
+                price: 101,
+                quantity: 4,
+            },
+            ClientMessage::Subscribe {
+                symbol: symbol(),
+                channels: Channels(Channels::TRADES.0 | Channels::DEPTH.0),
+            },
+            ClientMessage::Unsubscribe {
+                symbol: symbol(),
+                channels: Channels::MARK_PRICE,
+            },
+            ClientMessage::Heartbeat,
+        ];
+        for message in messages {
+            assert_eq!(client_round_trip(message), message);
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        let bids = [(100, 5), (99, 3)];
+        let depth = Depth {
+            symbol: symbol(),
+            sequence: 7,
+            timestamp: 9,
+            bids: Levels::Slice(&bids),
+            asks: Levels::Slice(&[]),
+        };
+        let messages = [
+            ServerMessage::LogonAccepted { user_id: 7 },
+            ServerMessage::SessionReject {
+                code: SessionError::SlowConsumer,
+            },
+            ServerMessage::OrderRejected {
+                order_id: 1,
+                symbol: symbol(),
+                reason: RejectReason::Unavailable,
+                timestamp: 2,
+            },
+            ServerMessage::Fill {
+                order_id: 1,
+                trade_id: 2,
+                symbol: symbol(),
+                side: Side::Sell,
+                liquidity: Liquidity::Maker,
+                price: 3,
+                quantity: 4,
+                fee: Decimal::new(-125, 4),
+                timestamp: 5,
+            },
+            ServerMessage::MarkPrice {
+                symbol: symbol(),
+                mark_price: Decimal::new(10_050, 2),
+                timestamp: 6,
+            },
+            ServerMessage::DepthSnapshot(depth),
+            ServerMessage::DepthUpdate(depth),
+        ];
+        let mut buf = [0; 256];
+        for message in messages {
+            let len = message.encode(&mut buf).unwrap();
+            assert_eq!(len, message.encoded_len());
+            assert_eq!(
+                ServerMessage::decode(&buf[..len]).unwrap(),
+                Some((message, len))
+            );
+        }
+    }
+
+    #[test]
+    fn every_reject_reason_has_a_code() {
+        for reason in REJECT_REASONS {
+            assert_eq!(reject_reason_from(reject_reason_code(reason)), Ok(reason));
+        }
+        assert_eq!(
+            reject_reason_from(0),
+            Err(WireError::InvalidField("reason"))
+        );
+    }
+
+    #[test]
+    fn encoding_needs_room_for_the_whole_frame() {
+        let message = ClientMessage::NewOrder(new_order());
+        let mut buf = [0; 16];
+        assert_eq!(
+            message.encode(&mut buf),
+            Err(WireError::BufferTooSmall {
+                needed: message.encoded_len()
+            })
+        );
+    }
+
+    #[test]
+    fn longer_blocks_from_later_versions_are_skipped() {
+        let message = ClientMessage::CancelOrder {
+            order_id: 5,
+            symbol: symbol(),
+        };
+        let mut buf = vec![0; message.encoded_len()];
+        message.encode(&mut buf).unwrap();
+        buf.extend_from_slice(&[1, 2, 3]);
+        let len = buf.len();
+        put_u32(&mut buf, 0, len as u32);
+        put_u16(&mut buf, 6, 24 + 3);
+        assert_eq!(ClientMessage::decode(&buf), Ok(Some((message, len))));
+    }
+
+    #[test]
+    fn malformed_client_frames_are_refused() {
+        let mut buf = [0; 128];
+        let len = ClientMessage::Heartbeat.encode(&mut buf).unwrap();
+
+        let mut bad_encoding = buf;
+        put_u16(&mut bad_encoding, 4, 0);
+        assert_eq!(
+            ClientMessage::decode(&bad_encoding[..len]),
+            Err(WireError::BadHeader)
+        );
+
+        let mut oversized = buf;
+        put_u32(&mut oversized, 0, MAX_CLIENT_FRAME as u32 + 1);
+        assert_eq!(
+            ClientMessage::decode(&oversized[..len]),
+            Err(WireError::FrameTooLarge(MAX_CLIENT_FRAME + 1))
+        );
+
+        let mut unknown = buf;
+        put_u16(&mut unknown, 8, 99);
+        assert_eq!(
+            ClientMessage::decode(&unknown[..len]),
+            Err(WireError::UnknownTemplate(99))
+        );
+
+        let len = ClientMessage::NewOrder(new_order())
+            .encode(&mut buf)
+            .unwrap();
+        buf[HEADER_LENGTH + 56] = 2;
+        assert_eq!(
+            ClientMessage::decode(&buf[..len]),
+            Err(WireError::InvalidField("side"))
+        );
+    }
+
+    #[test]
+    fn symbols_must_fit_and_be_ascii() {
+        assert_eq!(symbol().as_str(), "BTC-USDT");
+        assert!(Symbol::new("ABCDEFGHIJKLMNOPQ").is_err());
+        assert!(Symbol::new("BTC-€").is_err());
+        assert!(Symbol::new("BTC\0").is_err());
+    }
+
+    #[test]
+    fn new_orders_become_orders_for_the_sender() {
+        let order = new_order().to_order(9);
+        assert_eq!((order.user_id, order.symbol.as_str()), (9, "BTC-USDT"));
+        assert_eq!(order.time_in_force, TimeInForce::GoodTillDate(1_234));
+        assert_eq!(order.display_quantity, Some(2));
+    }
+}
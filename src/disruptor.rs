@@ -0,0 +1,358 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::utils::CachePadded;
+
+/// A consumer stage. Stages see every event in sequence order, each one only
+/// after all earlier stages have finished with it.
+pub trait EventHandler<T>: Send + 'static {
+    fn on_event(&mut self, event: &mut T, sequence: u64);
+}
+
+/// State shared between the producer and the stage threads.
+///
+/// Sequences are counts: `published` is the number of events written so far
+/// (the next sequence to be claimed) and `processed[i]` the number of events
+/// stage `i` has finished. The slot for sequence `s` is `s & mask`.
+struct Shared<T> {
+    slots: Box<[Mutex<T>]>,
+    mask: u64,
+    published: CachePadded<AtomicU64>,
+    processed: Box<[CachePadded<AtomicU64>]>,
+    /// Set per stage once it has drained and exited.
+    finished: Box<[AtomicBool]>,
+    /// Set once a stage has panicked; the ring can then no longer drain.
+    failed: AtomicBool,
+    running: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn slot(&self, sequence: u64) -> MutexGuard<'_, T> {
+        // Sequence barriers already guarantee exclusive access, so the lock is
+        // never contended; it only exists to keep the ring free of unsafe code.
+        self.slots[(sequence & self.mask) as usize]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.mask + 1
+    }
+}
+
+/// Backs off from spinning to yielding to sleeping while there is no work.
+fn wait(idle: &mut u32) {
+    *idle += 1;
+    if *idle < 100 {
+        std::hint::spin_loop();
+    } else if *idle < 200 {
+        thread::yield_now();
+    } else {
+        thread::sleep(Duration::from_micros(50));
+    }
+}
+
+/// Builds a bounded, pre-allocated ring buffer with a linear chain of
+/// consumer stages, in the style of the LMAX disruptor.
+pub struct DisruptorBuilder<T> {
+    capacity: usize,
+    stages: Vec<(String, Box<dyn EventHandler<T>>)>,
+}
+
+impl<T: Default + Send + 'static> DisruptorBuilder<T> {
+    /// `capacity` must be a power of two.
+    pub fn new(capacity: usize) -> Self {
+        assert!(
+            capacity.is_power_of_two(),
+            "ring capacity must be a power of two"
+        );
+        Self {
+            capacity,
+            stages: Vec::new(),
+        }
+    }
+
+    /// Appends a stage that runs after every stage added before it.
+    pub fn stage(mut self, name: impl Into<String>, handler: impl EventHandler<T>) -> Self {
+        self.stages.push((name.into(), Box::new(handler)));
+        self
+    }
+
+    /// Allocates every slot up front and spawns one thread per stage.
+    pub fn start(self) -> (Producer<T>, Disruptor<T>) {
+        assert!(
+            !self.stages.is_empty(),
+            "disruptor needs at least one stage"
+        );
+
+        let stage_count = self.stages.len();
+        let shared = Arc::new(Shared {
+            slots: (0..self.capacity)
+                .map(|_| Mutex::new(T::default()))
+                .collect(),
+            mask: self.capacity as u64 - 1,
+            published: CachePadded::new(AtomicU64::new(0)),
+            processed: (0..stage_count)
+                .map(|_| CachePadded::new(AtomicU64::new(0)))
+                .collect(),
+            finished: (0..stage_count).map(|_| AtomicBool::new(false)).collect(),
+            failed: AtomicBool::new(false),
+            running: AtomicBool::new(true),
+        });
+
+        let threads = self
+            .stages
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, handler))| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(name)
+                    .spawn(move || run_stage(&shared, index, handler))
+                    .expect("failed to spawn disruptor stage")
+            })
+            .collect();
+
+        let producer = Producer {
+            shared: Arc::clone(&shared),
+            next: 0,
+        };
+        (producer, Disruptor { shared, threads })
+    }
+}
+
+/// Marks a stage finished when its thread exits, and the ring failed if it
+/// exits by panicking, so later stages and the producer stop waiting on it.
+struct StageExit<'a, T> {
+    shared: &'a Shared<T>,
+    index: usize,
+}
+
+impl<T> Drop for StageExit<'_, T> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.shared.failed.store(true, Ordering::Release);
+        }
+        self.shared.finished[self.index].store(true, Ordering::Release);
+    }
+}
+
+fn run_stage<T: 'static>(shared: &Shared<T>, index: usize, mut handler: Box<dyn EventHandler<T>>) {
+    let _exit = StageExit { shared, index };
+    let (upstream, upstream_done): (&AtomicU64, Option<&AtomicBool>) = match index {
+        0 => (&shared.published, None),
+        _ => (
+            &shared.processed[index - 1],
+            Some(&shared.finished[index - 1]),
+        ),
+    };
+    let processed = &shared.processed[index];
+
+    let mut next = 0;
+    let mut idle = 0;
+    loop {
+        // Read the stop signal before the barrier so nothing published before
+        // it is missed.
+        let stopping = match upstream_done {
+            None => !shared.running.load(Ordering::Acquire),
+            Some(done) => done.load(Ordering::Acquire),
+        };
+        let available = upstream.load(Ordering::Acquire);
+        if next < available {
+            while next < available {
+                handler.on_event(&mut shared.slot(next), next);
+                next += 1;
+                processed.store(next, Ordering::Release);
+            }
+            idle = 0;
+        } else if stopping {
+            break;
+        } else {
+            wait(&mut idle);
+        }
+    }
+}
+
+/// A stage thread panicked, so published events would never be processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageFailed;
+
+impl fmt::Display for StageFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a disruptor stage has failed")
+    }
+}
+
+impl std::error::Error for StageFailed {}
+
+/// Single writer into the ring. Wrap it in a `Mutex` to share it.
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    next: u64,
+}
+
+impl<T> Producer<T> {
+    /// Claims the next slot, waiting while the ring is full, lets `write`
+    /// fill it in place and publishes it. Returns the event's sequence, or
+    /// an error once a stage has panicked.
+    pub fn publish(&mut self, write: impl FnOnce(&mut T)) -> Result<u64, StageFailed> {
+        let sequence = self.next;
+        let last_stage = &self.shared.processed[self.shared.processed.len() - 1];
+        let mut idle = 0;
+        loop {
+            if self.shared.failed.load(Ordering::Acquire) {
+                return Err(StageFailed);
+            }
+            if sequence < last_stage.load(Ordering::Acquire) + self.shared.capacity() {
+                break;
+            }
+            wait(&mut idle);
+        }
+
+        write(&mut self.shared.slot(sequence));
+        self.next += 1;
+        self.shared.published.store(self.next, Ordering::Release);
+        Ok(sequence)
+    }
+
+    /// Sequence the next published event will get.
+    pub fn next_sequence(&self) -> u64 {
+        self.next
+    }
+}
+
+/// Handle to the stage threads of a running disruptor.
+pub struct Disruptor<T> {
+    shared: Arc<Shared<T>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<T> Disruptor<T> {
+    /// Number of events the final stage has finished.
+    pub fn processed(&self) -> u64 {
+        self.shared.processed[self.shared.processed.len() - 1].load(Ordering::Acquire)
+    }
+
+    /// Lets every stage drain what has been published, then joins them.
+    pub fn shutdown(self) {
+        self.shared.running.store(false, Ordering::Release);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Event {
+        value: u64,
+        doubled: u64,
+    }
+
+    /// First stage: derives a field later stages depend on.
+    struct Double;
+
+    impl EventHandler<Event> for Double {
+        fn on_event(&mut self, event: &mut Event, _sequence: u64) {
+            event.doubled = event.value * 2;
+        }
+    }
+
+    /// Records what it sees as `(sequence, value, doubled)`.
+    struct Record(Arc<Mutex<Vec<(u64, u64, u64)>>>);
+
+    impl EventHandler<Event> for Record {
+        fn on_event(&mut self, event: &mut Event, sequence: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((sequence, event.value, event.doubled));
+        }
+    }
+
+    #[test]
+    fn stages_see_every_event_in_order_after_earlier_stages() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (mut producer, disruptor) = DisruptorBuilder::new(4)
+            .stage("double", Double)
+            .stage("record", Record(Arc::clone(&seen)))
+            .start();
+        // Many more events than slots, so the ring wraps repeatedly.
+        for value in 0..1_000 {
+            assert_eq!(producer.publish(|event| event.value = value), Ok(value));
+        }
+        assert_eq!(producer.next_sequence(), 1_000);
+        disruptor.shutdown();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1_000);
+        for (expected, &(sequence, value, doubled)) in seen.iter().enumerate() {
+            assert_eq!(
+                (sequence, value, doubled),
+                (expected as u64, expected as u64, expected as u64 * 2)
+            );
+        }
+    }
+
+    #[test]
+    fn shutdown_drains_published_events() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (mut producer, disruptor) = DisruptorBuilder::new(8)
+            .stage("record", Record(Arc::clone(&seen)))
+            .start();
+        for value in 0..5 {
+            producer.publish(|event| event.value = value).unwrap();
+        }
+        disruptor.shutdown();
+        assert_eq!(seen.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn processed_counts_the_final_stage() {
+        let (mut producer, disruptor) = DisruptorBuilder::new(2).stage("double", Double).start();
+        producer.publish(|event| event.value = 1).unwrap();
+        let mut idle = 0;
+        while disruptor.processed() < 1 {
+            wait(&mut idle);
+        }
+        assert_eq!(disruptor.processed(), 1);
+        disruptor.shutdown();
+    }
+
+    /// Panics on the first event it sees.
+    struct Fail;
+
+    impl EventHandler<Event> for Fail {
+        fn on_event(&mut self, _event: &mut Event, _sequence: u64) {
+            panic!("stage failed");
+        }
+    }
+
+    #[test]
+    fn a_panicked_stage_stops_the_producer() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (mut producer, disruptor) = DisruptorBuilder::new(2)
+            .stage("fail", Fail)
+            .stage("record", Record(Arc::clone(&seen)))
+            .start();
+        // Publishing may succeed until the ring fills; it must not block after.
+        let results: Vec<_> = (0..4)
+            .map(|value| producer.publish(|event| event.value = value))
+            .collect();
+        assert_eq!(results.last(), Some(&Err(StageFailed)));
+        disruptor.shutdown();
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "ring capacity must be a power of two")]
+    fn capacity_must_be_a_power_of_two() {
+        DisruptorBuilder::<Event>::new(6);
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::instruments::Instrument;
+use crate::types::{DAY_MS, Execution};
+
+const VOLUME_WINDOW_DAYS: u64 = 30;
+
+/// Rates applied once a user's 30-day traded notional reaches `min_volume`.
+/// Rates are fractions of notional; a negative maker rate is a rebate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeTier {
+    pub min_volume: Decimal,
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeeSchedule {
+    /// Sorted by `min_volume`, lowest first.
+    tiers: Vec<FeeTier>,
+}
+
+impl FeeSchedule {
+    pub fn new(mut tiers: Vec<FeeTier>) -> Self {
+        assert!(!tiers.is_empty(), "fee schedule needs at least one tier");
+        tiers.sort_by_key(|tier| tier.min_volume);
+        Self { tiers }
+    }
+
+    /// Single tier charging the same rates regardless of volume.
+    pub fn flat(maker_rate: Decimal, taker_rate: Decimal) -> Self {
+        Self::new(vec![FeeTier {
+            min_volume: Decimal::ZERO,
+            maker_rate,
+            taker_rate,
+        }])
+    }
+
+    /// Highest tier whose threshold `volume` reaches. Volumes below the first
+    /// threshold pay the first tier.
+    pub fn tier_for(&self, volume: Decimal) -> &FeeTier {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|tier| volume >= tier.min_volume)
+            .unwrap_or(&self.tiers[0])
+    }
+}
+
+/// Value traded per UTC day over the trailing window. Values saturate at
+/// `Decimal::MAX` rather than overflow.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RollingVolume {
+    days: VecDeque<(u64, Decimal)>,
+}
+
+impl RollingVolume {
+    /// Adds `value` traded at `timestamp`.
+    pub fn record(&mut self, timestamp: u64, value: Decimal) {
+        let day = timestamp / DAY_MS;
+        match self.days.back_mut() {
+            Some((last, volume)) if *last == day => *volume = volume.saturating_add(value),
+            _ => self.days.push_back((day, value)),
+        }
+        while self
+            .days
+            .front()
+            .is_some_and(|(d, _)| d + VOLUME_WINDOW_DAYS <= day)
+        {
+            self.days.pop_front();
+        }
+    }
+
+    /// Value traded in the window ending on the day of `timestamp`.
+    pub fn total(&self, timestamp: u64) -> Decimal {
+        let day = timestamp / DAY_MS;
+        self.days
+            .iter()
+            .filter(|(d, _)| d + VOLUME_WINDOW_DAYS > day)
+            .fold(Decimal::ZERO, |total, (_, volume)| {
+                total.saturating_add(*volume)
+            })
+    }
+
+    /// Adds every day of `other`, each value multiplied by `unit`.
+    pub fn merge(&mut self, other: &RollingVolume, unit: Decimal) {
+        let mut days: BTreeMap<u64, Decimal> = self.days.drain(..).collect();
+        for (day, value) in &other.days {
+            let volume = days.entry(*day).or_default();
+            *volume = volume.saturating_add(value.saturating_mul(unit));
+        }
+        self.days = days.into_iter().collect();
+    }
+}
+
+/// Adds an execution to the buyer's and seller's rolling volume in one
+/// symbol, as price times quantity in scaled units, so it can be rebuilt
+/// from executions without the instrument.
+pub fn record_volume(volumes: &mut BTreeMap<u64, RollingVolume>, execution: &Execution) {
+    let value = Decimal::from(execution.price).saturating_mul(Decimal::from(execution.quantity));
+    for user_id in [execution.buyer_user_id, execution.seller_user_id] {
+        volumes
+            .entry(user_id)
+            .or_default()
+            .record(execution.timestamp, value);
+    }
+}
+
+/// Prices maker and taker fees for executions from per-instrument schedules
+/// and each user's 30-day volume.
+///
+/// Clones share one volume store, so a user's tier follows their notional
+/// across every symbol, whichever matching shard trades it. Volumes are
+/// kept in a `DashMap` so books on different threads update them without a
+/// global lock. After a restart they are rebuilt from each book's volumes
+/// in the Oracle log with [`FeeEngine::resume`].
+#[derive(Debug, Clone)]
+pub struct FeeEngine {
+    default_schedule: FeeSchedule,
+    schedules: HashMap<String, FeeSchedule>,
+    /// Notional traded per user, in quote asset units.
+    volumes: Arc<DashMap<u64, RollingVolume>>,
+}
+
+impl FeeEngine {
+    pub fn new(default_schedule: FeeSchedule) -> Self {
+        Self {
+            default_schedule,
+            schedules: HashMap::new(),
+            volumes: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Adds the volumes recorded for `instrument`, e.g. those recovered from
+    /// the event log, to every user's total. Call it once per symbol.
+    pub fn resume(&self, instrument: &Instrument, volumes: &BTreeMap<u64, RollingVolume>) {
+        // One scaled unit of price times quantity is worth `notional(1, 1)`.
+        let unit = instrument.notional(1, 1).unwrap_or_default();
+        for (user_id, volume) in volumes {
+            self.volumes
+                .entry(*user_id)
+                .or_default()
+                .merge(volume, unit);
+        }
+    }
+
+    /// Overrides the default schedule for one symbol.
+    pub fn set_schedule(&mut self, symbol: impl Into<String>, schedule: FeeSchedule) {
+        self.schedules.insert(symbol.into(), schedule);
+    }
+
+    pub fn schedule(&self, symbol: &str) -> &FeeSchedule {
+        self.schedules.get(symbol).unwrap_or(&self.default_schedule)
+    }
+
+    /// Notional traded by `user_id` across all symbols in the 30 days up to
+    /// `timestamp`.
+    pub fn thirty_day_volume(&self, user_id: u64, timestamp: u64) -> Decimal {
+        self.volumes
+            .get(&user_id)
+            .map_or(Decimal::ZERO, |v| v.total(timestamp))
+    }
+
+    /// Fills in `maker_fee` and `taker_fee` on `execution` using each side's
+    /// tier before this trade, then adds the trade to both users' volume.
+    pub fn charge(&self, execution: &mut Execution, instrument: &Instrument) {
+        // Validation bounds the notional of every priced order, and a fill
+        // is never larger than its maker.
+        let notional = instrument
+            .notional(execution.price, execution.quantity)
+            .unwrap_or(Decimal::MAX);
+        let schedule = self.schedule(&execution.symbol);
+        let maker_user_id = execution.maker_user_id();
+        let taker_user_id = execution.taker_user_id();
+
+        let maker_tier =
+            schedule.tier_for(self.thirty_day_volume(maker_user_id, execution.timestamp));
+        let taker_tier =
+            schedule.tier_for(self.thirty_day_volume(taker_user_id, execution.timestamp));
+        execution.maker_fee = notional * maker_tier.maker_rate;
+        execution.taker_fee = notional * taker_tier.taker_rate;
+
+        for user_id in [maker_user_id, taker_user_id] {
+            self.volumes
+                .entry(user_id)
+                .or_default()
+                .record(execution.timestamp, notional);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Side;
+
+    fn decimal(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn instrument() -> Instrument {
+        Instrument {
+            symbol: "BTC-USDT".into(),
+            base_asset: "BTC".into(),
+            quote_asset: "USDT".into(),
+            price_scale: 2,
+            quantity_scale: 0,
+            tick_size: 1,
+            lot_size: 1,
+            min_notional: Decimal::ZERO,
+        }
+    }
+
+    /// Maker 0.1% and taker 0.2%, dropping to a 0.01% maker rebate and 0.1%
+    /// taker fee from 1,000 USDT of volume.
+    fn tiered() -> FeeEngine {
+        FeeEngine::new(FeeSchedule::new(vec![
+            FeeTier {
+                min_volume: Decimal::from(1_000),
+                maker_rate: decimal("-0.0001"),
+                taker_rate: decimal("0.001"),
+            },
+            FeeTier {
+                min_volume: Decimal::ZERO,
+                maker_rate: decimal("0.001"),
+                taker_rate: decimal("0.002"),
+            },
+        ]))
+    }
+
+    /// User 1 buys `quantity` at 100.00 from user 2.
+    fn execution(quantity: u64, timestamp: u64) -> Execution {
+        Execution {
+            trade_id: 1,
+            symbol: "BTC-USDT".into(),
+            buy_order_id: 10,
+            sell_order_id: 20,
+            buyer_user_id: 1,
+            seller_user_id: 2,
+            aggressor_side: Side::Buy,
+            price: 10_000,
+            quantity,
+            timestamp,
+            maker_fee: Decimal::ZERO,
+            taker_fee: Decimal::ZERO,
+        }
+    }
+
+    #[test]
+    fn tiers_are_picked_by_volume() {
+        let schedule = tiered().schedule("BTC-USDT").clone();
+        assert_eq!(
+            schedule.tier_for(Decimal::ZERO).taker_rate,
+            decimal("0.002")
+        );
+        assert_eq!(
+            schedule.tier_for(decimal("999.99")).taker_rate,
+            decimal("0.002")
+        );
+        assert_eq!(
+            schedule.tier_for(Decimal::from(1_000)).taker_rate,
+            decimal("0.001")
+        );
+    }
+
+    #[test]
+    fn fees_use_the_tier_before_the_trade() {
+        let fees = tiered();
+        let mut first = execution(10, 0);
+        fees.charge(&mut first, &instrument());
+        assert_eq!(
+            (first.maker_fee, first.taker_fee),
+            (decimal("1"), decimal("2"))
+        );
+        assert_eq!(fees.thirty_day_volume(1, 0), Decimal::from(1_000));
+
+        let mut second = execution(10, 1);
+        fees.charge(&mut second, &instrument());
+        assert_eq!(
+            (second.maker_fee, second.taker_fee),
+            (decimal("-0.1"), decimal("1"))
+        );
+    }
+
+    #[test]
+    fn volume_counts_across_symbols() {
+        let btc = tiered();
+        let eth = btc.clone();
+        let mut other = instrument();
+        other.symbol = "ETH-USDT".into();
+        let mut first = execution(5, 0);
+        btc.charge(&mut first, &instrument());
+        let mut second = execution(5, 1);
+        second.symbol = other.symbol.clone();
+        eth.charge(&mut second, &other);
+
+        assert_eq!(btc.thirty_day_volume(1, 1), Decimal::from(1_000));
+        let mut third = execution(10, 2);
+        btc.charge(&mut third, &instrument());
+        assert_eq!(third.taker_fee, decimal("1"));
+    }
+
+    #[test]
+    fn volume_leaves_the_window_after_thirty_days() {
+        let mut volume = RollingVolume::default();
+        volume.record(0, Decimal::from(100_000));
+        volume.record(DAY_MS, Decimal::from(50_000));
+        assert_eq!(volume.total(DAY_MS), Decimal::from(150_000));
+        assert_eq!(volume.total(30 * DAY_MS), Decimal::from(50_000));
+        assert_eq!(volume.total(31 * DAY_MS), Decimal::ZERO);
+    }
+
+    #[test]
+    fn volume_saturates_instead_of_overflowing() {
+        let mut volumes = BTreeMap::new();
+        let mut large = execution(100_000_000_000_000, 0);
+        large.price = 1_000_000_000_000_000;
+        record_volume(&mut volumes, &large);
+        let mut largest = execution(u64::MAX, DAY_MS);
+        largest.price = u64::MAX;
+        record_volume(&mut volumes, &largest);
+        assert_eq!(volumes[&1].total(DAY_MS), Decimal::MAX);
+    }
+
+    #[test]
+    fn resumed_volumes_set_the_tier() {
+        let mut volumes = BTreeMap::new();
+        record_volume(&mut volumes, &execution(10, 0));
+        let fees = tiered();
+        fees.resume(&instrument(), &volumes);
+        assert_eq!(fees.thirty_day_volume(1, 0), Decimal::from(1_000));
+        let mut next = execution(10, 1);
+        fees.charge(&mut next, &instrument());
+        assert_eq!(next.taker_fee, decimal("1"));
+    }
+
+    #[test]
+    fn symbols_can_override_the_default_schedule() {
+        let mut fees = tiered();
+        fees.set_schedule("BTC-USDT", FeeSchedule::flat(Decimal::ZERO, Decimal::ZERO));
+        let mut free = execution(10, 0);
+        fees.charge(&mut free, &instrument());
+        assert_eq!(
+            (free.maker_fee, free.taker_fee),
+            (Decimal::ZERO, Decimal::ZERO)
+        );
+    }
+}
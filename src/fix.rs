@@ -0,0 +1,1863 @@
+//! FIX 4.4 order entry.
+//!
+//! Each session is identified by the counterparty's SenderCompID and is
+//! authenticated by the Password (554) of its Logon against the order
+//! gateway's authenticator. Orders go through [`OrderGateway`], so FIX users
+//! get the same order ids, ownership checks and private events as WebSocket
+//! users.
+//!
+//! Sequence numbers and every message sent are persisted per session, so a
+//! reconnect, even after a restart, resumes the sequence and can answer
+//! ResendRequests. Application messages are resent with PossDupFlag; session
+//! messages are replaced by gap fills.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use metrics::{counter, gauge};
+use rust_decimal::Decimal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::instruments::{Instrument, InstrumentRegistry};
+use crate::order_gateway::{Fill, GatewayMessage, GatewaySession, OrderGateway};
+use crate::titan::OrderCommand;
+use crate::types::{
+    DAY_MS, Order, OrderType, RejectReason, SelfTradePrevention, Side, TimeInForce,
+};
+
+pub const BEGIN_STRING: &str = "FIX.4.4";
+/// Largest BodyLength (9) the acceptor reads; anything longer is treated as
+/// garbled rather than waited for.
+pub const MAX_BODY_LENGTH: usize = 4096;
+const SOH: u8 = 0x01;
+
+/// Tags used by the acceptor.
+pub mod tag {
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const CUM_QTY: u32 = 14;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const EXEC_ID: u32 = 17;
+    pub const EXEC_INST: u32 = 18;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const PRICE: u32 = 44;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TIME_IN_FORCE: u32 = 59;
+    pub const TRANSACT_TIME: u32 = 60;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const ORD_REJ_REASON: u32 = 103;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const MAX_FLOOR: u32 = 111;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const ORIG_SENDING_TIME: u32 = 122;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const EXPIRE_TIME: u32 = 126;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const REF_TAG_ID: u32 = 371;
+    pub const REF_MSG_TYPE: u32 = 372;
+    pub const SESSION_REJECT_REASON: u32 = 373;
+    pub const BUSINESS_REJECT_REASON: u32 = 380;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+    pub const PASSWORD: u32 = 554;
+}
+
+/// Header fields written by the session when a message is sent.
+const HEADER_TAGS: [u32; 7] = [
+    tag::MSG_TYPE,
+    tag::SENDER_COMP_ID,
+    tag::TARGET_COMP_ID,
+    tag::MSG_SEQ_NUM,
+    tag::POSS_DUP_FLAG,
+    tag::SENDING_TIME,
+    tag::ORIG_SENDING_TIME,
+];
+
+/// A message whose content cannot be translated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixError {
+    MissingTag(u32),
+    /// The value is malformed or not supported.
+    InvalidValue(u32),
+}
+
+impl FixError {
+    fn tag(self) -> u32 {
+        match self {
+            FixError::MissingTag(tag) | FixError::InvalidValue(tag) => tag,
+        }
+    }
+
+    /// SessionRejectReason (373).
+    fn session_reject_reason(self) -> u32 {
+        match self {
+            FixError::MissingTag(_) => 1,
+            FixError::InvalidValue(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for FixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixError::MissingTag(tag) => write!(f, "required tag {tag} missing"),
+            FixError::InvalidValue(tag) => write!(f, "invalid value for tag {tag}"),
+        }
+    }
+}
+
+impl std::error::Error for FixError {}
+
+/// A FIX message without its BeginString, BodyLength and CheckSum, which
+/// are added by [`FixMessage::encode`]. The first field is always MsgType.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixMessage {
+    fields: Vec<(u32, String)>,
+}
+
+/// Result of reading one message from the front of a byte stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decoded {
+    /// More bytes are needed.
+    Incomplete,
+    /// A message and the number of bytes it used.
+    Message(FixMessage, usize),
+    /// The first `n` bytes are not a valid message and should be skipped.
+    Garbled(usize),
+}
+
+impl FixMessage {
+    pub fn new(msg_type: &str) -> Self {
+        Self {
+            fields: vec![(tag::MSG_TYPE, msg_type.to_string())],
+        }
+    }
+
+    pub fn msg_type(&self) -> &str {
+        &self.fields[0].1
+    }
+
+    pub fn with(mut self, tag: u32, value: impl ToString) -> Self {
+        self.push(tag, value);
+        self
+    }
+
+    pub fn push(&mut self, tag: u32, value: impl ToString) {
+        self.fields.push((tag, value.to_string()));
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn require(&self, tag: u32) -> Result<&str, FixError> {
+        self.get(tag).ok_or(FixError::MissingTag(tag))
+    }
+
+    /// Parses a required field.
+    pub fn parse<T: FromStr>(&self, tag: u32) -> Result<T, FixError> {
+        self.require(tag)?
+            .parse()
+            .map_err(|_| FixError::InvalidValue(tag))
+    }
+
+    /// Parses an optional field.
+    pub fn parse_opt<T: FromStr>(&self, tag: u32) -> Result<Option<T>, FixError> {
+        self.get(tag)
+            .map(|value| value.parse().map_err(|_| FixError::InvalidValue(tag)))
+            .transpose()
+    }
+
+    pub fn flag(&self, tag: u32) -> bool {
+        self.get(tag) == Some("Y")
+    }
+
+    fn body(&self) -> impl Iterator<Item = &(u32, String)> {
+        self.fields.iter().filter(|(t, _)| !HEADER_TAGS.contains(t))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(128);
+        for (tag, value) in &self.fields {
+            body.extend_from_slice(format!("{tag}={value}").as_bytes());
+            body.push(SOH);
+        }
+        let mut out = format!("8={BEGIN_STRING}\x019={}\x01", body.len()).into_bytes();
+        out.extend_from_slice(&body);
+        let checksum = out.iter().map(|&b| b as u32).sum::<u32>() % 256;
+        out.extend_from_slice(format!("10={checksum:03}\x01").as_bytes());
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Decoded {
+        let prefix = format!("8={BEGIN_STRING}\x019=");
+        let prefix = prefix.as_bytes();
+        let available = buf.len().min(prefix.len());
+        if buf[..available] != prefix[..available] {
+            return Decoded::Garbled(next_message_start(buf));
+        }
+        if buf.len() == available {
+            return Decoded::Incomplete;
+        }
+
+        let Some(length_end) = buf[prefix.len()..].iter().position(|&b| b == SOH) else {
+            return if buf.len() - prefix.len() > 6 {
+                Decoded::Garbled(next_message_start(buf))
+            } else {
+                Decoded::Incomplete
+            };
+        };
+        let Some(body_length) = std::str::from_utf8(&buf[prefix.len()..prefix.len() + length_end])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len <= MAX_BODY_LENGTH)
+        else {
+            return Decoded::Garbled(next_message_start(buf));
+        };
+        let body_start = prefix.len() + length_end + 1;
+        let Some(body_end) = body_start.checked_add(body_length) else {
+            return Decoded::Garbled(next_message_start(buf));
+        };
+        let total = body_end + 7;
+        if buf.len() < total {
+            return Decoded::Incomplete;
+        }
+
+        let trailer = &buf[body_end..total];
+        let checksum = buf[..body_end].iter().map(|&b| b as u32).sum::<u32>() % 256;
+        let valid_trailer = trailer.starts_with(b"10=")
+            && trailer[6] == SOH
+            && std::str::from_utf8(&trailer[3..6])
+                .ok()
+                .and_then(|sum| sum.parse::<u32>().ok())
+                == Some(checksum);
+        if !valid_trailer {
+            return Decoded::Garbled(total);
+        }
+
+        let mut fields = Vec::new();
+        for field in buf[body_start..body_end].split(|&b| b == SOH) {
+            if field.is_empty() {
+                continue;
+            }
+            let Some(parsed) = std::str::from_utf8(field).ok().and_then(|field| {
+                let (tag, value) = field.split_once('=')?;
+                Some((tag.parse::<u32>().ok()?, value.to_string()))
+            }) else {
+                return Decoded::Garbled(total);
+            };
+            fields.push(parsed);
+        }
+        if fields.first().is_none_or(|(tag, _)| *tag != tag::MSG_TYPE) {
+            return Decoded::Garbled(total);
+        }
+        Decoded::Message(FixMessage { fields }, total)
+    }
+}
+
+/// Offset of the next possible BeginString after the first byte.
+fn next_message_start(buf: &[u8]) -> usize {
+    buf.windows(2)
+        .skip(1)
+        .position(|w| w == b"8=")
+        .map_or(buf.len(), |i| i + 1)
+}
+
+/// Translates a NewOrderSingle into an order for `user_id`. The gateway
+/// assigns the order id and timestamp when it is submitted.
+///
+/// TimeInForce Day is treated as good-till-cancel, as instruments trade
+/// continuously. ExecInst `6` (participate don't initiate) makes a
+/// good-till-cancel order post-only, and MaxFloor makes it an iceberg.
+pub fn new_order_single(
+    message: &FixMessage,
+    instrument: &Instrument,
+    user_id: u64,
+) -> Result<Order, FixError> {
+    let side = parse_side(message.require(tag::SIDE)?)?;
+    let quantity = parse_quantity(message, tag::ORDER_QTY, instrument)?;
+    let order_type = match message.require(tag::ORD_TYPE)? {
+        "1" => OrderType::Market,
+        "2" => OrderType::Limit,
+        _ => return Err(FixError::InvalidValue(tag::ORD_TYPE)),
+    };
+    let price = match order_type {
+        OrderType::Market => 0,
+        OrderType::Limit => parse_price(message, instrument)?,
+    };
+    let mut time_in_force = match message.get(tag::TIME_IN_FORCE).unwrap_or("0") {
+        "0" | "1" => TimeInForce::GoodTillCancel,
+        "3" => TimeInForce::ImmediateOrCancel,
+        "4" => TimeInForce::FillOrKill,
+        "6" => TimeInForce::GoodTillDate(
+            parse_utc_timestamp(message.require(tag::EXPIRE_TIME)?)
+                .ok_or(FixError::InvalidValue(tag::EXPIRE_TIME))?,
+        ),
+        _ => return Err(FixError::InvalidValue(tag::TIME_IN_FORCE)),
+    };
+    if message
+        .get(tag::EXEC_INST)
+        .is_some_and(|inst| inst.split(' ').any(|i| i == "6"))
+    {
+        if time_in_force != TimeInForce::GoodTillCancel {
+            return Err(FixError::InvalidValue(tag::EXEC_INST));
+        }
+        time_in_force = TimeInForce::PostOnly;
+    }
+    let display_quantity = message
+        .get(tag::MAX_FLOOR)
+        .map(|_| parse_quantity(message, tag::MAX_FLOOR, instrument))
+        .transpose()?;
+
+    Ok(Order {
+        order_id: 0,
+        user_id,
+        symbol: instrument.symbol.clone(),
+        side,
+        price,
+        quantity,
+        timestamp: 0,
+        order_type,
+        time_in_force,
+        display_quantity,
+        self_trade_prevention: SelfTradePrevention::None,
+    })
+}
+
+fn parse_side(value: &str) -> Result<Side, FixError> {
+    match value {
+        "1" => Ok(Side::Buy),
+        "2" => Ok(Side::Sell),
+        _ => Err(FixError::InvalidValue(tag::SIDE)),
+    }
+}
+
+fn side_code(side: Side) -> &'static str {
+    match side {
+        Side::Buy => "1",
+        Side::Sell => "2",
+    }
+}
+
+fn parse_price(message: &FixMessage, instrument: &Instrument) -> Result<u64, FixError> {
+    instrument
+        .price_from_decimal(message.parse::<Decimal>(tag::PRICE)?)
+        .ok_or(FixError::InvalidValue(tag::PRICE))
+}
+
+fn parse_quantity(
+    message: &FixMessage,
+    tag: u32,
+    instrument: &Instrument,
+) -> Result<u64, FixError> {
+    instrument
+        .quantity_from_decimal(message.parse::<Decimal>(tag)?)
+        .ok_or(FixError::InvalidValue(tag))
+}
+
+/// OrdRejReason (103) for a Titan rejection.
+fn ord_rej_reason(reason: RejectReason) -> u32 {
+    match reason {
+        RejectReason::UnknownSymbol => 1,
+        RejectReason::MarketClosed | RejectReason::TradingHalted => 2,
+        RejectReason::UnknownOrder => 5,
+        RejectReason::DuplicateOrderId => 6,
+        _ => 99,
+    }
+}
+
+/// CxlRejReason (102) for a Titan rejection.
+fn cxl_rej_reason(reason: RejectReason) -> u32 {
+    match reason {
+        RejectReason::UnknownOrder => 1,
+        _ => 99,
+    }
+}
+
+/// Formats milliseconds since the epoch as a UTCTimestamp.
+pub fn utc_timestamp(ms: u64) -> String {
+    let (year, month, day) = civil_from_days((ms / DAY_MS) as i64);
+    let time = ms % DAY_MS;
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}:{:02}:{:02}.{:03}",
+        time / 3_600_000,
+        time / 60_000 % 60,
+        time / 1_000 % 60,
+        time % 1_000
+    )
+}
+
+/// Parses a UTCTimestamp, with or without milliseconds.
+pub fn parse_utc_timestamp(value: &str) -> Option<u64> {
+    let (date, time) = value.split_once('-')?;
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let year: i64 = date[..4].parse().ok()?;
+    let month: u32 = date[4..6].parse().ok()?;
+    let day: u32 = date[6..].parse().ok()?;
+    let (time, millis) = time.split_once('.').unwrap_or((time, "0"));
+    let mut hms = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (hms.next()??, hms.next()??, hms.next()??);
+    let millis: u64 = millis.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * DAY_MS + ((hours * 60 + minutes) * 60 + seconds) * 1_000 + millis)
+}
+
+/// Gregorian date of a day count since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let mp = i64::from((month + 9) % 12);
+    let day_of_year = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Persisted state of one session: the next sequence numbers in both
+/// directions in `<session>.seqnums`, and every message sent, framed as
+/// sequence number, length and bytes, in `<session>.messages`.
+struct SessionStore {
+    seqnums_path: PathBuf,
+    messages_path: PathBuf,
+    messages: File,
+    next_incoming: u64,
+    next_outgoing: u64,
+}
+
+impl SessionStore {
+    fn open(dir: &Path, session: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let name: String = session
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let seqnums_path = dir.join(format!("{name}.seqnums"));
+        let messages_path = dir.join(format!("{name}.messages"));
+        let (next_incoming, next_outgoing) = match fs::read_to_string(&seqnums_path) {
+            Ok(contents) => {
+                let mut numbers = contents.split_whitespace().map(str::parse::<u64>);
+                match (numbers.next(), numbers.next()) {
+                    (Some(Ok(incoming)), Some(Ok(outgoing))) => (incoming, outgoing),
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("corrupt sequence file {}", seqnums_path.display()),
+                        ));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (1, 1),
+            Err(e) => return Err(e),
+        };
+        let messages = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&messages_path)?;
+        Ok(Self {
+            seqnums_path,
+            messages_path,
+            messages,
+            next_incoming,
+            next_outgoing,
+        })
+    }
+
+    /// Starts both sequences again at 1 and forgets the messages sent.
+    fn reset(&mut self) -> io::Result<()> {
+        self.messages.set_len(0)?;
+        self.next_incoming = 1;
+        self.next_outgoing = 1;
+        self.save()
+    }
+
+    fn set_next_incoming(&mut self, next: u64) -> io::Result<()> {
+        self.next_incoming = next;
+        self.save()
+    }
+
+    fn record_sent(&mut self, sequence: u64, message: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(message.len() + 12);
+        record.extend_from_slice(&sequence.to_le_bytes());
+        record.extend_from_slice(&(message.len() as u32).to_le_bytes());
+        record.extend_from_slice(message);
+        self.messages.write_all(&record)?;
+        self.next_outgoing = sequence + 1;
+        self.save()
+    }
+
+    /// Messages sent with sequence numbers in `from..=to`.
+    fn sent(&self, from: u64, to: u64) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let mut contents = Vec::new();
+        File::open(&self.messages_path)?.read_to_end(&mut contents)?;
+        let mut messages = Vec::new();
+        let mut rest = contents.as_slice();
+        while rest.len() >= 12 {
+            let sequence = u64::from_le_bytes(rest[..8].try_into().expect("8 bytes"));
+            let len = u32::from_le_bytes(rest[8..12].try_into().expect("4 bytes")) as usize;
+            let Some(message) = rest.get(12..12 + len) else {
+                break;
+            };
+            if (from..=to).contains(&sequence) {
+                messages.push((sequence, message.to_vec()));
+            }
+            rest = &rest[12 + len..];
+        }
+        Ok(messages)
+    }
+
+    /// Writes the sequence numbers to a temporary file and renames it over
+    /// the old one, so a crash leaves either the old or the new numbers.
+    fn save(&self) -> io::Result<()> {
+        let tmp = self.seqnums_path.with_extension("seqnums.tmp");
+        fs::write(
+            &tmp,
+            format!("{} {}\n", self.next_incoming, self.next_outgoing),
+        )?;
+        fs::rename(tmp, &self.seqnums_path)
+    }
+}
+
+/// An order entered on a FIX session, tracked to fill in ExecutionReports.
+#[derive(Debug)]
+struct FixOrder {
+    cl_ord_id: String,
+    symbol: String,
+    side: Side,
+    price: u64,
+    /// Total quantity, including what has been filled.
+    quantity: u64,
+    cum_qty: u64,
+    /// Sum of price * quantity over all fills, in decimal units.
+    cum_notional: Decimal,
+    executions: u64,
+    /// Set once Titan accepted the order.
+    placed: bool,
+    pending: Option<PendingRequest>,
+}
+
+impl FixOrder {
+    fn leaves_qty(&self) -> u64 {
+        self.quantity - self.cum_qty
+    }
+}
+
+/// A cancel or cancel/replace awaiting Titan's answer.
+#[derive(Debug)]
+struct PendingRequest {
+    cl_ord_id: String,
+    kind: PendingKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingKind {
+    Cancel,
+    Replace,
+}
+
+/// Open orders of one session by gateway order id, and the ClOrdIDs that
+/// refer to them. Kept across reconnects of the session.
+#[derive(Debug, Default)]
+struct OrderTracker {
+    orders: HashMap<u64, FixOrder>,
+    cl_ord_ids: HashMap<String, u64>,
+}
+
+impl OrderTracker {
+    fn insert(&mut self, order_id: u64, order: FixOrder) {
+        self.cl_ord_ids.insert(order.cl_ord_id.clone(), order_id);
+        self.orders.insert(order_id, order);
+    }
+
+    fn remove(&mut self, order_id: u64) {
+        if self.orders.remove(&order_id).is_some() {
+            self.cl_ord_ids.retain(|_, id| *id != order_id);
+        }
+    }
+
+    /// Resolves OrigClOrdID, falling back to OrderID.
+    fn resolve(&self, message: &FixMessage) -> Option<u64> {
+        message
+            .get(tag::ORIG_CL_ORD_ID)
+            .and_then(|id| self.cl_ord_ids.get(id).copied())
+            .or_else(|| message.parse_opt::<u64>(tag::ORDER_ID).ok().flatten())
+            .filter(|order_id| self.orders.contains_key(order_id))
+    }
+
+    /// Turns the user's private events into ExecutionReports and
+    /// OrderCancelRejects. Events for orders not entered on this session
+    /// are ignored.
+    fn report(
+        &mut self,
+        message: GatewayMessage,
+        instruments: &InstrumentRegistry,
+    ) -> Option<FixMessage> {
+        match message {
+            GatewayMessage::OrderPlaced(placed) => {
+                let instrument = instruments.get(&placed.symbol);
+                let order = self.orders.get_mut(&placed.order_id)?;
+                if order.placed {
+                    return None;
+                }
+                order.placed = true;
+                Some(execution_report(placed.order_id, order, "0", instrument))
+            }
+            GatewayMessage::Rejected {
+                order_id, reason, ..
+            } => {
+                let instrument = instruments.get(&self.orders.get(&order_id)?.symbol);
+                let order = self.orders.get_mut(&order_id)?;
+                // Titan refusing the order itself ends it, whatever cancel
+                // or replace was sent while the order was in flight.
+                if !order.placed {
+                    let report = execution_report(order_id, order, "8", instrument)
+                        .with(tag::ORD_REJ_REASON, ord_rej_reason(reason))
+                        .with(tag::TEXT, reason);
+                    self.remove(order_id);
+                    return Some(report);
+                }
+                let pending = order.pending.take()?;
+                Some(
+                    FixMessage::new("9")
+                        .with(tag::ORDER_ID, order_id)
+                        .with(tag::CL_ORD_ID, pending.cl_ord_id)
+                        .with(tag::ORIG_CL_ORD_ID, &order.cl_ord_id)
+                        .with(tag::ORD_STATUS, ord_status(order))
+                        .with(tag::CXL_REJ_RESPONSE_TO, pending.kind.response_to())
+                        .with(tag::CXL_REJ_REASON, cxl_rej_reason(reason))
+                        .with(tag::TEXT, reason),
+                )
+            }
+            GatewayMessage::Cancelled { order_id, .. } => {
+                let instrument = instruments.get(&self.orders.get(&order_id)?.symbol);
+                let order = self.orders.get_mut(&order_id)?;
+                let orig = order.cl_ord_id.clone();
+                let requested = order
+                    .pending
+                    .take()
+                    .filter(|pending| pending.kind == PendingKind::Cancel);
+                if let Some(pending) = &requested {
+                    order.cl_ord_id = pending.cl_ord_id.clone();
+                }
+                let mut report = execution_report(order_id, order, "4", instrument);
+                if requested.is_some() {
+                    report.push(tag::ORIG_CL_ORD_ID, orig);
+                }
+                self.remove(order_id);
+                Some(report)
+            }
+            GatewayMessage::Amended {
+                order_id, quantity, ..
+            } => self.replaced(order_id, None, quantity, instruments),
+            GatewayMessage::Replaced {
+                order_id,
+                price,
+                quantity,
+                ..
+            } => self.replaced(order_id, Some(price), quantity, instruments),
+            GatewayMessage::Fill(fill) => self.filled(&fill, instruments),
+            _ => None,
+        }
+    }
+
+    fn replaced(
+        &mut self,
+        order_id: u64,
+        price: Option<u64>,
+        leaves: u64,
+        instruments: &InstrumentRegistry,
+    ) -> Option<FixMessage> {
+        let instrument = instruments.get(&self.orders.get(&order_id)?.symbol);
+        let order = self.orders.get_mut(&order_id)?;
+        let orig = order.cl_ord_id.clone();
+        if let Some(pending) = order.pending.take() {
+            self.cl_ord_ids.insert(pending.cl_ord_id.clone(), order_id);
+            order.cl_ord_id = pending.cl_ord_id;
+        }
+        order.price = price.unwrap_or(order.price);
+        order.quantity = order.cum_qty + leaves;
+        Some(execution_report(order_id, order, "5", instrument).with(tag::ORIG_CL_ORD_ID, orig))
+    }
+
+    fn filled(&mut self, fill: &Fill, instruments: &InstrumentRegistry) -> Option<FixMessage> {
+        let instrument = instruments.get(&fill.symbol);
+        let order = self.orders.get_mut(&fill.order_id)?;
+        order.cum_qty += fill.quantity;
+        if let Some(instrument) = instrument {
+            order.cum_notional += instrument.price_to_decimal(fill.price)
+                * instrument.quantity_to_decimal(fill.quantity);
+        }
+        let done = order.leaves_qty() == 0;
+        let mut report = execution_report(fill.order_id, order, "F", instrument);
+        if let Some(instrument) = instrument {
+            report.push(tag::LAST_PX, instrument.price_to_decimal(fill.price));
+            report.push(tag::LAST_QTY, instrument.quantity_to_decimal(fill.quantity));
+        }
+        if done {
+            self.remove(fill.order_id);
+        }
+        Some(report)
+    }
+}
+
+/// Accepts FIX 4.4 sessions and routes their orders through the order
+/// gateway.
+pub struct FixAcceptor {
+    gateway: Arc<OrderGateway>,
+    instruments: InstrumentRegistry,
+    comp_id: String,
+    store_dir: PathBuf,
+    /// Sessions currently logged on, by counterparty CompID.
+    active: Mutex<HashSet<String>>,
+    trackers: Mutex<HashMap<String, OrderTracker>>,
+}
+
+impl FixAcceptor {
+    /// `comp_id` is the acceptor's own CompID; session state is persisted
+    /// under `store_dir`.
+    pub fn new(
+        gateway: Arc<OrderGateway>,
+        instruments: InstrumentRegistry,
+        comp_id: impl Into<String>,
+        store_dir: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            gateway,
+            instruments,
+            comp_id: comp_id.into(),
+            store_dir: store_dir.into(),
+            active: Mutex::new(HashSet::new()),
+            trackers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accepts connections until the listener fails.
+    pub async fn serve(self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!(
+            "[FIX] Acceptor {} listening on {}",
+            self.comp_id,
+            listener.local_addr()?
+        );
+        let acceptor = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let acceptor = Arc::clone(&acceptor);
+            tokio::spawn(async move {
+                gauge!("fix.connections").increment(1.0);
+                if let Err(e) = acceptor.handle(stream).await {
+                    eprintln!("[FIX] Connection {peer} failed: {e}");
+                }
+                gauge!("fix.connections").decrement(1.0);
+            });
+        }
+    }
+
+    async fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let (mut reader, writer) = stream.into_split();
+        let mut buffer = Vec::with_capacity(4096);
+        let logon = loop {
+            match FixMessage::decode(&buffer) {
+                Decoded::Message(message, used) => {
+                    buffer.drain(..used);
+                    break message;
+                }
+                Decoded::Garbled(skip) => {
+                    buffer.drain(..skip);
+                }
+                Decoded::Incomplete => {
+                    if reader.read_buf(&mut buffer).await? == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        let Some(mut session) = self.logon(&logon, writer).await? else {
+            return Ok(());
+        };
+        let result = match session.on_logon(&logon).await {
+            Ok(true) => session.run(&mut reader, buffer).await,
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.release(session);
+        result
+    }
+
+    /// Validates the Logon and claims the session. Returns `None` if the
+    /// logon was refused; the refusal has then already been sent.
+    async fn logon(
+        &self,
+        logon: &FixMessage,
+        mut writer: OwnedWriteHalf,
+    ) -> io::Result<Option<FixSession<'_>>> {
+        let target = logon
+            .get(tag::SENDER_COMP_ID)
+            .unwrap_or_default()
+            .to_string();
+        let accepted = self.check_logon(logon).and_then(|accepted| {
+            if lock(&self.active).insert(target.clone()) {
+                Ok(accepted)
+            } else {
+                Err("session already logged on".to_string())
+            }
+        });
+        let (user_id, heartbeat) = match accepted {
+            Ok(accepted) => accepted,
+            Err(text) => {
+                counter!("fix.logons_refused").increment(1);
+                let logout = FixMessage::new("5")
+                    .with(tag::SENDER_COMP_ID, &self.comp_id)
+                    .with(tag::TARGET_COMP_ID, &target)
+                    .with(tag::MSG_SEQ_NUM, 1)
+                    .with(tag::SENDING_TIME, utc_timestamp(now_ms()))
+                    .with(tag::TEXT, text);
+                writer.write_all(&logout.encode()).await?;
+                return Ok(None);
+            }
+        };
+
+        let session = format!("{}-{}", self.comp_id, target);
+        let store = match SessionStore::open(&self.store_dir, &session) {
+            Ok(store) => store,
+            Err(e) => {
+                lock(&self.active).remove(&target);
+                return Err(e);
+            }
+        };
+        let orders = lock(&self.trackers).remove(&target).unwrap_or_default();
+        Ok(Some(FixSession {
+            acceptor: self,
+            private: self.gateway.open_session(user_id),
+            target,
+            writer,
+            store,
+            orders,
+            heartbeat: Duration::from_secs(heartbeat),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            test_request: None,
+            recovering_to: None,
+        }))
+    }
+
+    /// The user and heartbeat interval of an acceptable Logon, or why it is
+    /// refused.
+    fn check_logon(&self, logon: &FixMessage) -> Result<(u64, u64), String> {
+        if logon.msg_type() != "A" {
+            return Err("first message must be Logon".to_string());
+        }
+        if logon.get(tag::SENDER_COMP_ID).is_none_or(str::is_empty) {
+            return Err("SenderCompID missing".to_string());
+        }
+        if logon.get(tag::TARGET_COMP_ID) != Some(self.comp_id.as_str()) {
+            return Err(format!("TargetCompID must be {}", self.comp_id));
+        }
+        if logon.parse::<u64>(tag::MSG_SEQ_NUM).is_err() {
+            return Err("MsgSeqNum missing".to_string());
+        }
+        let heartbeat = logon
+            .parse::<u64>(tag::HEART_BT_INT)
+            .ok()
+            .filter(|&seconds| seconds > 0)
+            .ok_or_else(|| "HeartBtInt missing".to_string())?;
+        let user_id = logon
+            .get(tag::PASSWORD)
+            .and_then(|password| self.gateway.authenticate(password))
+            .ok_or_else(|| "authentication failed".to_string())?;
+        Ok((user_id, heartbeat))
+    }
+
+    /// Keeps the session's open orders for its next logon and frees it.
+    fn release(&self, mut session: FixSession<'_>) {
+        self.gateway.close_session(&session.private);
+        lock(&self.trackers).insert(session.target.clone(), std::mem::take(&mut session.orders));
+        lock(&self.active).remove(&session.target);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// One logged-on connection.
+struct FixSession<'a> {
+    acceptor: &'a FixAcceptor,
+    /// The user's private events from the order gateway.
+    private: GatewaySession,
+    /// The counterparty's CompID.
+    target: String,
+    writer: OwnedWriteHalf,
+    store: SessionStore,
+    orders: OrderTracker,
+    heartbeat: Duration,
+    last_sent: Instant,
+    last_received: Instant,
+    /// TestReqID sent while waiting for the counterparty to show it is alive.
+    test_request: Option<String>,
+    /// Highest sequence number seen beyond a gap, until the gap is filled.
+    recovering_to: Option<u64>,
+}
+
+impl<'a> FixSession<'a> {
+    /// Answers the Logon, resetting or checking sequence numbers. Returns
+    /// `false` if the session ended instead.
+    async fn on_logon(&mut self, logon: &FixMessage) -> io::Result<bool> {
+        let reset = logon.flag(tag::RESET_SEQ_NUM_FLAG);
+        if reset {
+            self.store.reset()?;
+        }
+        let sequence = logon.parse::<u64>(tag::MSG_SEQ_NUM).unwrap_or_default();
+        let expected = self.store.next_incoming;
+        if sequence < expected {
+            let text = format!("MsgSeqNum too low, expecting {expected} but received {sequence}");
+            self.send(FixMessage::new("5").with(tag::TEXT, text))
+                .await?;
+            return Ok(false);
+        }
+
+        let mut reply = FixMessage::new("A")
+            .with(tag::ENCRYPT_METHOD, 0)
+            .with(tag::HEART_BT_INT, self.heartbeat.as_secs());
+        if reset {
+            reply.push(tag::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        self.send(reply).await?;
+        if sequence > expected {
+            self.request_resend(sequence).await?;
+        } else {
+            self.store.set_next_incoming(sequence + 1)?;
+        }
+        println!(
+            "[FIX] {} logged on as user {}",
+            self.target, self.private.user_id
+        );
+        Ok(true)
+    }
+
+    async fn run(&mut self, reader: &mut OwnedReadHalf, mut buffer: Vec<u8>) -> io::Result<()> {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            loop {
+                match FixMessage::decode(&buffer) {
+                    Decoded::Message(message, used) => {
+                        buffer.drain(..used);
+                        self.last_received = Instant::now();
+                        if !self.on_message(message).await? {
+                            return Ok(());
+                        }
+                    }
+                    Decoded::Garbled(skip) => {
+                        counter!("fix.garbled_messages").increment(1);
+                        buffer.drain(..skip);
+                    }
+                    Decoded::Incomplete => break,
+                }
+            }
+            tokio::select! {
+                read = reader.read_buf(&mut buffer) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+                private = self.private.messages.recv() => match private {
+                    Some(message) => self.on_gateway(message).await?,
+                    None => {
+                        self.send(FixMessage::new("5").with(tag::TEXT, "session fell behind")).await?;
+                        return Ok(());
+                    }
+                },
+                _ = tick.tick() => {
+                    if !self.on_tick().await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn on_tick(&mut self) -> io::Result<bool> {
+        if self.last_sent.elapsed() >= self.heartbeat {
+            self.send(FixMessage::new("0")).await?;
+        }
+        let silent = self.last_received.elapsed();
+        match &self.test_request {
+            None if silent >= self.heartbeat + self.heartbeat / 5 => {
+                let id = now_ms().to_string();
+                self.send(FixMessage::new("1").with(tag::TEST_REQ_ID, &id))
+                    .await?;
+                self.test_request = Some(id);
+            }
+            Some(_) if silent >= self.heartbeat * 2 + self.heartbeat / 5 => {
+                self.send(FixMessage::new("5").with(tag::TEXT, "heartbeat timeout"))
+                    .await?;
+                return Ok(false);
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Sequences, persists and writes a message.
+    async fn send(&mut self, message: FixMessage) -> io::Result<()> {
+        let sequence = self.store.next_outgoing;
+        let now = utc_timestamp(now_ms());
+        let mut stamped = self.header(message.msg_type(), sequence, &now);
+        stamped.fields.extend(message.body().cloned());
+        let bytes = stamped.encode();
+        self.store.record_sent(sequence, &bytes)?;
+        self.writer.write_all(&bytes).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn header(&self, msg_type: &str, sequence: u64, sending_time: &str) -> FixMessage {
+        FixMessage::new(msg_type)
+            .with(tag::SENDER_COMP_ID, &self.acceptor.comp_id)
+            .with(tag::TARGET_COMP_ID, &self.target)
+            .with(tag::MSG_SEQ_NUM, sequence)
+            .with(tag::SENDING_TIME, sending_time)
+    }
+
+    /// Returns `false` once the session is over.
+    async fn on_message(&mut self, message: FixMessage) -> io::Result<bool> {
+        let Ok(sequence) = message.parse::<u64>(tag::MSG_SEQ_NUM) else {
+            self.send(FixMessage::new("5").with(tag::TEXT, "MsgSeqNum missing"))
+                .await?;
+            return Ok(false);
+        };
+        let expected = self.store.next_incoming;
+
+        // A SequenceReset in reset mode applies whatever its own number.
+        if message.msg_type() == "4" && !message.flag(tag::GAP_FILL_FLAG) {
+            match message.parse::<u64>(tag::NEW_SEQ_NO) {
+                Ok(next) if next >= expected => self.store.set_next_incoming(next)?,
+                _ => {
+                    self.reject(&message, FixError::InvalidValue(tag::NEW_SEQ_NO))
+                        .await?
+                }
+            }
+            return Ok(true);
+        }
+        if sequence > expected {
+            self.request_resend(sequence).await?;
+            return Ok(true);
+        }
+        if sequence < expected {
+            if message.flag(tag::POSS_DUP_FLAG) {
+                return Ok(true);
+            }
+            let text = format!("MsgSeqNum too low, expecting {expected} but received {sequence}");
+            self.send(FixMessage::new("5").with(tag::TEXT, text))
+                .await?;
+            return Ok(false);
+        }
+        if message.get(tag::SENDER_COMP_ID) != Some(self.target.as_str())
+            || message.get(tag::TARGET_COMP_ID) != Some(self.acceptor.comp_id.as_str())
+        {
+            let reject = FixMessage::new("3")
+                .with(tag::REF_SEQ_NUM, sequence)
+                .with(tag::SESSION_REJECT_REASON, 9)
+                .with(tag::TEXT, "CompID problem");
+            self.send(reject).await?;
+            self.send(FixMessage::new("5").with(tag::TEXT, "CompID problem"))
+                .await?;
+            return Ok(false);
+        }
+
+        let next = match message.msg_type() {
+            "4" => message
+                .parse::<u64>(tag::NEW_SEQ_NO)
+                .ok()
+                .filter(|&next| next > sequence)
+                .unwrap_or(sequence + 1),
+            _ => sequence + 1,
+        };
+        self.store.set_next_incoming(next)?;
+        if self.recovering_to.is_some_and(|to| next > to) {
+            self.recovering_to = None;
+        }
+
+        match message.msg_type() {
+            "0" => {
+                if message.get(tag::TEST_REQ_ID).is_some() {
+                    self.test_request = None;
+                }
+            }
+            "1" => {
+                let id = message
+                    .get(tag::TEST_REQ_ID)
+                    .unwrap_or_default()
+                    .to_string();
+                self.send(FixMessage::new("0").with(tag::TEST_REQ_ID, id))
+                    .await?;
+            }
+            "2" => {
+                let range = message
+                    .parse::<u64>(tag::BEGIN_SEQ_NO)
+                    .and_then(|from| Ok((from, message.parse::<u64>(tag::END_SEQ_NO)?)));
+                match range {
+                    Ok((from, to)) => self.resend(from, to).await?,
+                    Err(e) => self.reject(&message, e).await?,
+                }
+            }
+            "3" | "4" | "A" => {}
+            "5" => {
+                self.send(FixMessage::new("5")).await?;
+                println!("[FIX] {} logged out", self.target);
+                return Ok(false);
+            }
+            "D" => self.new_order(&message).await?,
+            "F" => self.cancel(&message).await?,
+            "G" => self.cancel_replace(&message).await?,
+            other => {
+                let reject = FixMessage::new("j")
+                    .with(tag::REF_SEQ_NUM, sequence)
+                    .with(tag::REF_MSG_TYPE, other)
+                    .with(tag::BUSINESS_REJECT_REASON, 3)
+                    .with(tag::TEXT, "unsupported message type");
+                self.send(reject).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Asks for everything from the first missing message on, unless a
+    /// request for this gap is already outstanding.
+    async fn request_resend(&mut self, received: u64) -> io::Result<()> {
+        let outstanding = self.recovering_to.is_some();
+        self.recovering_to = Some(self.recovering_to.unwrap_or(0).max(received));
+        if outstanding {
+            return Ok(());
+        }
+        let request = FixMessage::new("2")
+            .with(tag::BEGIN_SEQ_NO, self.store.next_incoming)
+            .with(tag::END_SEQ_NO, 0);
+        self.send(request).await
+    }
+
+    /// Answers a ResendRequest. Application messages are resent as possible
+    /// duplicates; runs of session messages become a single gap fill.
+    async fn resend(&mut self, from: u64, to: u64) -> io::Result<()> {
+        let last = self.store.next_outgoing - 1;
+        let to = if to == 0 || to > last { last } else { to };
+        if from == 0 || from > to {
+            return Ok(());
+        }
+        counter!("fix.resends").increment(1);
+        let mut gap_start = None;
+        for (sequence, bytes) in self.store.sent(from, to)? {
+            let Decoded::Message(original, _) = FixMessage::decode(&bytes) else {
+                continue;
+            };
+            if matches!(original.msg_type(), "0" | "1" | "2" | "3" | "4" | "5" | "A") {
+                gap_start.get_or_insert(sequence);
+                continue;
+            }
+            if let Some(start) = gap_start.take() {
+                self.gap_fill(start, sequence).await?;
+            }
+            let now = utc_timestamp(now_ms());
+            let mut resent = self
+                .header(original.msg_type(), sequence, &now)
+                .with(tag::POSS_DUP_FLAG, "Y");
+            if let Some(sent_at) = original.get(tag::SENDING_TIME) {
+                resent.push(tag::ORIG_SENDING_TIME, sent_at);
+            }
+            resent.fields.extend(original.body().cloned());
+            self.writer.write_all(&resent.encode()).await?;
+        }
+        if let Some(start) = gap_start {
+            self.gap_fill(start, to + 1).await?;
+        }
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn gap_fill(&mut self, sequence: u64, next: u64) -> io::Result<()> {
+        let now = utc_timestamp(now_ms());
+        let fill = self
+            .header("4", sequence, &now)
+            .with(tag::POSS_DUP_FLAG, "Y")
+            .with(tag::GAP_FILL_FLAG, "Y")
+            .with(tag::NEW_SEQ_NO, next);
+        self.writer.write_all(&fill.encode()).await
+    }
+
+    async fn reject(&mut self, message: &FixMessage, error: FixError) -> io::Result<()> {
+        counter!("fix.session_rejects").increment(1);
+        let reject = FixMessage::new("3")
+            .with(
+                tag::REF_SEQ_NUM,
+                message.get(tag::MSG_SEQ_NUM).unwrap_or("0"),
+            )
+            .with(tag::REF_TAG_ID, error.tag())
+            .with(tag::REF_MSG_TYPE, message.msg_type())
+            .with(tag::SESSION_REJECT_REASON, error.session_reject_reason())
+            .with(tag::TEXT, error);
+        self.send(reject).await
+    }
+
+    fn instrument(&self, message: &FixMessage) -> Result<Option<&Instrument>, FixError> {
+        Ok(self.acceptor.instruments.get(message.require(tag::SYMBOL)?))
+    }
+
+    async fn new_order(&mut self, message: &FixMessage) -> io::Result<()> {
+        let cl_ord_id = match message.require(tag::CL_ORD_ID) {
+            Ok(id) => id.to_string(),
+            Err(e) => return self.reject(message, e).await,
+        };
+        let order = match self.instrument(message).and_then(|instrument| {
+            instrument
+                .map(|instrument| new_order_single(message, instrument, self.private.user_id))
+                .transpose()
+        }) {
+            Ok(Some(order)) => order,
+            Ok(None) => {
+                let reason = RejectReason::UnknownSymbol;
+                let report = rejected_order(message, &cl_ord_id, ord_rej_reason(reason), reason);
+                return self.send(report).await;
+            }
+            Err(e) => return self.reject(message, e).await,
+        };
+        if self.orders.cl_ord_ids.contains_key(&cl_ord_id) {
+            let report = rejected_order(message, &cl_ord_id, 6, "duplicate ClOrdID");
+            return self.send(report).await;
+        }
+
+        counter!("fix.orders").increment(1);
+        let tracked = FixOrder {
+            cl_ord_id: cl_ord_id.clone(),
+            symbol: order.symbol.clone(),
+            side: order.side,
+            price: order.price,
+            quantity: order.quantity,
+            cum_qty: 0,
+            cum_notional: Decimal::ZERO,
+            executions: 0,
+            placed: false,
+            pending: None,
+        };
+        match self.acceptor.gateway.submit(self.private.user_id, order) {
+            Ok(order_id) => {
+                self.orders.insert(order_id, tracked);
+                Ok(())
+            }
+            Err(reason) => {
+                let report = rejected_order(message, &cl_ord_id, ord_rej_reason(reason), reason);
+                self.send(report).await
+            }
+        }
+    }
+
+    async fn cancel(&mut self, message: &FixMessage) -> io::Result<()> {
+        let cl_ord_id = match message.require(tag::CL_ORD_ID) {
+            Ok(id) => id.to_string(),
+            Err(e) => return self.reject(message, e).await,
+        };
+        let Some(order_id) = self.orders.resolve(message) else {
+            let reason = RejectReason::UnknownOrder;
+            let reject = cancel_reject(message, None, &cl_ord_id, PendingKind::Cancel, 1, reason);
+            return self.send(reject).await;
+        };
+        let symbol = self.orders.orders[&order_id].symbol.clone();
+        let command = OrderCommand::Cancel {
+            symbol,
+            order_id,
+            user_id: self.private.user_id,
+            timestamp: now_ms(),
+        };
+        self.forward(message, order_id, cl_ord_id, PendingKind::Cancel, command)
+            .await
+    }
+
+    /// Maps to Titan's amend when only the quantity goes down, keeping
+    /// queue priority, and to a replace otherwise.
+    async fn cancel_replace(&mut self, message: &FixMessage) -> io::Result<()> {
+        let cl_ord_id = match message.require(tag::CL_ORD_ID) {
+            Ok(id) => id.to_string(),
+            Err(e) => return self.reject(message, e).await,
+        };
+        let Some(order_id) = self.orders.resolve(message) else {
+            let reason = RejectReason::UnknownOrder;
+            let reject = cancel_reject(message, None, &cl_ord_id, PendingKind::Replace, 1, reason);
+            return self.send(reject).await;
+        };
+        let instrument = match self.instrument(message) {
+            Ok(Some(instrument)) => instrument,
+            Ok(None) => {
+                let reason = RejectReason::UnknownSymbol;
+                let reject = cancel_reject(
+                    message,
+                    Some(order_id),
+                    &cl_ord_id,
+                    PendingKind::Replace,
+                    cxl_rej_reason(reason),
+                    reason,
+                );
+                return self.send(reject).await;
+            }
+            Err(e) => return self.reject(message, e).await,
+        };
+        let requested = parse_quantity(message, tag::ORDER_QTY, instrument).and_then(|quantity| {
+            let price = match message.get(tag::PRICE) {
+                Some(_) => Some(parse_price(message, instrument)?),
+                None => None,
+            };
+            Ok((quantity, price))
+        });
+        let (quantity, price) = match requested {
+            Ok(requested) => requested,
+            Err(e) => return self.reject(message, e).await,
+        };
+
+        let order = &self.orders.orders[&order_id];
+        let price = price.unwrap_or(order.price);
+        if quantity <= order.cum_qty {
+            let reject = cancel_reject(
+                message,
+                Some(order_id),
+                &cl_ord_id,
+                PendingKind::Replace,
+                99,
+                "quantity must exceed the quantity already filled",
+            );
+            return self.send(reject).await;
+        }
+        let leaves = quantity - order.cum_qty;
+        let (symbol, user_id, timestamp) = (order.symbol.clone(), self.private.user_id, now_ms());
+        let command = if price == order.price && leaves < order.leaves_qty() {
+            OrderCommand::Amend {
+                symbol,
+                order_id,
+                user_id,
+                quantity: leaves,
+                timestamp,
+            }
+        } else {
+            OrderCommand::Replace {
+                symbol,
+                order_id,
+                user_id,
+                price,
+                quantity: leaves,
+                timestamp,
+            }
+        };
+        self.forward(message, order_id, cl_ord_id, PendingKind::Replace, command)
+            .await
+    }
+
+    async fn forward(
+        &mut self,
+        message: &FixMessage,
+        order_id: u64,
+        cl_ord_id: String,
+        kind: PendingKind,
+        command: OrderCommand,
+    ) -> io::Result<()> {
+        if self.orders.orders[&order_id].pending.is_some() {
+            let reject = cancel_reject(
+                message,
+                Some(order_id),
+                &cl_ord_id,
+                kind,
+                3,
+                "cancel or replace already pending",
+            );
+            return self.send(reject).await;
+        }
+        match self.acceptor.gateway.send(self.private.user_id, command) {
+            Ok(()) => {
+                if let Some(order) = self.orders.orders.get_mut(&order_id) {
+                    order.pending = Some(PendingRequest { cl_ord_id, kind });
+                }
+                Ok(())
+            }
+            Err(reason) => {
+                let reject = cancel_reject(
+                    message,
+                    Some(order_id),
+                    &cl_ord_id,
+                    kind,
+                    cxl_rej_reason(reason),
+                    reason,
+                );
+                self.send(reject).await
+            }
+        }
+    }
+
+    async fn on_gateway(&mut self, message: GatewayMessage) -> io::Result<()> {
+        match self.orders.report(message, &self.acceptor.instruments) {
+            Some(report) => self.send(report).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl PendingKind {
+    /// CxlRejResponseTo (434).
+    fn response_to(self) -> &'static str {
+        match self {
+            PendingKind::Cancel => "1",
+            PendingKind::Replace => "2",
+        }
+    }
+}
+
+fn ord_status(order: &FixOrder) -> &'static str {
+    if order.cum_qty == 0 {
+        "0"
+    } else if order.leaves_qty() == 0 {
+        "2"
+    } else {
+        "1"
+    }
+}
+
+/// An ExecutionReport for `order` as it stands.
+fn execution_report(
+    order_id: u64,
+    order: &mut FixOrder,
+    exec_type: &str,
+    instrument: Option<&Instrument>,
+) -> FixMessage {
+    order.executions += 1;
+    let status = match exec_type {
+        "4" => "4",
+        "8" => "8",
+        _ => ord_status(order),
+    };
+    let leaves = match status {
+        "4" | "8" => 0,
+        _ => order.leaves_qty(),
+    };
+    let mut report = FixMessage::new("8")
+        .with(tag::ORDER_ID, order_id)
+        .with(tag::CL_ORD_ID, &order.cl_ord_id)
+        .with(tag::EXEC_ID, format!("{order_id}-{}", order.executions))
+        .with(tag::EXEC_TYPE, exec_type)
+        .with(tag::ORD_STATUS, status)
+        .with(tag::SYMBOL, &order.symbol)
+        .with(tag::SIDE, side_code(order.side))
+        .with(tag::TRANSACT_TIME, utc_timestamp(now_ms()));
+    match instrument {
+        Some(instrument) => {
+            let avg_px = if order.cum_qty == 0 {
+                Decimal::ZERO
+            } else {
+                order.cum_notional / instrument.quantity_to_decimal(order.cum_qty)
+            };
+            report.push(
+                tag::ORDER_QTY,
+                instrument.quantity_to_decimal(order.quantity),
+            );
+            report.push(tag::PRICE, instrument.price_to_decimal(order.price));
+            report.push(tag::LEAVES_QTY, instrument.quantity_to_decimal(leaves));
+            report.push(tag::CUM_QTY, instrument.quantity_to_decimal(order.cum_qty));
+            report.push(tag::AVG_PX, avg_px.normalize());
+        }
+        None => {
+            report.push(tag::LEAVES_QTY, leaves);
+            report.push(tag::CUM_QTY, order.cum_qty);
+            report.push(tag::AVG_PX, 0);
+        }
+    }
+    report
+}
+
+/// An ExecutionReport rejecting a NewOrderSingle before it reached Titan.
+fn rejected_order(
+    message: &FixMessage,
+    cl_ord_id: &str,
+    ord_rej_reason: u32,
+    text: impl ToString,
+) -> FixMessage {
+    counter!("fix.orders_rejected").increment(1);
+    FixMessage::new("8")
+        .with(tag::ORDER_ID, "NONE")
+        .with(tag::CL_ORD_ID, cl_ord_id)
+        .with(tag::EXEC_ID, format!("R-{cl_ord_id}-{}", now_ms()))
+        .with(tag::EXEC_TYPE, "8")
+        .with(tag::ORD_STATUS, "8")
+        .with(tag::SYMBOL, message.get(tag::SYMBOL).unwrap_or_default())
+        .with(tag::SIDE, message.get(tag::SIDE).unwrap_or("1"))
+        .with(tag::LEAVES_QTY, 0)
+        .with(tag::CUM_QTY, 0)
+        .with(tag::AVG_PX, 0)
+        .with(tag::ORD_REJ_REASON, ord_rej_reason)
+        .with(tag::TEXT, text)
+}
+
+fn cancel_reject(
+    message: &FixMessage,
+    order_id: Option<u64>,
+    cl_ord_id: &str,
+    kind: PendingKind,
+    cxl_rej_reason: u32,
+    text: impl ToString,
+) -> FixMessage {
+    FixMessage::new("9")
+        .with(
+            tag::ORDER_ID,
+            order_id.map_or_else(|| "NONE".to_string(), |id| id.to_string()),
+        )
+        .with(tag::CL_ORD_ID, cl_ord_id)
+        .with(
+            tag::ORIG_CL_ORD_ID,
+            message.get(tag::ORIG_CL_ORD_ID).unwrap_or("NONE"),
+        )
+        .with(tag::ORD_STATUS, if order_id.is_some() { "0" } else { "8" })
+        .with(tag::CXL_REJ_RESPONSE_TO, kind.response_to())
+        .with(tag::CXL_REJ_REASON, cxl_rej_reason)
+        .with(tag::TEXT, text)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order_gateway::Liquidity;
+
+    fn instrument() -> Instrument {
+        Instrument {
+            symbol: "BTC-USDT".into(),
+            base_asset: "BTC".into(),
+            quote_asset: "USDT".into(),
+            price_scale: 2,
+            quantity_scale: 3,
+            tick_size: 1,
+            lot_size: 1,
+            min_notional: Decimal::ZERO,
+        }
+    }
+
+    fn heartbeat() -> FixMessage {
+        FixMessage::new("0")
+            .with(tag::SENDER_COMP_ID, "CLIENT")
+            .with(tag::TARGET_COMP_ID, "EXCH")
+            .with(tag::MSG_SEQ_NUM, 1)
+    }
+
+    /// A framed message claiming `body_length` bytes of body.
+    fn with_body_length(body_length: &str) -> Vec<u8> {
+        format!("8={BEGIN_STRING}\x019={body_length}\x0135=0\x01").into_bytes()
+    }
+
+    fn store_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fix-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn encoded_messages_decode_to_themselves() {
+        let message = heartbeat();
+        let bytes = message.encode();
+        assert_eq!(
+            FixMessage::decode(&bytes),
+            Decoded::Message(message, bytes.len())
+        );
+    }
+
+    #[test]
+    fn partial_messages_wait_for_more_bytes() {
+        let bytes = heartbeat().encode();
+        for end in [0, 5, 12, bytes.len() - 1] {
+            assert_eq!(FixMessage::decode(&bytes[..end]), Decoded::Incomplete);
+        }
+    }
+
+    #[test]
+    fn bad_checksums_skip_the_whole_message() {
+        let mut bytes = heartbeat().encode();
+        let len = bytes.len();
+        bytes[len - 2] = if bytes[len - 2] == b'0' { b'1' } else { b'0' };
+        assert_eq!(FixMessage::decode(&bytes), Decoded::Garbled(len));
+    }
+
+    #[test]
+    fn garbage_is_skipped_up_to_the_next_begin_string() {
+        let mut bytes = b"junk".to_vec();
+        bytes.extend(heartbeat().encode());
+        assert_eq!(FixMessage::decode(&bytes), Decoded::Garbled(4));
+    }
+
+    #[test]
+    fn oversized_body_lengths_are_garbled() {
+        let mut bytes = with_body_length(&(MAX_BODY_LENGTH + 1).to_string());
+        let next = bytes.len();
+        bytes.extend(heartbeat().encode());
+        assert_eq!(FixMessage::decode(&bytes), Decoded::Garbled(next));
+
+        let bytes = with_body_length(&usize::MAX.to_string());
+        assert_eq!(FixMessage::decode(&bytes), Decoded::Garbled(bytes.len()));
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        assert_eq!(utc_timestamp(0), "19700101-00:00:00.000");
+        let leap = parse_utc_timestamp("20240229-23:59:58.123").unwrap();
+        assert_eq!(utc_timestamp(leap), "20240229-23:59:58.123");
+        assert_eq!(
+            parse_utc_timestamp("20000301-00:00:00"),
+            Some(951_868_800_000)
+        );
+        assert_eq!(parse_utc_timestamp("20241301-00:00:00"), None);
+    }
+
+    #[test]
+    fn new_order_singles_translate_to_orders() {
+        let message = FixMessage::new("D")
+            .with(tag::SIDE, 2)
+            .with(tag::ORDER_QTY, "1.5")
+            .with(tag::ORD_TYPE, 2)
+            .with(tag::PRICE, "100.25")
+            .with(tag::EXEC_INST, "6")
+            .with(tag::MAX_FLOOR, "0.5");
+        let order = new_order_single(&message, &instrument(), 7).unwrap();
+        assert_eq!(
+            (order.user_id, order.side, order.price, order.quantity),
+            (7, Side::Sell, 10_025, 1_500)
+        );
+        assert_eq!(order.time_in_force, TimeInForce::PostOnly);
+        assert_eq!(order.display_quantity, Some(500));
+
+        let ioc = message.clone().with(tag::TIME_IN_FORCE, 3);
+        assert_eq!(
+            new_order_single(&ioc, &instrument(), 7).unwrap_err(),
+            FixError::InvalidValue(tag::EXEC_INST)
+        );
+        let unpriced = FixMessage::new("D")
+            .with(tag::SIDE, 1)
+            .with(tag::ORDER_QTY, "1")
+            .with(tag::ORD_TYPE, 2);
+        assert_eq!(
+            new_order_single(&unpriced, &instrument(), 7).unwrap_err(),
+            FixError::MissingTag(tag::PRICE)
+        );
+    }
+
+    #[test]
+    fn session_stores_survive_reopening() {
+        let dir = store_dir();
+        let mut store = SessionStore::open(&dir, "EXCH-CLIENT").unwrap();
+        assert_eq!((store.next_incoming, store.next_outgoing), (1, 1));
+        store.record_sent(1, b"first").unwrap();
+        store.record_sent(2, b"second").unwrap();
+        store.set_next_incoming(5).unwrap();
+        drop(store);
+
+        let mut store = SessionStore::open(&dir, "EXCH-CLIENT").unwrap();
+        assert_eq!((store.next_incoming, store.next_outgoing), (5, 3));
+        assert_eq!(store.sent(2, 0).unwrap(), vec![]);
+        assert_eq!(store.sent(2, 9).unwrap(), vec![(2, b"second".to_vec())]);
+
+        store.reset().unwrap();
+        assert_eq!((store.next_incoming, store.next_outgoing), (1, 1));
+        assert!(store.sent(1, u64::MAX).unwrap().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cancels_resolve_by_client_order_id_then_order_id() {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(
+            1_000,
+            FixOrder {
+                cl_ord_id: "a".into(),
+                symbol: "BTC-USDT".into(),
+                side: Side::Buy,
+                price: 100,
+                quantity: 1,
+                cum_qty: 0,
+                cum_notional: Decimal::ZERO,
+                executions: 0,
+                placed: true,
+                pending: None,
+            },
+        );
+        let by_cl_ord_id = FixMessage::new("F").with(tag::ORIG_CL_ORD_ID, "a");
+        let by_order_id = FixMessage::new("F").with(tag::ORDER_ID, 1_000);
+        let unknown = FixMessage::new("F").with(tag::ORDER_ID, 1_001);
+        assert_eq!(tracker.resolve(&by_cl_ord_id), Some(1_000));
+        assert_eq!(tracker.resolve(&by_order_id), Some(1_000));
+        assert_eq!(tracker.resolve(&unknown), None);
+
+        tracker.remove(1_000);
+        assert_eq!(tracker.resolve(&by_cl_ord_id), None);
+    }
+
+    /// Order 1_000 as entered on the session, before Titan has placed it.
+    fn tracked(quantity: u64) -> OrderTracker {
+        let mut tracker = OrderTracker::default();
+        tracker.insert(
+            1_000,
+            FixOrder {
+                cl_ord_id: "a".into(),
+                symbol: "BTC-USDT".into(),
+                side: Side::Buy,
+                price: 10_000,
+                quantity,
+                cum_qty: 0,
+                cum_notional: Decimal::ZERO,
+                executions: 0,
+                placed: false,
+                pending: None,
+            },
+        );
+        tracker
+    }
+
+    fn registry() -> InstrumentRegistry {
+        let mut registry = InstrumentRegistry::new();
+        registry.register(instrument());
+        registry
+    }
+
+    #[test]
+    fn unfilled_immediate_or_cancel_remainders_end_cancelled() {
+        let instruments = registry();
+        let mut tracker = tracked(5_000);
+        let placed = Order {
+            order_id: 1_000,
+            user_id: 7,
+            symbol: "BTC-USDT".into(),
+            side: Side::Buy,
+            price: 10_000,
+            quantity: 5_000,
+            timestamp: 1,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            display_quantity: None,
+            self_trade_prevention: SelfTradePrevention::None,
+        };
+        let fill = Fill {
+            symbol: "BTC-USDT".into(),
+            trade_id: 1,
+            order_id: 1_000,
+            side: Side::Buy,
+            price: 10_000,
+            quantity: 2_000,
+            liquidity: Liquidity::Taker,
+            fee: Decimal::ZERO,
+            timestamp: 1,
+        };
+        let cancelled = GatewayMessage::Cancelled {
+            order_id: 1_000,
+            symbol: "BTC-USDT".into(),
+            remaining_quantity: 3_000,
+            timestamp: 1,
+        };
+
+        let messages = [
+            GatewayMessage::OrderPlaced(placed),
+            GatewayMessage::Fill(fill),
+            cancelled,
+        ];
+        let reports: Vec<_> = messages
+            .into_iter()
+            .map(|message| tracker.report(message, &instruments).unwrap())
+            .collect();
+        let statuses: Vec<_> = reports
+            .iter()
+            .map(|report| (report.get(tag::EXEC_TYPE), report.get(tag::ORD_STATUS)))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (Some("0"), Some("0")),
+                (Some("F"), Some("1")),
+                (Some("4"), Some("4")),
+            ]
+        );
+        assert_eq!(reports[2].get(tag::LEAVES_QTY), Some("0.000"));
+        assert_eq!(reports[2].get(tag::CUM_QTY), Some("2.000"));
+        assert!(tracker.orders.is_empty() && tracker.cl_ord_ids.is_empty());
+    }
+
+    #[test]
+    fn titan_rejecting_an_order_with_a_cancel_pending_rejects_the_order() {
+        let instruments = registry();
+        let mut tracker = tracked(1_000);
+        tracker.orders.get_mut(&1_000).unwrap().pending = Some(PendingRequest {
+            cl_ord_id: "b".into(),
+            kind: PendingKind::Cancel,
+        });
+        let rejected = GatewayMessage::Rejected {
+            order_id: 1_000,
+            symbol: "BTC-USDT".into(),
+            reason: RejectReason::InsufficientMargin,
+            timestamp: 1,
+        };
+
+        let report = tracker.report(rejected, &instruments).unwrap();
+        assert_eq!(report.msg_type(), "8");
+        assert_eq!(report.get(tag::ORD_STATUS), Some("8"));
+        assert_eq!(report.get(tag::CL_ORD_ID), Some("a"));
+        assert!(tracker.orders.is_empty() && tracker.cl_ord_ids.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// Static definition of a tradable symbol.
+///
+/// Titan works in integer units: an `Order.price` is the decimal price
+/// multiplied by `10^price_scale`, and `Order.quantity` likewise uses
+/// `quantity_scale`. Sentinel and the price feed work in `Decimal`; the
+/// conversions below are the only place the two meet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Instrument {
+    pub symbol: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    /// Decimal places in a `u64` price. With scale 2, `5_000_012` is 50000.12.
+    pub price_scale: u32,
+    /// Decimal places in a `u64` quantity.
+    pub quantity_scale: u32,
+    /// Smallest price increment, in scaled price units.
+    pub tick_size: u64,
+    /// Smallest quantity increment, in scaled quantity units.
+    pub lot_size: u64,
+    /// Smallest accepted price * quantity, in quote asset units.
+    pub min_notional: Decimal,
+}
+
+impl Instrument {
+    pub fn price_to_decimal(&self, price: u64) -> Decimal {
+        Decimal::from_i128_with_scale(price as i128, self.price_scale)
+    }
+
+    pub fn quantity_to_decimal(&self, quantity: u64) -> Decimal {
+        Decimal::from_i128_with_scale(quantity as i128, self.quantity_scale)
+    }
+
+    /// Converts a decimal price to scaled units, rounding to the nearest unit.
+    /// Returns `None` for negative or out-of-range prices.
+    pub fn price_from_decimal(&self, price: Decimal) -> Option<u64> {
+        price
+            .checked_mul(Decimal::from(10u64.checked_pow(self.price_scale)?))?
+            .round()
+            .to_u64()
+    }
+
+    /// Converts a decimal quantity to scaled units, rounding to the nearest unit.
+    pub fn quantity_from_decimal(&self, quantity: Decimal) -> Option<u64> {
+        quantity
+            .checked_mul(Decimal::from(10u64.checked_pow(self.quantity_scale)?))?
+            .round()
+            .to_u64()
+    }
+
+    /// Value of `quantity` at `price` in quote asset units, or `None` if it
+    /// is too large for a `Decimal`.
+    pub fn notional(&self, price: u64, quantity: u64) -> Option<Decimal> {
+        self.price_to_decimal(price)
+            .checked_mul(self.quantity_to_decimal(quantity))
+    }
+
+    pub fn is_on_tick(&self, price: u64) -> bool {
+        price.is_multiple_of(self.tick_size)
+    }
+
+    pub fn is_on_lot(&self, quantity: u64) -> bool {
+        quantity.is_multiple_of(self.lot_size)
+    }
+}
+
+/// Lookup of every listed instrument by symbol.
+#[derive(Debug, Default, Clone)]
+pub struct InstrumentRegistry {
+    instruments: HashMap<String, Instrument>,
+}
+
+impl InstrumentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces an instrument definition.
+    pub fn register(&mut self, instrument: Instrument) {
+        assert!(instrument.tick_size > 0, "tick size must be non-zero");
+        assert!(instrument.lot_size > 0, "lot size must be non-zero");
+        self.instruments
+            .insert(instrument.symbol.clone(), instrument);
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&Instrument> {
+        self.instruments.get(symbol)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Instrument> {
+        self.instruments.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instruments.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn btc() -> Instrument {
+        Instrument {
+            symbol: "BTC-USDT".into(),
+            base_asset: "BTC".into(),
+            quote_asset: "USDT".into(),
+            price_scale: 2,
+            quantity_scale: 3,
+            tick_size: 5,
+            lot_size: 10,
+            min_notional: Decimal::from(10),
+        }
+    }
+
+    fn decimal(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn converts_between_scaled_units_and_decimals() {
+        let instrument = btc();
+        assert_eq!(instrument.price_to_decimal(5_000_012), decimal("50000.12"));
+        assert_eq!(instrument.quantity_to_decimal(1_500), decimal("1.5"));
+        assert_eq!(
+            instrument.price_from_decimal(decimal("50000.125")),
+            Some(5_000_012)
+        );
+        assert_eq!(instrument.quantity_from_decimal(decimal("0.25")), Some(250));
+        assert_eq!(instrument.price_from_decimal(decimal("-1")), None);
+        assert_eq!(instrument.notional(5_000_000, 500), Some(decimal("25000")));
+        assert_eq!(instrument.notional(u64::MAX, u64::MAX), None);
+    }
+
+    #[test]
+    fn checks_tick_and_lot_sizes() {
+        let instrument = btc();
+        assert!(instrument.is_on_tick(100));
+        assert!(!instrument.is_on_tick(101));
+        assert!(instrument.is_on_lot(30));
+        assert!(!instrument.is_on_lot(35));
+    }
+
+    #[test]
+    fn registry_replaces_by_symbol() {
+        let mut registry = InstrumentRegistry::new();
+        registry.register(btc());
+        let mut relisted = btc();
+        relisted.tick_size = 1;
+        registry.register(relisted);
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.get("BTC-USDT").unwrap().tick_size, 1);
+        assert!(registry.get("ETH-USDT").is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "tick size must be non-zero")]
+    fn registry_refuses_a_zero_tick() {
+        let mut instrument = btc();
+        instrument.tick_size = 0;
+        InstrumentRegistry::new().register(instrument);
+    }
+}
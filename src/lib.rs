@@ -0,0 +1,12 @@
+pub mod binary;
+pub mod disruptor;
+pub mod fees;
+pub mod fix;
+pub mod instruments;
+pub mod market_data;
+pub mod market_data_server;
+pub mod oracle;
+pub mod order_gateway;
+pub mod sentinel;
+pub mod titan;
+pub mod types;
@@ -0,0 +1,473 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::titan::OrderBook;
+use crate::types::Side;
+
+/// One change to the resting orders of a book. Quantities are the displayed
+/// quantity; hidden iceberg reserve is never published.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderUpdate {
+    /// An order joined the back of the queue at `price`. Iceberg refreshes
+    /// are published as a new add for the same order id.
+    Add {
+        order_id: u64,
+        side: Side,
+        price: u64,
+        quantity: u64,
+    },
+    /// The order's displayed quantity changed; it keeps its queue position.
+    Modify {
+        order_id: u64,
+        side: Side,
+        price: u64,
+        quantity: u64,
+    },
+    Delete {
+        order_id: u64,
+        side: Side,
+        price: u64,
+    },
+    /// A trade took place. The resting orders it touched are updated by the
+    /// `Modify` and `Delete` messages that follow.
+    Trade {
+        trade_id: u64,
+        buy_order_id: u64,
+        sell_order_id: u64,
+        aggressor_side: Side,
+        price: u64,
+        quantity: u64,
+    },
+}
+
+/// Aggregated displayed quantity per price, best price first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepthSnapshot {
+    pub symbol: String,
+    /// Sequence of the last depth update the snapshot includes.
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub bids: Vec<(u64, u64)>,
+    pub asks: Vec<(u64, u64)>,
+}
+
+/// Levels that changed since the previous update. A quantity of zero removes
+/// the level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepthUpdate {
+    pub symbol: String,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub bids: Vec<(u64, u64)>,
+    pub asks: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookOrder {
+    pub order_id: u64,
+    pub price: u64,
+    pub quantity: u64,
+}
+
+/// Every resting order in priority order, best price first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderSnapshot {
+    pub symbol: String,
+    /// Sequence of the last order message the snapshot includes.
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub bids: Vec<BookOrder>,
+    pub asks: Vec<BookOrder>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderMessage {
+    pub symbol: String,
+    pub sequence: u64,
+    pub timestamp: u64,
+    pub update: OrderUpdate,
+}
+
+/// Titan's market data output. Depth (L2) and order-by-order (L3) messages
+/// are sequenced independently per symbol, each starting at 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketData {
+    DepthSnapshot(DepthSnapshot),
+    DepthUpdate(DepthUpdate),
+    OrderSnapshot(OrderSnapshot),
+    Order(OrderMessage),
+}
+
+impl MarketData {
+    pub fn symbol(&self) -> &str {
+        match self {
+            MarketData::DepthSnapshot(snapshot) => &snapshot.symbol,
+            MarketData::DepthUpdate(update) => &update.symbol,
+            MarketData::OrderSnapshot(snapshot) => &snapshot.symbol,
+            MarketData::Order(message) => &message.symbol,
+        }
+    }
+}
+
+/// Sequences the order changes of one book into L3 messages and the depth
+/// levels they touched into L2 updates.
+#[derive(Debug)]
+pub struct MarketDataPublisher {
+    symbol: String,
+    depth_sequence: u64,
+    order_sequence: u64,
+}
+
+impl MarketDataPublisher {
+    pub fn new(symbol: impl Into<String>) -> Self {
+        Self {
+            symbol: symbol.into(),
+            depth_sequence: 0,
+            order_sequence: 0,
+        }
+    }
+
+    /// Publishes `changes`, which must be everything that happened to `book`
+    /// since the previous call, as one L3 message each followed by a single
+    /// L2 update read from the book's current state.
+    pub fn publish(
+        &mut self,
+        book: &OrderBook,
+        changes: Vec<OrderUpdate>,
+        timestamp: u64,
+    ) -> Vec<MarketData> {
+        let (mut bid_levels, mut ask_levels) = (BTreeSet::new(), BTreeSet::new());
+        let mut messages = Vec::with_capacity(changes.len() + 1);
+        for update in changes {
+            if let OrderUpdate::Add { side, price, .. }
+            | OrderUpdate::Modify { side, price, .. }
+            | OrderUpdate::Delete { side, price, .. } = update
+            {
+                match side {
+                    Side::Buy => bid_levels.insert(price),
+                    Side::Sell => ask_levels.insert(price),
+                };
+            }
+            self.order_sequence += 1;
+            messages.push(MarketData::Order(OrderMessage {
+                symbol: self.symbol.clone(),
+                sequence: self.order_sequence,
+                timestamp,
+                update,
+            }));
+        }
+
+        if !bid_levels.is_empty() || !ask_levels.is_empty() {
+            let bids = bid_levels
+                .into_iter()
+                .rev()
+                .map(|price| (price, book.level_quantity(Side::Buy, price)))
+                .collect();
+            let asks = ask_levels
+                .into_iter()
+                .map(|price| (price, book.level_quantity(Side::Sell, price)))
+                .collect();
+            self.depth_sequence += 1;
+            messages.push(MarketData::DepthUpdate(DepthUpdate {
+                symbol: self.symbol.clone(),
+                sequence: self.depth_sequence,
+                timestamp,
+                bids,
+                asks,
+            }));
+        }
+        messages
+    }
+
+    pub fn depth_snapshot(&self, book: &OrderBook, timestamp: u64) -> DepthSnapshot {
+        DepthSnapshot {
+            symbol: self.symbol.clone(),
+            sequence: self.depth_sequence,
+            timestamp,
+            bids: book.depth(Side::Buy, usize::MAX),
+            asks: book.depth(Side::Sell, usize::MAX),
+        }
+    }
+
+    pub fn order_snapshot(&self, book: &OrderBook, timestamp: u64) -> OrderSnapshot {
+        OrderSnapshot {
+            symbol: self.symbol.clone(),
+            sequence: self.order_sequence,
+            timestamp,
+            bids: book.visible_orders(Side::Buy).collect(),
+            asks: book.visible_orders(Side::Sell).collect(),
+        }
+    }
+}
+
+/// A sequence number was skipped; the consumer must re-request a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub expected: u64,
+    pub received: u64,
+}
+
+impl fmt::Display for SequenceGap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sequence gap: expected {}, received {}",
+            self.expected, self.received
+        )
+    }
+}
+
+impl std::error::Error for SequenceGap {}
+
+/// Consumer-side depth book kept current from a snapshot and the updates
+/// after it.
+#[derive(Debug, Clone)]
+pub struct DepthView {
+    symbol: String,
+    /// `None` until a snapshot has been applied.
+    sequence: Option<u64>,
+    bids: BTreeMap<u64, u64>,
+    asks: BTreeMap<u64, u64>,
+}
+
+impl DepthView {
+    pub fn new(symbol: impl Into<String>) -> Self {
+        Self {
+            symbol: symbol.into(),
+            sequence: None,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+        }
+    }
+
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    pub fn apply_snapshot(&mut self, snapshot: &DepthSnapshot) {
+        self.bids = snapshot.bids.iter().copied().collect();
+        self.asks = snapshot.asks.iter().copied().collect();
+        self.sequence = Some(snapshot.sequence);
+    }
+
+    /// Applies the next update. Updates already covered by the snapshot are
+    /// ignored; a skipped sequence, or any update before the first snapshot,
+    /// is a gap and leaves the view unchanged.
+    pub fn apply_update(&mut self, update: &DepthUpdate) -> Result<(), SequenceGap> {
+        let Some(sequence) = self.sequence else {
+            return Err(SequenceGap {
+                expected: 0,
+                received: update.sequence,
+            });
+        };
+        if update.sequence <= sequence {
+            return Ok(());
+        }
+        if update.sequence != sequence + 1 {
+            return Err(SequenceGap {
+                expected: sequence + 1,
+                received: update.sequence,
+            });
+        }
+        for (levels, changes) in [
+            (&mut self.bids, &update.bids),
+            (&mut self.asks, &update.asks),
+        ] {
+            for &(price, quantity) in changes {
+                if quantity == 0 {
+                    levels.remove(&price);
+                } else {
+                    levels.insert(price, quantity);
+                }
+            }
+        }
+        self.sequence = Some(update.sequence);
+        Ok(())
+    }
+
+    /// Current state as a snapshot, limited to `levels` per side.
+    pub fn snapshot(&self, levels: usize, timestamp: u64) -> DepthSnapshot {
+        DepthSnapshot {
+            symbol: self.symbol.clone(),
+            sequence: self.sequence.unwrap_or_default(),
+            timestamp,
+            bids: self
+                .bids
+                .iter()
+                .rev()
+                .take(levels)
+                .map(|(&p, &q)| (p, q))
+                .collect(),
+            asks: self
+                .asks
+                .iter()
+                .take(levels)
+                .map(|(&p, &q)| (p, q))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::instruments::Instrument;
+    use crate::types::{Order, OrderType, SelfTradePrevention, TimeInForce};
+
+    fn book() -> OrderBook {
+        let mut book = OrderBook::new(Instrument {
+            symbol: "BTC-USDT".into(),
+            base_asset: "BTC".into(),
+            quote_asset: "USDT".into(),
+            price_scale: 0,
+            quantity_scale: 0,
+            tick_size: 1,
+            lot_size: 1,
+            min_notional: Decimal::ZERO,
+        });
+        book.record_changes();
+        book
+    }
+
+    fn limit(order_id: u64, side: Side, price: u64, quantity: u64) -> Order {
+        Order {
+            order_id,
+            user_id: order_id,
+            symbol: "BTC-USDT".into(),
+            side,
+            price,
+            quantity,
+            timestamp: order_id,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            display_quantity: None,
+            self_trade_prevention: SelfTradePrevention::None,
+        }
+    }
+
+    fn update(sequence: u64, bids: Vec<(u64, u64)>, asks: Vec<(u64, u64)>) -> DepthUpdate {
+        DepthUpdate {
+            symbol: "BTC-USDT".into(),
+            sequence,
+            timestamp: 0,
+            bids,
+            asks,
+        }
+    }
+
+    /// A view initialised from a snapshot at sequence 3.
+    fn view() -> DepthView {
+        let mut view = DepthView::new("BTC-USDT");
+        view.apply_snapshot(&DepthSnapshot {
+            symbol: "BTC-USDT".into(),
+            sequence: 3,
+            timestamp: 0,
+            bids: vec![(99, 5), (98, 2)],
+            asks: vec![(101, 4)],
+        });
+        view
+    }
+
+    #[test]
+    fn updates_before_a_snapshot_are_a_gap() {
+        let mut view = DepthView::new("BTC-USDT");
+        assert_eq!(
+            view.apply_update(&update(1, vec![(99, 1)], vec![])),
+            Err(SequenceGap {
+                expected: 0,
+                received: 1
+            })
+        );
+        assert_eq!(view.sequence(), None);
+    }
+
+    #[test]
+    fn updates_covered_by_the_snapshot_are_ignored() {
+        let mut view = view();
+        assert_eq!(view.apply_update(&update(3, vec![(99, 1)], vec![])), Ok(()));
+        assert_eq!(view.snapshot(5, 0).bids, [(99, 5), (98, 2)]);
+        assert_eq!(view.sequence(), Some(3));
+    }
+
+    #[test]
+    fn next_update_changes_and_removes_levels() {
+        let mut view = view();
+        view.apply_update(&update(4, vec![(99, 0), (97, 1)], vec![(101, 6)]))
+            .unwrap();
+        let snapshot = view.snapshot(1, 9);
+        assert_eq!(snapshot.sequence, 4);
+        assert_eq!(snapshot.bids, [(98, 2)]);
+        assert_eq!(snapshot.asks, [(101, 6)]);
+        assert_eq!(view.snapshot(5, 9).bids, [(98, 2), (97, 1)]);
+    }
+
+    #[test]
+    fn a_skipped_sequence_leaves_the_view_unchanged() {
+        let mut view = view();
+        assert_eq!(
+            view.apply_update(&update(5, vec![(99, 0)], vec![])),
+            Err(SequenceGap {
+                expected: 4,
+                received: 5
+            })
+        );
+        assert_eq!(view.sequence(), Some(3));
+        assert_eq!(view.snapshot(5, 0).bids, [(99, 5), (98, 2)]);
+    }
+
+    #[test]
+    fn publisher_sequences_order_messages_and_depth_updates() {
+        let mut book = book();
+        let mut publisher = MarketDataPublisher::new("BTC-USDT");
+        let mut iceberg = limit(1, Side::Sell, 100, 10);
+        iceberg.display_quantity = Some(4);
+        book.submit(iceberg);
+        book.submit(limit(2, Side::Sell, 101, 5));
+        let changes = book.take_changes();
+        let messages = publisher.publish(&book, changes, 1);
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(
+            &messages[2],
+            MarketData::DepthUpdate(DepthUpdate { sequence: 1, asks, .. })
+                if asks == &[(100, 4), (101, 5)]
+        ));
+
+        book.submit(limit(3, Side::Buy, 100, 4));
+        let changes = book.take_changes();
+        let messages = publisher.publish(&book, changes, 2);
+        let updates: Vec<_> = messages
+            .iter()
+            .filter_map(|message| match message {
+                MarketData::Order(message) => Some((message.sequence, message.update.clone())),
+                _ => None,
+            })
+            .collect();
+        // The slice is traded away, then the refreshed slice joins the back of
+        // the queue; the hidden reserve is never shown.
+        assert!(matches!(
+            updates[..],
+            [
+                (3, OrderUpdate::Trade { quantity: 4, .. }),
+                (4, OrderUpdate::Delete { order_id: 1, .. }),
+                (
+                    5,
+                    OrderUpdate::Add {
+                        order_id: 1,
+                        quantity: 4,
+                        ..
+                    }
+                ),
+            ]
+        ));
+
+        let depth = publisher.depth_snapshot(&book, 3);
+        assert_eq!((depth.sequence, depth.asks), (2, vec![(100, 4), (101, 5)]));
+        let orders = publisher.order_snapshot(&book, 3);
+        assert_eq!(orders.sequence, 5);
+        assert_eq!(orders.asks.len(), 2);
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use metrics::{counter, gauge};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use crate::market_data::{DepthSnapshot, DepthUpdate, DepthView, MarketData, SequenceGap};
+use crate::types::{PriceUpdate, Side, SystemEvent};
+
+/// Per-connection queue between the subscription forwarders and the socket.
+const OUTBOUND_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Trades,
+    Depth,
+    MarkPrice,
+}
+
+/// Requests a client may send, as JSON text frames tagged by `op`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Subscribe { channel: Channel, symbol: String },
+    Unsubscribe { channel: Channel, symbol: String },
+    Ping,
+}
+
+/// A public trade. Prices and quantities are in the instrument's scaled
+/// integer units, as in Titan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicTrade {
+    pub symbol: String,
+    pub trade_id: u64,
+    pub price: u64,
+    pub quantity: u64,
+    pub aggressor_side: Side,
+    pub timestamp: u64,
+}
+
+/// Messages sent to clients, as JSON text frames tagged by `type`.
+///
+/// A depth subscription starts with a `depth_snapshot`; every later
+/// `depth_update` carries the next sequence number. A fresh snapshot is sent
+/// whenever the connection fell behind the feed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Subscribed { channel: Channel, symbol: String },
+    Unsubscribed { channel: Channel, symbol: String },
+    DepthSnapshot(DepthSnapshot),
+    DepthUpdate(DepthUpdate),
+    Trade(PublicTrade),
+    MarkPrice(PriceUpdate),
+    Heartbeat { timestamp: u64 },
+    Pong,
+    Error { message: String },
+}
+
+impl ServerMessage {
+    /// Channel and symbol of feed messages; `None` for control messages.
+    pub(crate) fn route(&self) -> Option<(Channel, &str)> {
+        match self {
+            ServerMessage::DepthSnapshot(snapshot) => Some((Channel::Depth, &snapshot.symbol)),
+            ServerMessage::DepthUpdate(update) => Some((Channel::Depth, &update.symbol)),
+            ServerMessage::Trade(trade) => Some((Channel::Trades, &trade.symbol)),
+            ServerMessage::MarkPrice(update) => Some((Channel::MarkPrice, &update.symbol)),
+            _ => None,
+        }
+    }
+}
+
+/// Public state of one symbol and the live stream that follows it. Updates
+/// are broadcast while the lock is held, so a connection that subscribes
+/// before reading the state misses nothing in between.
+struct SymbolFeed {
+    depth: DepthView,
+    mark_price: Option<PriceUpdate>,
+    updates: broadcast::Sender<Arc<ServerMessage>>,
+}
+
+impl SymbolFeed {
+    fn publish(&self, message: ServerMessage) {
+        // No receivers just means nobody is subscribed.
+        let _ = self.updates.send(Arc::new(message));
+    }
+}
+
+/// Fans Titan's market data and trade and price events out to WebSocket
+/// subscribers.
+///
+/// Each symbol starts from an empty book at depth sequence 0, which matches a
+/// freshly started Titan. When the hub reports a [`SequenceGap`], request a
+/// snapshot from Titan; depth updates are refused until it arrives.
+pub struct MarketDataHub {
+    feeds: HashMap<String, Mutex<SymbolFeed>>,
+}
+
+impl MarketDataHub {
+    /// `capacity` is how many messages per symbol a slow connection may fall
+    /// behind before it is resynchronised.
+    pub fn new<S: Into<String>>(symbols: impl IntoIterator<Item = S>, capacity: usize) -> Self {
+        let feeds = symbols
+            .into_iter()
+            .map(|symbol| {
+                let symbol = symbol.into();
+                let mut depth = DepthView::new(symbol.clone());
+                depth.apply_snapshot(&DepthSnapshot {
+                    symbol: symbol.clone(),
+                    sequence: 0,
+                    timestamp: 0,
+                    bids: Vec::new(),
+                    asks: Vec::new(),
+                });
+                let feed = SymbolFeed {
+                    depth,
+                    mark_price: None,
+                    updates: broadcast::channel(capacity).0,
+                };
+                (symbol, Mutex::new(feed))
+            })
+            .collect();
+        Self { feeds }
+    }
+
+    fn feed(&self, symbol: &str) -> Option<MutexGuard<'_, SymbolFeed>> {
+        self.feeds
+            .get(symbol)
+            .map(|feed| feed.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    /// Applies one message from Titan's market data stream. Order-by-order
+    /// messages are not published here.
+    pub fn on_market_data(&self, message: &MarketData) -> Result<(), SequenceGap> {
+        let Some(mut feed) = self.feed(message.symbol()) else {
+            return Ok(());
+        };
+        match message {
+            MarketData::DepthSnapshot(snapshot) => {
+                feed.depth.apply_snapshot(snapshot);
+                feed.publish(ServerMessage::DepthSnapshot(snapshot.clone()));
+            }
+            MarketData::DepthUpdate(update) => {
+                let current = feed.depth.sequence();
+                feed.depth.apply_update(update)?;
+                if feed.depth.sequence() != current {
+                    feed.publish(ServerMessage::DepthUpdate(update.clone()));
+                }
+            }
+            MarketData::OrderSnapshot(_) | MarketData::Order(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Publishes trades and mark prices from the platform event stream.
+    pub fn on_event(&self, event: &SystemEvent) {
+        match event {
+            SystemEvent::OrderExecuted(execution) => {
+                if let Some(feed) = self.feed(&execution.symbol) {
+                    feed.publish(ServerMessage::Trade(PublicTrade {
+                        symbol: execution.symbol.clone(),
+                        trade_id: execution.trade_id,
+                        price: execution.price,
+                        quantity: execution.quantity,
+                        aggressor_side: execution.aggressor_side,
+                        timestamp: execution.timestamp,
+                    }));
+                }
+            }
+            SystemEvent::PriceUpdate {
+                symbol,
+                price,
+                timestamp,
+            } => {
+                if let Some(mut feed) = self.feed(symbol) {
+                    let update = PriceUpdate {
+                        symbol: symbol.clone(),
+                        mark_price: *price,
+                        timestamp: *timestamp,
+                    };
+                    feed.mark_price = Some(update.clone());
+                    feed.publish(ServerMessage::MarkPrice(update));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn depth_snapshot(&self, symbol: &str) -> Option<DepthSnapshot> {
+        self.feed(symbol)
+            .map(|feed| feed.depth.snapshot(usize::MAX, now_ms()))
+    }
+
+    pub(crate) fn mark_price(&self, symbol: &str) -> Option<PriceUpdate> {
+        self.feed(symbol).and_then(|feed| feed.mark_price.clone())
+    }
+
+    pub(crate) fn subscribe(
+        &self,
+        symbol: &str,
+    ) -> Option<broadcast::Receiver<Arc<ServerMessage>>> {
+        self.feed(symbol).map(|feed| feed.updates.subscribe())
+    }
+}
+
+/// Public WebSocket server for trades, depth and mark prices.
+pub struct MarketDataServer {
+    hub: Arc<MarketDataHub>,
+    heartbeat_interval: Duration,
+}
+
+impl MarketDataServer {
+    pub fn new(hub: Arc<MarketDataHub>, heartbeat_interval: Duration) -> Self {
+        Self {
+            hub,
+            heartbeat_interval,
+        }
+    }
+
+    /// Accepts connections until the listener fails.
+    pub async fn serve(self, addr: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!(
+            "[MarketData] WebSocket server listening on {}",
+            listener.local_addr()?
+        );
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                gauge!("market_data.connections").increment(1.0);
+                if let Err(e) = server.handle(stream).await {
+                    eprintln!("[MarketData] Connection {peer} failed: {e}");
+                }
+                gauge!("market_data.connections").decrement(1.0);
+            });
+        }
+    }
+
+    async fn handle(&self, stream: TcpStream) -> Result<(), WsError> {
+        let (mut sink, mut source) = tokio_tungstenite::accept_async(stream).await?.split();
+        let (outbound_tx, mut outbound) = mpsc::channel(OUTBOUND_CAPACITY);
+        let mut subscriptions = HashMap::new();
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.heartbeat_interval,
+            self.heartbeat_interval,
+        );
+
+        let result = loop {
+            let sent = tokio::select! {
+                incoming = source.next() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        let replies = self.on_request(text.as_str(), &mut subscriptions, &outbound_tx);
+                        send_all(&mut sink, &replies).await
+                    }
+                    Some(Ok(Message::Close(_))) | None => break Ok(()),
+                    // Pings are answered by tungstenite itself.
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) => break Err(e),
+                },
+                Some(forwarded) = outbound.recv() => {
+                    let messages = self.on_forwarded(forwarded, &mut subscriptions);
+                    send_all(&mut sink, &messages).await
+                }
+                _ = heartbeat.tick() => {
+                    send_all(&mut sink, &[ServerMessage::Heartbeat { timestamp: now_ms() }]).await
+                }
+            };
+            if let Err(e) = sent {
+                break Err(e);
+            }
+        };
+
+        for subscription in subscriptions.values() {
+            subscription.forwarder.abort();
+        }
+        result
+    }
+
+    fn on_request(
+        &self,
+        text: &str,
+        subscriptions: &mut HashMap<String, Subscription>,
+        outbound: &mpsc::Sender<Forwarded>,
+    ) -> Vec<ServerMessage> {
+        let request = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                return vec![ServerMessage::Error {
+                    message: format!("invalid request: {e}"),
+                }];
+            }
+        };
+        match request {
+            ClientMessage::Ping => vec![ServerMessage::Pong],
+            ClientMessage::Subscribe { channel, symbol } => {
+                self.subscribe(channel, symbol, subscriptions, outbound)
+            }
+            ClientMessage::Unsubscribe { channel, symbol } => {
+                if let Some(subscription) = subscriptions.get_mut(&symbol) {
+                    subscription.channels.remove(&channel);
+                    if subscription.channels.is_empty() {
+                        subscription.forwarder.abort();
+                        subscriptions.remove(&symbol);
+                    }
+                }
+                vec![ServerMessage::Unsubscribed { channel, symbol }]
+            }
+        }
+    }
+
+    /// Subscribes to the symbol's stream first, then reads the state the
+    /// client starts from. Stream messages already covered by that state are
+    /// dropped by sequence number.
+    fn subscribe(
+        &self,
+        channel: Channel,
+        symbol: String,
+        subscriptions: &mut HashMap<String, Subscription>,
+        outbound: &mpsc::Sender<Forwarded>,
+    ) -> Vec<ServerMessage> {
+        if !subscriptions.contains_key(&symbol) {
+            let Some(updates) = self.hub.subscribe(&symbol) else {
+                return vec![ServerMessage::Error {
+                    message: format!("unknown symbol {symbol}"),
+                }];
+            };
+            let subscription = Subscription {
+                channels: HashSet::new(),
+                depth_sequence: 0,
+                forwarder: forward(symbol.clone(), updates, outbound.clone()),
+            };
+            subscriptions.insert(symbol.clone(), subscription);
+        }
+        let Some(subscription) = subscriptions.get_mut(&symbol) else {
+            return Vec::new();
+        };
+        subscription.channels.insert(channel);
+
+        let mut replies = vec![ServerMessage::Subscribed {
+            channel,
+            symbol: symbol.clone(),
+        }];
+        match channel {
+            Channel::Depth => {
+                if let Some(snapshot) = self.hub.depth_snapshot(&symbol) {
+                    subscription.depth_sequence = snapshot.sequence;
+                    replies.push(ServerMessage::DepthSnapshot(snapshot));
+                }
+            }
+            Channel::MarkPrice => {
+                replies.extend(self.hub.mark_price(&symbol).map(ServerMessage::MarkPrice));
+            }
+            Channel::Trades => {}
+        }
+        replies
+    }
+
+    /// Filters a stream message down to what this connection subscribed to.
+    fn on_forwarded(
+        &self,
+        forwarded: Forwarded,
+        subscriptions: &mut HashMap<String, Subscription>,
+    ) -> Vec<ServerMessage> {
+        match forwarded {
+            Forwarded::Message(message) => {
+                let Some((channel, symbol)) = message.route() else {
+                    return Vec::new();
+                };
+                let Some(subscription) = subscriptions.get_mut(symbol) else {
+                    return Vec::new();
+                };
+                if !subscription.channels.contains(&channel) {
+                    return Vec::new();
+                }
+                match &*message {
+                    ServerMessage::DepthUpdate(update)
+                        if update.sequence <= subscription.depth_sequence =>
+                    {
+                        return Vec::new();
+                    }
+                    ServerMessage::DepthUpdate(update) => {
+                        subscription.depth_sequence = update.sequence;
+                    }
+                    ServerMessage::DepthSnapshot(snapshot) => {
+                        subscription.depth_sequence = snapshot.sequence;
+                    }
+                    _ => {}
+                }
+                vec![(*message).clone()]
+            }
+            Forwarded::Lagged(symbol) => {
+                counter!("market_data.resyncs").increment(1);
+                let Some(subscription) = subscriptions.get_mut(&symbol) else {
+                    return Vec::new();
+                };
+                if !subscription.channels.contains(&Channel::Depth) {
+                    return Vec::new();
+                }
+                let Some(snapshot) = self.hub.depth_snapshot(&symbol) else {
+                    return Vec::new();
+                };
+                subscription.depth_sequence = snapshot.sequence;
+                vec![ServerMessage::DepthSnapshot(snapshot)]
+            }
+        }
+    }
+}
+
+/// One connection's interest in one symbol.
+struct Subscription {
+    channels: HashSet<Channel>,
+    /// Sequence of the last depth message sent to the client.
+    depth_sequence: u64,
+    forwarder: JoinHandle<()>,
+}
+
+pub(crate) enum Forwarded {
+    Message(Arc<ServerMessage>),
+    /// The connection fell behind and missed messages for this symbol.
+    Lagged(String),
+}
+
+/// Copies a symbol's broadcast stream into the connection's queue.
+pub(crate) fn forward(
+    symbol: String,
+    mut updates: broadcast::Receiver<Arc<ServerMessage>>,
+    outbound: mpsc::Sender<Forwarded>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let next = match updates.recv().await {
+                Ok(message) => Forwarded::Message(message),
+                Err(RecvError::Lagged(_)) => Forwarded::Lagged(symbol.clone()),
+                Err(RecvError::Closed) => return,
+            };
+            if outbound.send(next).await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+type WsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+async fn send_all(sink: &mut WsSink, messages: &[ServerMessage]) -> Result<(), WsError> {
+    for message in messages {
+        let text = serde_json::to_string(message).expect("server messages always serialize");
+        sink.send(Message::text(text)).await?;
+        counter!("market_data.messages_sent").increment(1);
+    }
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::types::Execution;
+
+    fn depth_update(sequence: u64, bids: Vec<(u64, u64)>) -> MarketData {
+        MarketData::DepthUpdate(DepthUpdate {
+            symbol: "BTC-USDT".into(),
+            sequence,
+            timestamp: 0,
+            bids,
+            asks: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn client_messages_are_tagged_by_op() {
+        let subscribe: ClientMessage = serde_json::from_str(
+            r#"{"op":"subscribe","channel":"mark_price","symbol":"BTC-USDT"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            subscribe,
+            ClientMessage::Subscribe {
+                channel: Channel::MarkPrice,
+                symbol: "BTC-USDT".into()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<ClientMessage>(r#"{"op":"ping"}"#).unwrap(),
+            ClientMessage::Ping
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerMessage::Pong).unwrap(),
+            r#"{"type":"pong"}"#
+        );
+    }
+
+    #[test]
+    fn depth_updates_are_published_in_sequence() {
+        let hub = MarketDataHub::new(["BTC-USDT"], 16);
+        let mut updates = hub.subscribe("BTC-USDT").unwrap();
+        hub.on_market_data(&depth_update(1, vec![(100, 5)]))
+            .unwrap();
+        // Already applied, so not published again.
+        hub.on_market_data(&depth_update(1, vec![(100, 5)]))
+            .unwrap();
+        assert_eq!(
+            hub.on_market_data(&depth_update(3, vec![(100, 0)])),
+            Err(SequenceGap {
+                expected: 2,
+                received: 3
+            })
+        );
+
+        let published = updates.try_recv().unwrap();
+        assert!(matches!(&*published, ServerMessage::DepthUpdate(update) if update.sequence == 1));
+        assert!(updates.try_recv().is_err());
+        assert_eq!(hub.depth_snapshot("BTC-USDT").unwrap().bids, [(100, 5)]);
+    }
+
+    #[test]
+    fn a_snapshot_resynchronises_the_feed() {
+        let hub = MarketDataHub::new(["BTC-USDT"], 16);
+        hub.on_market_data(&MarketData::DepthSnapshot(DepthSnapshot {
+            symbol: "BTC-USDT".into(),
+            sequence: 7,
+            timestamp: 0,
+            bids: vec![(99, 1)],
+            asks: Vec::new(),
+        }))
+        .unwrap();
+        hub.on_market_data(&depth_update(8, vec![(98, 2)])).unwrap();
+        let snapshot = hub.depth_snapshot("BTC-USDT").unwrap();
+        assert_eq!(
+            (snapshot.sequence, snapshot.bids),
+            (8, vec![(99, 1), (98, 2)])
+        );
+    }
+
+    #[test]
+    fn trades_and_mark_prices_come_from_events() {
+        let hub = MarketDataHub::new(["BTC-USDT"], 16);
+        let mut updates = hub.subscribe("BTC-USDT").unwrap();
+        hub.on_event(&SystemEvent::OrderExecuted(Execution {
+            trade_id: 3,
+            symbol: "BTC-USDT".into(),
+            buy_order_id: 1,
+            sell_order_id: 2,
+            buyer_user_id: 1,
+            seller_user_id: 2,
+            aggressor_side: Side::Sell,
+            price: 100,
+            quantity: 4,
+            timestamp: 5,
+            maker_fee: Decimal::ZERO,
+            taker_fee: Decimal::ZERO,
+        }));
+        hub.on_event(&SystemEvent::PriceUpdate {
+            symbol: "BTC-USDT".into(),
+            price: Decimal::from(101),
+            timestamp: 6,
+        });
+
+        assert!(matches!(
+            &*updates.try_recv().unwrap(),
+            ServerMessage::Trade(PublicTrade {
+                trade_id: 3,
+                quantity: 4,
+                aggressor_side: Side::Sell,
+                ..
+            })
+        ));
+        assert!(matches!(
+            &*updates.try_recv().unwrap(),
+            ServerMessage::MarkPrice(_)
+        ));
+        assert_eq!(
+            hub.mark_price("BTC-USDT").unwrap().mark_price,
+            Decimal::from(101)
+        );
+    }
+
+    #[test]
+    fn unlisted_symbols_are_ignored() {
+        let hub = MarketDataHub::new(["BTC-USDT"], 16);
+        assert!(hub.subscribe("ETH-USDT").is_none());
+        let update = MarketData::DepthUpdate(DepthUpdate {
+            symbol: "ETH-USDT".into(),
+            sequence: 5,
+            timestamp: 0,
+            bids: Vec::new(),
+            asks: Vec::new(),
+        });
+        assert_eq!(hub.on_market_data(&update), Ok(()));
+    }
+}
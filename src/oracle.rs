@@ -0,0 +1,1168 @@
+//! Oracle: append-only event store over RocksDB.
+//!
+//! Every `SystemEvent` is stored in an [`EventEnvelope`] under its global
+//! sequence number, starting at 1 and increasing by one per event. Keys are
+//! big-endian so RocksDB's byte order is sequence order, and values are
+//! JSON. Appends are synced to disk before they return.
+//!
+//! Each producer also numbers its own events, and each event records what
+//! caused it: the causation id is the sequence of the event it reacts to,
+//! and the correlation id is the sequence of the first event in that chain.
+//!
+//! The log outlives the code that wrote it. Each envelope records the
+//! [`SCHEMA_VERSION`] it was written with, and replay passes older events
+//! through the registered [`Upcasters`] until they reach the current shape.
+//! A change to `SystemEvent` or anything it contains that old JSON cannot
+//! deserialize into, such as a new field without `#[serde(default)]` or a
+//! rename, must bump `SCHEMA_VERSION`, register an upcaster from the
+//! previous version and add the new encoding to `tests/corpus`. So must a new
+//! event variant, with an upcaster that leaves older events as they are: each
+//! version's corpus then stays fixed, and builds that do not know the variant
+//! refuse the log instead of failing on the event.
+//!
+//! The vault folds every event into an [`OracleState`] and periodically
+//! stores it as a snapshot tagged with the sequence it covers. Opening a
+//! vault loads the newest snapshot whose SHA-256 state hash still matches
+//! and replays only the events after it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use metrics::counter;
+use rocksdb::{ColumnFamily, DB, Direction, IteratorMode, Options, WriteBatch, WriteOptions};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::fees::{self, RollingVolume};
+use crate::types::{
+    Account, AllocationPolicy, Order, OrderType, PriceBands, SessionSchedule, SessionState,
+    StopOrder, SystemEvent, TimeInForce,
+};
+
+/// Version of the envelope and `SystemEvent` encoding written by this build.
+/// Version 0 is the bare `SystemEvent` stored before events had envelopes;
+/// version 2 added `PositionUpdated` and `StopOrderTrailed`, version 3 the
+/// administrative `SessionScheduleChanged`, `PriceBandsChanged` and
+/// `AllocationChanged`.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Column family holding each producer's last stream sequence.
+const STREAMS: &str = "streams";
+/// Column family holding state snapshots by the sequence they cover.
+const SNAPSHOTS: &str = "snapshots";
+/// Events between snapshots unless set with
+/// [`OracleVault::with_snapshot_interval`].
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 10_000;
+/// Snapshots kept; older ones are deleted when a new one is written.
+const SNAPSHOTS_KEPT: usize = 3;
+
+#[derive(Debug)]
+pub enum OracleError {
+    Storage(rocksdb::Error),
+    /// A stored value could not be encoded or decoded.
+    Codec(serde_json::Error),
+    /// A key in the event log is not an 8-byte sequence number.
+    CorruptKey(Vec<u8>),
+    /// No stored event has this sequence.
+    UnknownSequence(u64),
+    /// The event was written with a schema version this build has no
+    /// upcaster for, or a newer one.
+    UnsupportedVersion {
+        sequence: u64,
+        version: u32,
+    },
+    /// An upcaster could not migrate the event.
+    Upcast {
+        sequence: u64,
+        version: u32,
+        reason: String,
+    },
+}
+
+impl fmt::Display for OracleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OracleError::Storage(e) => write!(f, "storage error: {e}"),
+            OracleError::Codec(e) => write!(f, "event codec error: {e}"),
+            OracleError::CorruptKey(key) => write!(f, "corrupt event key {key:?}"),
+            OracleError::UnknownSequence(sequence) => write!(f, "no event {sequence}"),
+            OracleError::UnsupportedVersion { sequence, version } => {
+                write!(
+                    f,
+                    "event {sequence} has unsupported schema version {version}"
+                )
+            }
+            OracleError::Upcast {
+                sequence,
+                version,
+                reason,
+            } => write!(
+                f,
+                "cannot upcast event {sequence} from schema version {version}: {reason}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OracleError {}
+
+impl From<rocksdb::Error> for OracleError {
+    fn from(e: rocksdb::Error) -> Self {
+        OracleError::Storage(e)
+    }
+}
+
+impl From<serde_json::Error> for OracleError {
+    fn from(e: serde_json::Error) -> Self {
+        OracleError::Codec(e)
+    }
+}
+
+/// Component that emitted an event. Each has its own stream sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Producer {
+    Titan,
+    Sentinel,
+    /// Order entry: the WebSocket, FIX and binary gateways.
+    Gateway,
+}
+
+impl Producer {
+    const ALL: [Producer; 3] = [Producer::Titan, Producer::Sentinel, Producer::Gateway];
+
+    fn key(self) -> &'static [u8] {
+        match self {
+            Producer::Titan => b"titan",
+            Producer::Sentinel => b"sentinel",
+            Producer::Gateway => b"gateway",
+        }
+    }
+}
+
+/// A stored event and its metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    /// Position in the whole log.
+    pub sequence: u64,
+    /// Position among the producer's own events, starting at 1.
+    pub stream_sequence: u64,
+    /// Version the event was written with. Replay upcasts older events, so
+    /// the other fields always have the current shape.
+    pub schema_version: u32,
+    pub producer: Producer,
+    /// Sequence of the event that started this chain; an event without a
+    /// cause starts its own.
+    pub correlation_id: u64,
+    /// Sequence of the event this one reacts to.
+    pub causation_id: Option<u64>,
+    pub event: SystemEvent,
+}
+
+impl EventEnvelope {
+    /// The cause to record for events this one leads to.
+    pub fn cause(&self) -> Cause {
+        Cause {
+            correlation_id: self.correlation_id,
+            causation_id: self.sequence,
+        }
+    }
+}
+
+/// What an appended event reacts to, taken from the triggering envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cause {
+    pub correlation_id: u64,
+    pub causation_id: u64,
+}
+
+/// Migrates a stored event from one schema version to the next. Receives
+/// the sequence the event is stored under and its JSON at the older version,
+/// and returns the JSON at the next one.
+pub type Upcaster = fn(sequence: u64, value: Value) -> Result<Value, String>;
+
+/// Upcasters by the schema version they migrate from.
+#[derive(Clone)]
+pub struct Upcasters(BTreeMap<u32, Upcaster>);
+
+impl Upcasters {
+    /// No upcasters: only events at the current version decode.
+    pub fn empty() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Uses `upcaster` to migrate events written at `from_version`,
+    /// replacing any registered before.
+    pub fn register(&mut self, from_version: u32, upcaster: Upcaster) -> &mut Self {
+        self.0.insert(from_version, upcaster);
+        self
+    }
+
+    /// Decodes an event as stored under `sequence`, upcasting it to the
+    /// current schema version.
+    pub fn decode(&self, sequence: u64, bytes: &[u8]) -> Result<EventEnvelope, OracleError> {
+        let mut value: Value = serde_json::from_slice(bytes)?;
+        let written = match value.get("schema_version") {
+            Some(version) => u32::deserialize(version)?,
+            None => 0,
+        };
+        if written > SCHEMA_VERSION {
+            return Err(OracleError::UnsupportedVersion {
+                sequence,
+                version: written,
+            });
+        }
+        for version in written..SCHEMA_VERSION {
+            let upcaster = self
+                .0
+                .get(&version)
+                .ok_or(OracleError::UnsupportedVersion { sequence, version })?;
+            value = upcaster(sequence, value).map_err(|reason| OracleError::Upcast {
+                sequence,
+                version,
+                reason,
+            })?;
+        }
+        if let Some(envelope) = value.as_object_mut() {
+            envelope.insert("schema_version".to_string(), json!(written));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Every upcaster this build knows.
+impl Default for Upcasters {
+    fn default() -> Self {
+        let mut upcasters = Self::empty();
+        upcasters.register(0, envelope_bare_event);
+        upcasters.register(1, unchanged);
+        upcasters.register(2, unchanged);
+        upcasters
+    }
+}
+
+/// For versions that only added event variants: older events are already
+/// in the next version's shape.
+fn unchanged(_sequence: u64, envelope: Value) -> Result<Value, String> {
+    Ok(envelope)
+}
+
+/// Version 0 to 1: wraps a bare `SystemEvent` in an envelope. The producer
+/// follows from the event; the stream position was never recorded and is
+/// left at 0, and each event starts its own correlation.
+fn envelope_bare_event(sequence: u64, event: Value) -> Result<Value, String> {
+    let variant = match &event {
+        Value::Object(fields) if fields.len() == 1 => fields.keys().next().map(String::as_str),
+        _ => None,
+    }
+    .ok_or("not a SystemEvent")?;
+    let producer = match variant {
+        "PositionOpened" | "PositionLiquidated" | "PriceUpdate" | "AccountUpdated" => {
+            Producer::Sentinel
+        }
+        _ => Producer::Titan,
+    };
+    Ok(json!({
+        "sequence": sequence,
+        "stream_sequence": 0,
+        "schema_version": 1,
+        "producer": producer,
+        "correlation_id": sequence,
+        "causation_id": null,
+        "event": event,
+    }))
+}
+
+/// Platform state rebuilt from the event log: what rests on each book and
+/// every account's collateral and positions.
+///
+/// Only what events report is known. Accounts appear with their first
+/// `AccountUpdated` or position event, and post-only-slide orders keep
+/// their submitted price because the slide is not reported.
+///
+/// The books here are for audit: queue priority and iceberg slices are not
+/// in the log, so Titan does not rebuild its books from them. It only takes
+/// the last trade id and traded volumes of each book, through
+/// `TitanEngine::resume`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OracleState {
+    /// Sequence of the last event applied.
+    pub sequence: u64,
+    pub books: BTreeMap<String, BookState>,
+    pub accounts: BTreeMap<u64, Account>,
+    pub mark_prices: BTreeMap<String, Decimal>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BookState {
+    pub session: SessionState,
+    /// Configuration set by administrators; defaults until first changed.
+    pub schedule: SessionSchedule,
+    pub price_bands: PriceBands,
+    pub allocation: AllocationPolicy,
+    /// Resting orders by id, with their remaining quantity.
+    pub orders: BTreeMap<u64, Order>,
+    /// Stop orders waiting for their trigger, by id.
+    pub stops: BTreeMap<u64, StopOrder>,
+    pub last_trade_id: u64,
+    pub last_trade_price: Option<u64>,
+    /// Each user's rolling traded volume in the symbol, in scaled units. The
+    /// fee engine sums these across symbols for its tiers.
+    pub volumes: BTreeMap<u64, RollingVolume>,
+}
+
+impl BookState {
+    /// Takes `quantity` off a resting order, removing it once nothing is left.
+    fn reduce(&mut self, order_id: u64, quantity: u64) {
+        if let Some(order) = self.orders.get_mut(&order_id) {
+            order.quantity = order.quantity.saturating_sub(quantity);
+            if order.quantity == 0 {
+                self.orders.remove(&order_id);
+            }
+        }
+    }
+}
+
+impl OracleState {
+    /// Applies the event stored under `sequence`. Events must be applied in
+    /// sequence order.
+    pub fn apply(&mut self, sequence: u64, event: &SystemEvent) {
+        self.sequence = sequence;
+        match event {
+            SystemEvent::OrderPlaced(order) => {
+                // Market, IOC and FOK remainders never rest, so only their
+                // fills against resting orders matter.
+                let rests = order.order_type == OrderType::Limit
+                    && !matches!(
+                        order.time_in_force,
+                        TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+                    );
+                if rests {
+                    self.book(&order.symbol)
+                        .orders
+                        .insert(order.order_id, order.clone());
+                }
+            }
+            SystemEvent::OrderExecuted(execution) => {
+                let book = self.book(&execution.symbol);
+                book.reduce(execution.buy_order_id, execution.quantity);
+                book.reduce(execution.sell_order_id, execution.quantity);
+                book.last_trade_id = execution.trade_id;
+                book.last_trade_price = Some(execution.price);
+                fees::record_volume(&mut book.volumes, execution);
+            }
+            SystemEvent::OrderCancelled {
+                order_id, symbol, ..
+            } => {
+                let book = self.book(symbol);
+                book.orders.remove(order_id);
+                book.stops.remove(order_id);
+            }
+            SystemEvent::OrderReplaced {
+                order_id,
+                symbol,
+                price,
+                quantity,
+                timestamp,
+                ..
+            } => {
+                if let Some(order) = self.book(symbol).orders.get_mut(order_id) {
+                    order.price = *price;
+                    order.quantity = *quantity;
+                    order.timestamp = *timestamp;
+                }
+            }
+            SystemEvent::OrderAmended {
+                order_id,
+                symbol,
+                quantity,
+                ..
+            } => {
+                if let Some(order) = self.book(symbol).orders.get_mut(order_id) {
+                    order.quantity = *quantity;
+                }
+            }
+            SystemEvent::SelfTradePrevented(prevented) => {
+                let book = self.book(&prevented.symbol);
+                book.reduce(prevented.taker_order_id, prevented.taker_quantity_cancelled);
+                book.reduce(prevented.maker_order_id, prevented.maker_quantity_cancelled);
+            }
+            SystemEvent::SessionStateChanged { symbol, to, .. } => {
+                self.book(symbol).session = *to;
+            }
+            SystemEvent::SessionScheduleChanged {
+                symbol, schedule, ..
+            } => {
+                self.book(symbol).schedule = schedule.clone();
+            }
+            SystemEvent::PriceBandsChanged { symbol, bands, .. } => {
+                self.book(symbol).price_bands = *bands;
+            }
+            SystemEvent::AllocationChanged { symbol, policy, .. } => {
+                self.book(symbol).allocation = *policy;
+            }
+            SystemEvent::StopOrderPlaced(stop) => {
+                self.book(&stop.order.symbol)
+                    .stops
+                    .insert(stop.order.order_id, stop.clone());
+            }
+            SystemEvent::StopOrderTriggered {
+                order_id, symbol, ..
+            } => {
+                self.book(symbol).stops.remove(order_id);
+            }
+            SystemEvent::StopOrderTrailed {
+                order_id,
+                symbol,
+                stop_price,
+                ..
+            } => {
+                if let Some(stop) = self.book(symbol).stops.get_mut(order_id) {
+                    stop.stop_price = *stop_price;
+                }
+            }
+            SystemEvent::PositionOpened {
+                user_id, position, ..
+            }
+            | SystemEvent::PositionUpdated {
+                user_id, position, ..
+            } => {
+                let positions = &mut self.account(*user_id).positions;
+                positions.retain(|p| p.symbol != position.symbol);
+                if !position.size.is_zero() {
+                    positions.push(position.clone());
+                }
+            }
+            SystemEvent::PositionLiquidated(liquidation) => {
+                self.account(liquidation.user_id)
+                    .positions
+                    .retain(|p| p.symbol != liquidation.symbol);
+            }
+            SystemEvent::PriceUpdate { symbol, price, .. } => {
+                self.mark_prices.insert(symbol.clone(), *price);
+            }
+            SystemEvent::AccountUpdated {
+                user_id,
+                collateral,
+                margin_ratio,
+                ..
+            } => {
+                let account = self.account(*user_id);
+                account.collateral = *collateral;
+                account.margin_ratio = *margin_ratio;
+            }
+            SystemEvent::OrderRejected { .. }
+            | SystemEvent::AuctionIndicative { .. }
+            | SystemEvent::AuctionUncrossed { .. } => {}
+        }
+    }
+
+    /// Hex SHA-256 of the state's JSON encoding. Maps are ordered, so equal
+    /// states always hash equal.
+    pub fn hash(&self) -> Result<String, OracleError> {
+        Ok(sha256_hex(&serde_json::to_vec(self)?))
+    }
+
+    fn book(&mut self, symbol: &str) -> &mut BookState {
+        self.books.entry(symbol.to_string()).or_default()
+    }
+
+    fn account(&mut self, user_id: u64) -> &mut Account {
+        self.accounts.entry(user_id).or_insert_with(|| Account {
+            user_id,
+            collateral: Decimal::ZERO,
+            unrealized_pnl: Decimal::ZERO,
+            margin_ratio: Decimal::ZERO,
+            positions: Vec::new(),
+        })
+    }
+}
+
+/// A stored [`OracleState`] and the hash it had when written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub state_hash: String,
+    pub state: OracleState,
+}
+
+impl Snapshot {
+    /// Whether the state still hashes to the hash stored with it.
+    pub fn verify(&self) -> bool {
+        self.state.hash().is_ok_and(|hash| hash == self.state_hash)
+    }
+}
+
+/// Next global sequence, the last sequence of each stream and the state
+/// after the last event.
+struct Heads {
+    next_sequence: u64,
+    streams: HashMap<Producer, u64>,
+    state: OracleState,
+    /// Events appended since the last snapshot.
+    since_snapshot: u64,
+}
+
+pub struct OracleVault {
+    db: DB,
+    upcasters: Upcasters,
+    snapshot_interval: u64,
+    /// Held for the whole write so sequences are assigned in the order
+    /// appends reach the disk.
+    heads: Mutex<Heads>,
+}
+
+impl OracleVault {
+    /// Opens or creates the event log at `path`, continues numbering after
+    /// its last event and recovers the state it describes.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, OracleError> {
+        Self::open_with(path, Upcasters::default())
+    }
+
+    /// Opens the event log, replaying older events through `upcasters`.
+    pub fn open_with(path: impl AsRef<Path>, upcasters: Upcasters) -> Result<Self, OracleError> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let db = DB::open_cf(&options, path, [STREAMS, SNAPSHOTS])?;
+        let last = match db.iterator(IteratorMode::End).next() {
+            Some(entry) => decode_key(&entry?.0)?,
+            None => 0,
+        };
+        let mut streams = HashMap::new();
+        let cf = streams_cf(&db);
+        for producer in Producer::ALL {
+            if let Some(value) = db.get_cf(cf, producer.key())? {
+                streams.insert(producer, decode_key(&value)?);
+            }
+        }
+        let vault = Self {
+            db,
+            upcasters,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            heads: Mutex::new(Heads {
+                next_sequence: last + 1,
+                streams,
+                state: OracleState::default(),
+                since_snapshot: 0,
+            }),
+        };
+        let state = vault.recover()?;
+        {
+            let mut heads = vault.lock();
+            heads.since_snapshot = last.saturating_sub(state.sequence);
+            heads.state = state;
+        }
+        Ok(vault)
+    }
+
+    /// Writes a snapshot every `events` appended events; 0 disables them.
+    pub fn with_snapshot_interval(mut self, events: u64) -> Self {
+        self.snapshot_interval = events;
+        self
+    }
+
+    /// Sequence of the last stored event, or 0 if the log is empty.
+    pub fn last_sequence(&self) -> u64 {
+        self.lock().next_sequence - 1
+    }
+
+    /// Last stream sequence `producer` appended, or 0 if none.
+    pub fn last_stream_sequence(&self, producer: Producer) -> u64 {
+        self.lock().streams.get(&producer).copied().unwrap_or(0)
+    }
+
+    /// Durably appends `event` and returns its envelope.
+    pub fn append(
+        &self,
+        producer: Producer,
+        event: SystemEvent,
+        cause: Option<Cause>,
+    ) -> Result<EventEnvelope, OracleError> {
+        let mut envelopes = self.append_batch(producer, vec![event], cause)?;
+        Ok(envelopes.remove(0))
+    }
+
+    /// Durably appends `events` in one atomic write and returns their
+    /// envelopes. Without a cause the events are correlated with the first
+    /// of them, as the output of a single command.
+    pub fn append_batch(
+        &self,
+        producer: Producer,
+        events: Vec<SystemEvent>,
+        cause: Option<Cause>,
+    ) -> Result<Vec<EventEnvelope>, OracleError> {
+        if events.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut heads = self.lock();
+        let first = heads.next_sequence;
+        let stream_first = heads.streams.get(&producer).copied().unwrap_or(0) + 1;
+        let envelopes: Vec<_> = events
+            .into_iter()
+            .zip(0..)
+            .map(|(event, i)| EventEnvelope {
+                sequence: first + i,
+                stream_sequence: stream_first + i,
+                schema_version: SCHEMA_VERSION,
+                producer,
+                correlation_id: cause.map_or(first, |cause| cause.correlation_id),
+                causation_id: cause.map(|cause| cause.causation_id),
+                event,
+            })
+            .collect();
+
+        let mut batch = WriteBatch::default();
+        for envelope in &envelopes {
+            batch.put(
+                envelope.sequence.to_be_bytes(),
+                serde_json::to_vec(envelope)?,
+            );
+        }
+        let count = envelopes.len() as u64;
+        let stream_last = stream_first + count - 1;
+        batch.put_cf(
+            streams_cf(&self.db),
+            producer.key(),
+            stream_last.to_be_bytes(),
+        );
+        let mut sync = WriteOptions::default();
+        sync.set_sync(true);
+        self.db.write_opt(batch, &sync)?;
+        counter!("oracle.events_written").increment(count);
+
+        heads.next_sequence = first + count;
+        heads.streams.insert(producer, stream_last);
+        for envelope in &envelopes {
+            heads.state.apply(envelope.sequence, &envelope.event);
+        }
+        heads.since_snapshot += count;
+        if self.snapshot_interval > 0 && heads.since_snapshot >= self.snapshot_interval {
+            // The events are already durable; a missed snapshot only makes
+            // the next recovery replay further back.
+            match self.write_snapshot(&heads.state) {
+                Ok(()) => heads.since_snapshot = 0,
+                Err(e) => eprintln!("[Oracle] Snapshot at {} failed: {e}", heads.state.sequence),
+            }
+        }
+        Ok(envelopes)
+    }
+
+    /// The state after the last appended event.
+    pub fn state(&self) -> OracleState {
+        self.lock().state.clone()
+    }
+
+    /// Snapshots the current state now and returns the sequence it covers.
+    pub fn snapshot(&self) -> Result<u64, OracleError> {
+        let mut heads = self.lock();
+        self.write_snapshot(&heads.state)?;
+        heads.since_snapshot = 0;
+        Ok(heads.state.sequence)
+    }
+
+    fn write_snapshot(&self, state: &OracleState) -> Result<(), OracleError> {
+        let snapshot = Snapshot {
+            state_hash: state.hash()?,
+            state: state.clone(),
+        };
+        let cf = self.snapshots_cf();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            cf,
+            state.sequence.to_be_bytes(),
+            serde_json::to_vec(&snapshot)?,
+        );
+        let stale = self
+            .db
+            .iterator_cf(cf, IteratorMode::End)
+            .skip(SNAPSHOTS_KEPT - 1);
+        for entry in stale {
+            batch.delete_cf(cf, entry?.0);
+        }
+        let mut sync = WriteOptions::default();
+        sync.set_sync(true);
+        self.db.write_opt(batch, &sync)?;
+        counter!("oracle.snapshots_written").increment(1);
+        Ok(())
+    }
+
+    /// The newest snapshot that decodes, still matches its hash and covers
+    /// no more than the stored log, skipping any that do not.
+    pub fn latest_snapshot(&self) -> Result<Option<Snapshot>, OracleError> {
+        let last = self.last_sequence();
+        for entry in self.db.iterator_cf(self.snapshots_cf(), IteratorMode::End) {
+            let (key, value) = entry?;
+            let sequence = decode_key(&key)?;
+            // A snapshot ahead of the log describes events that were lost,
+            // e.g. with a truncated tail.
+            match serde_json::from_slice::<Snapshot>(&value) {
+                Ok(snapshot)
+                    if snapshot.state.sequence == sequence
+                        && sequence <= last
+                        && snapshot.verify() =>
+                {
+                    return Ok(Some(snapshot));
+                }
+                _ => {
+                    counter!("oracle.snapshots_invalid").increment(1);
+                    eprintln!("[Oracle] Skipping invalid snapshot at {sequence}");
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rebuilds the state from the latest valid snapshot and the events
+    /// stored after it.
+    pub fn recover(&self) -> Result<OracleState, OracleError> {
+        let mut state = self
+            .latest_snapshot()?
+            .map(|snapshot| snapshot.state)
+            .unwrap_or_default();
+        let mut replayed = 0;
+        for envelope in self.events_from(state.sequence + 1) {
+            let envelope = envelope?;
+            state.apply(envelope.sequence, &envelope.event);
+            replayed += 1;
+        }
+        counter!("oracle.events_replayed").increment(replayed);
+        Ok(state)
+    }
+
+    /// Rebuilds the state from every stored event, ignoring snapshots.
+    pub fn replay_state(&self) -> Result<OracleState, OracleError> {
+        let mut state = OracleState::default();
+        for envelope in self.replay_all()? {
+            state.apply(envelope.sequence, &envelope.event);
+        }
+        Ok(state)
+    }
+
+    /// The event stored under `sequence`.
+    pub fn get(&self, sequence: u64) -> Result<EventEnvelope, OracleError> {
+        match self.db.get(sequence.to_be_bytes())? {
+            Some(value) => self.upcasters.decode(sequence, &value),
+            None => Err(OracleError::UnknownSequence(sequence)),
+        }
+    }
+
+    /// The chain of events that led to `sequence`, from the event itself
+    /// back to the first cause.
+    pub fn trace(&self, sequence: u64) -> Result<Vec<EventEnvelope>, OracleError> {
+        let mut chain = vec![self.get(sequence)?];
+        while let Some(cause) = chain.last().and_then(|envelope| envelope.causation_id) {
+            chain.push(self.get(cause)?);
+        }
+        Ok(chain)
+    }
+
+    /// Events in sequence order, starting at `sequence`.
+    pub fn events_from(
+        &self,
+        sequence: u64,
+    ) -> impl Iterator<Item = Result<EventEnvelope, OracleError>> + '_ {
+        let start = sequence.to_be_bytes();
+        self.db
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+            .map(|entry| {
+                let (key, value) = entry?;
+                self.upcasters.decode(decode_key(&key)?, &value)
+            })
+    }
+
+    /// Every stored event, in order.
+    pub fn replay_all(&self) -> Result<Vec<EventEnvelope>, OracleError> {
+        self.replay_from(1)
+    }
+
+    /// Stored events from `sequence` onwards, in order.
+    pub fn replay_from(&self, sequence: u64) -> Result<Vec<EventEnvelope>, OracleError> {
+        let events = self.events_from(sequence).collect::<Result<Vec<_>, _>>()?;
+        counter!("oracle.events_replayed").increment(events.len() as u64);
+        Ok(events)
+    }
+
+    /// Hex SHA-256 of the state after the last appended event, as stored
+    /// with snapshots.
+    pub fn compute_state_hash(&self) -> Result<String, OracleError> {
+        self.lock().state.hash()
+    }
+
+    /// Hex SHA-256 over every stored key and value in sequence order. Two
+    /// logs hash equal exactly when they hold the same events in the same
+    /// order.
+    pub fn compute_log_hash(&self) -> Result<String, OracleError> {
+        let mut hasher = Sha256::new();
+        for entry in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = entry?;
+            hasher.update(&key);
+            hasher.update((value.len() as u64).to_be_bytes());
+            hasher.update(&value);
+        }
+        Ok(hex(&hasher.finalize()))
+    }
+
+    fn snapshots_cf(&self) -> &ColumnFamily {
+        self.db
+            .cf_handle(SNAPSHOTS)
+            .expect("snapshots column family is created on open")
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Heads> {
+        self.heads
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn streams_cf(db: &DB) -> &ColumnFamily {
+    db.cf_handle(STREAMS)
+        .expect("streams column family is created on open")
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_key(key: &[u8]) -> Result<u64, OracleError> {
+    let bytes: [u8; 8] = key
+        .try_into()
+        .map_err(|_| OracleError::CorruptKey(key.to_vec()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::types::{Execution, Order, OrderType, SelfTradePrevention, Side, TimeInForce};
+
+    /// A fresh vault directory under the system temp dir.
+    fn temp_dir() -> PathBuf {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "oracle-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn placed(order_id: u64, side: Side, price: u64) -> SystemEvent {
+        SystemEvent::OrderPlaced(Order {
+            order_id,
+            user_id: order_id % 3,
+            symbol: "BTC-USDT".into(),
+            side,
+            price,
+            quantity: 5,
+            timestamp: order_id,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            display_quantity: None,
+            self_trade_prevention: SelfTradePrevention::None,
+        })
+    }
+
+    fn executed(trade_id: u64, buy_order_id: u64, sell_order_id: u64) -> SystemEvent {
+        SystemEvent::OrderExecuted(Execution {
+            trade_id,
+            symbol: "BTC-USDT".into(),
+            buy_order_id,
+            sell_order_id,
+            buyer_user_id: buy_order_id % 3,
+            seller_user_id: sell_order_id % 3,
+            aggressor_side: Side::Sell,
+            price: 100,
+            quantity: 2,
+            timestamp: trade_id,
+            maker_fee: Decimal::ZERO,
+            taker_fee: Decimal::ZERO,
+        })
+    }
+
+    /// A vault snapshotting every 4 events, holding 11 events: resting
+    /// orders, two trades and a cancel.
+    fn filled_vault(dir: &Path) -> OracleVault {
+        let vault = OracleVault::open(dir).unwrap().with_snapshot_interval(4);
+        for order_id in 1..=8 {
+            let side = if order_id % 2 == 0 {
+                Side::Buy
+            } else {
+                Side::Sell
+            };
+            vault
+                .append(Producer::Titan, placed(order_id, side, 100), None)
+                .unwrap();
+        }
+        vault
+            .append(Producer::Titan, executed(1, 2, 1), None)
+            .unwrap();
+        vault
+            .append(Producer::Titan, executed(2, 4, 3), None)
+            .unwrap();
+        let cancelled = SystemEvent::OrderCancelled {
+            order_id: 5,
+            user_id: 2,
+            symbol: "BTC-USDT".into(),
+            remaining_quantity: 5,
+            timestamp: 11,
+        };
+        vault.append(Producer::Titan, cancelled, None).unwrap();
+        vault
+    }
+
+    #[test]
+    fn snapshot_and_tail_match_full_replay() {
+        let dir = temp_dir();
+        let vault = filled_vault(&dir);
+        let state = vault.state();
+        assert_eq!(state.sequence, 11);
+        assert_eq!(state.books["BTC-USDT"].last_trade_id, 2);
+        assert_eq!(vault.latest_snapshot().unwrap().unwrap().state.sequence, 8);
+
+        let replayed = vault.replay_state().unwrap().hash().unwrap();
+        assert_eq!(vault.recover().unwrap().hash().unwrap(), replayed);
+        assert_eq!(vault.compute_state_hash().unwrap(), replayed);
+        drop(vault);
+
+        let reopened = OracleVault::open(&dir).unwrap();
+        assert_eq!(reopened.compute_state_hash().unwrap(), replayed);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupt_snapshot_falls_back_to_an_older_one() {
+        let dir = temp_dir();
+        let vault = filled_vault(&dir);
+        let expected = vault.compute_state_hash().unwrap();
+        assert_eq!(vault.snapshot().unwrap(), 11);
+
+        let cf = vault.snapshots_cf();
+        let mut snapshot: Value =
+            serde_json::from_slice(&vault.db.get_cf(cf, 11u64.to_be_bytes()).unwrap().unwrap())
+                .unwrap();
+        snapshot["state"]["books"]["BTC-USDT"]["last_trade_id"] = 99.into();
+        vault
+            .db
+            .put_cf(
+                cf,
+                11u64.to_be_bytes(),
+                serde_json::to_vec(&snapshot).unwrap(),
+            )
+            .unwrap();
+        vault
+            .db
+            .put_cf(cf, 12u64.to_be_bytes(), b"not json")
+            .unwrap();
+
+        assert_eq!(vault.latest_snapshot().unwrap().unwrap().state.sequence, 8);
+        assert_eq!(vault.recover().unwrap().hash().unwrap(), expected);
+        drop(vault);
+
+        let reopened = OracleVault::open(&dir).unwrap();
+        assert_eq!(reopened.compute_state_hash().unwrap(), expected);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn missing_snapshots_replay_the_whole_log() {
+        let dir = temp_dir();
+        let vault = filled_vault(&dir);
+        let expected = vault.compute_state_hash().unwrap();
+        let cf = vault.snapshots_cf();
+        for sequence in [4u64, 8] {
+            vault.db.delete_cf(cf, sequence.to_be_bytes()).unwrap();
+        }
+
+        assert!(vault.latest_snapshot().unwrap().is_none());
+        assert_eq!(vault.recover().unwrap().hash().unwrap(), expected);
+        drop(vault);
+
+        let reopened = OracleVault::open(&dir).unwrap();
+        assert_eq!(reopened.state().books["BTC-USDT"].orders.len(), 7);
+        assert_eq!(reopened.compute_state_hash().unwrap(), expected);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn appends_are_numbered_across_reopening() {
+        let dir = temp_dir();
+        let vault = OracleVault::open(&dir).unwrap();
+        assert_eq!(vault.last_sequence(), 0);
+        let first = vault
+            .append(Producer::Titan, placed(1, Side::Buy, 100), None)
+            .unwrap();
+        let batch = vault
+            .append_batch(
+                Producer::Titan,
+                vec![placed(2, Side::Sell, 101), executed(1, 1, 2)],
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            vault
+                .append_batch(Producer::Titan, vec![], None)
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(first.sequence, 1);
+        assert_eq!(
+            batch
+                .iter()
+                .map(|envelope| envelope.sequence)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        let log_hash = vault.compute_log_hash().unwrap();
+        drop(vault);
+
+        let reopened = OracleVault::open(&dir).unwrap();
+        assert_eq!(reopened.last_sequence(), 3);
+        assert_eq!(reopened.compute_log_hash().unwrap(), log_hash);
+        let next = reopened
+            .append(Producer::Titan, placed(3, Side::Buy, 99), None)
+            .unwrap();
+        assert_eq!(next.sequence, 4);
+        assert_ne!(reopened.compute_log_hash().unwrap(), log_hash);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stored_events_read_back_in_order() {
+        let dir = temp_dir();
+        let vault = filled_vault(&dir);
+        assert!(matches!(
+            vault.get(9).unwrap().event,
+            SystemEvent::OrderExecuted(Execution { trade_id: 1, .. })
+        ));
+        assert!(matches!(
+            vault.get(12),
+            Err(OracleError::UnknownSequence(12))
+        ));
+
+        let all = vault.replay_all().unwrap();
+        assert_eq!(
+            all.iter()
+                .map(|envelope| envelope.sequence)
+                .collect::<Vec<_>>(),
+            (1..=11).collect::<Vec<_>>()
+        );
+        let tail = vault.replay_from(10).unwrap();
+        assert_eq!(tail.len(), 2);
+        assert!(matches!(
+            tail[1].event,
+            SystemEvent::OrderCancelled { order_id: 5, .. }
+        ));
+        assert!(vault.replay_from(12).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn identical_logs_hash_equal() {
+        let (left, right) = (temp_dir(), temp_dir());
+        let hashes = [&left, &right].map(|dir| {
+            let vault = filled_vault(dir);
+            (
+                vault.compute_log_hash().unwrap(),
+                vault.compute_state_hash().unwrap(),
+            )
+        });
+        assert_eq!(hashes[0], hashes[1]);
+        let _ = std::fs::remove_dir_all(&left);
+        let _ = std::fs::remove_dir_all(&right);
+    }
+
+    #[test]
+    fn each_producer_numbers_its_own_stream() {
+        let dir = temp_dir();
+        let vault = OracleVault::open(&dir).unwrap();
+        let titan = vault
+            .append_batch(
+                Producer::Titan,
+                vec![placed(1, Side::Buy, 100), placed(2, Side::Sell, 101)],
+                None,
+            )
+            .unwrap();
+        let sentinel = vault
+            .append(Producer::Sentinel, placed(3, Side::Buy, 99), None)
+            .unwrap();
+        assert_eq!(
+            titan
+                .iter()
+                .map(|envelope| envelope.stream_sequence)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!((sentinel.sequence, sentinel.stream_sequence), (3, 1));
+        assert_eq!(sentinel.schema_version, SCHEMA_VERSION);
+        drop(vault);
+
+        let reopened = OracleVault::open(&dir).unwrap();
+        assert_eq!(reopened.last_stream_sequence(Producer::Titan), 2);
+        assert_eq!(reopened.last_stream_sequence(Producer::Sentinel), 1);
+        assert_eq!(reopened.last_stream_sequence(Producer::Gateway), 0);
+        let next = reopened
+            .append(Producer::Titan, placed(4, Side::Buy, 98), None)
+            .unwrap();
+        assert_eq!((next.sequence, next.stream_sequence), (4, 3));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn caused_events_trace_back_to_their_command() {
+        let dir = temp_dir();
+        let vault = OracleVault::open(&dir).unwrap();
+        let command = vault
+            .append_batch(
+                Producer::Titan,
+                vec![placed(1, Side::Buy, 100), placed(2, Side::Sell, 100)],
+                None,
+            )
+            .unwrap();
+        assert!(command.iter().all(|envelope| envelope.correlation_id == 1));
+        assert!(
+            command
+                .iter()
+                .all(|envelope| envelope.causation_id.is_none())
+        );
+
+        let fill = vault
+            .append(Producer::Titan, executed(1, 1, 2), Some(command[1].cause()))
+            .unwrap();
+        let position = vault
+            .append(Producer::Sentinel, executed(2, 1, 2), Some(fill.cause()))
+            .unwrap();
+        assert_eq!((fill.correlation_id, fill.causation_id), (1, Some(2)));
+        assert_eq!(
+            (position.correlation_id, position.causation_id),
+            (1, Some(3))
+        );
+
+        let chain: Vec<_> = vault
+            .trace(position.sequence)
+            .unwrap()
+            .iter()
+            .map(|envelope| envelope.sequence)
+            .collect();
+        assert_eq!(chain, vec![4, 3, 2]);
+        let unrelated = vault
+            .append(Producer::Gateway, placed(3, Side::Buy, 99), None)
+            .unwrap();
+        assert_eq!(unrelated.correlation_id, unrelated.sequence);
+        assert_eq!(vault.trace(unrelated.sequence).unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
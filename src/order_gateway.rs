@@ -0,0 +1,806 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use metrics::{counter, gauge};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use crate::sentinel::Sentinel;
+use crate::titan::{OrderCommand, TitanEngine};
+use crate::types::{
+    Execution, LiquidationEvent, Order, Position, RejectReason, SelfTradeEvent, Side, SystemEvent,
+};
+
+/// Private messages queued per session before it counts as too slow and is
+/// disconnected.
+const OUTBOUND_CAPACITY: usize = 1024;
+
+/// Resolves a session token to the user it trades for.
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate(&self, token: &str) -> Option<u64>;
+}
+
+/// Fixed token table, e.g. API keys loaded at startup.
+impl Authenticator for HashMap<String, u64> {
+    fn authenticate(&self, token: &str) -> Option<u64> {
+        self.get(token).copied()
+    }
+}
+
+/// Requests a client may send, as JSON text frames tagged by `op`. The first
+/// request on a connection must be `authenticate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Authenticate {
+        token: String,
+    },
+    /// The order's `order_id` is the client's own reference. The gateway
+    /// assigns the id Titan uses and stamps the timestamp, and reports both
+    /// ids in the `ack`.
+    Submit(Order),
+    Cancel {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+    },
+    /// Reduces the resting quantity in place, keeping queue priority.
+    Amend {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+        quantity: u64,
+    },
+    /// Changes price and quantity; the order loses its queue priority.
+    Replace {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+        price: u64,
+        quantity: u64,
+    },
+    Ping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// One user's side of a trade.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fill {
+    pub symbol: String,
+    pub trade_id: u64,
+    pub order_id: u64,
+    pub side: Side,
+    pub price: u64,
+    pub quantity: u64,
+    pub liquidity: Liquidity,
+    /// In quote asset units; negative values are rebates.
+    pub fee: Decimal,
+    pub timestamp: u64,
+}
+
+impl Fill {
+    fn from_execution(execution: &Execution, side: Side) -> Self {
+        let order_id = match side {
+            Side::Buy => execution.buy_order_id,
+            Side::Sell => execution.sell_order_id,
+        };
+        let (fee, liquidity) = if side == execution.aggressor_side {
+            (execution.taker_fee, Liquidity::Taker)
+        } else {
+            (execution.maker_fee, Liquidity::Maker)
+        };
+        Self {
+            symbol: execution.symbol.clone(),
+            trade_id: execution.trade_id,
+            order_id,
+            side,
+            price: execution.price,
+            quantity: execution.quantity,
+            liquidity,
+            fee,
+            timestamp: execution.timestamp,
+        }
+    }
+}
+
+/// Messages sent to clients, as JSON text frames tagged by `type`. Everything
+/// after `authenticated` concerns the session's own user only.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GatewayMessage {
+    Authenticated {
+        user_id: u64,
+    },
+    /// The submit was forwarded to Titan as `order_id`. Whether it rests,
+    /// trades or is rejected follows as separate messages.
+    Ack {
+        client_order_id: u64,
+        order_id: u64,
+        symbol: String,
+    },
+    OrderPlaced(Order),
+    Rejected {
+        order_id: u64,
+        symbol: String,
+        reason: RejectReason,
+        timestamp: u64,
+    },
+    Cancelled {
+        order_id: u64,
+        symbol: String,
+        remaining_quantity: u64,
+        timestamp: u64,
+    },
+    Amended {
+        order_id: u64,
+        symbol: String,
+        quantity: u64,
+        timestamp: u64,
+    },
+    Replaced {
+        order_id: u64,
+        symbol: String,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    Fill(Fill),
+    SelfTradePrevented(SelfTradeEvent),
+    /// Position in `symbol` after a fill; `None` once it is flat.
+    Position {
+        symbol: String,
+        position: Option<Position>,
+        timestamp: u64,
+    },
+    Liquidated(LiquidationEvent),
+    Account {
+        collateral: Decimal,
+        margin_ratio: Decimal,
+        timestamp: u64,
+    },
+    Heartbeat {
+        timestamp: u64,
+    },
+    Pong,
+    Error {
+        message: String,
+    },
+}
+
+/// Receiving end of one authenticated session. The gateway drops its sender,
+/// ending the stream, if the session falls `OUTBOUND_CAPACITY` messages behind.
+pub struct GatewaySession {
+    pub id: u64,
+    pub user_id: u64,
+    pub messages: mpsc::Receiver<GatewayMessage>,
+}
+
+/// Routes authenticated users' orders to Titan and their private events back
+/// to their sessions.
+///
+/// Transport-neutral: connections open a session per authenticated user,
+/// submit through [`OrderGateway::submit`] and [`OrderGateway::send`], and
+/// forward what arrives on the session. A user may hold several sessions;
+/// each receives all of the user's messages.
+pub struct OrderGateway {
+    engine: Arc<TitanEngine>,
+    sentinel: Arc<Sentinel>,
+    authenticator: Box<dyn Authenticator>,
+    next_order_id: AtomicU64,
+    next_session_id: AtomicU64,
+    sessions: DashMap<u64, Vec<(u64, mpsc::Sender<GatewayMessage>)>>,
+}
+
+impl OrderGateway {
+    /// Order ids are assigned from `first_order_id` upwards, which must not
+    /// overlap ids used by other order sources.
+    pub fn new(
+        engine: Arc<TitanEngine>,
+        sentinel: Arc<Sentinel>,
+        authenticator: impl Authenticator,
+        first_order_id: u64,
+    ) -> Self {
+        Self {
+            engine,
+            sentinel,
+            authenticator: Box::new(authenticator),
+            next_order_id: AtomicU64::new(first_order_id),
+            next_session_id: AtomicU64::new(1),
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<u64> {
+        self.authenticator.authenticate(token)
+    }
+
+    pub fn open_session(&self, user_id: u64) -> GatewaySession {
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, messages) = mpsc::channel(OUTBOUND_CAPACITY);
+        self.sessions.entry(user_id).or_default().push((id, tx));
+        GatewaySession {
+            id,
+            user_id,
+            messages,
+        }
+    }
+
+    pub fn close_session(&self, session: &GatewaySession) {
+        if let Some(mut sessions) = self.sessions.get_mut(&session.user_id) {
+            sessions.retain(|(id, _)| *id != session.id);
+        }
+        self.sessions
+            .remove_if(&session.user_id, |_, sessions| sessions.is_empty());
+    }
+
+    /// Assigns `order` a gateway order id and the current time and sends it
+    /// to Titan. Returns the assigned id.
+    pub fn submit(&self, user_id: u64, mut order: Order) -> Result<u64, RejectReason> {
+        if order.user_id != user_id {
+            return Err(RejectReason::Unauthorized);
+        }
+        order.order_id = self.next_order_id.fetch_add(1, Ordering::Relaxed);
+        order.timestamp = now_ms();
+        let order_id = order.order_id;
+        self.send(user_id, OrderCommand::Submit(order))?;
+        Ok(order_id)
+    }
+
+    /// Sends a command on behalf of `user_id`. Commands for another user, or
+    /// for no user at all, are refused.
+    pub fn send(&self, user_id: u64, command: OrderCommand) -> Result<(), RejectReason> {
+        if command.user_id() != Some(user_id) {
+            return Err(RejectReason::Unauthorized);
+        }
+        if self.engine.shard_of(command.symbol()).is_none() {
+            return Err(RejectReason::UnknownSymbol);
+        }
+        counter!("gateway.commands").increment(1);
+        self.engine.send(command)
+    }
+
+    /// Routes a platform event to the sessions of the users it concerns.
+    /// Pass events after Sentinel has applied them, so the position sent
+    /// after each fill includes it.
+    pub fn on_event(&self, event: &SystemEvent) {
+        match event {
+            SystemEvent::OrderExecuted(execution) => {
+                for (user_id, side) in [
+                    (execution.buyer_user_id, Side::Buy),
+                    (execution.seller_user_id, Side::Sell),
+                ] {
+                    if !self.sessions.contains_key(&user_id) {
+                        continue;
+                    }
+                    self.deliver(
+                        user_id,
+                        GatewayMessage::Fill(Fill::from_execution(execution, side)),
+                    );
+                    let position = self.sentinel.account(user_id).and_then(|account| {
+                        account
+                            .positions
+                            .into_iter()
+                            .find(|p| p.symbol == execution.symbol)
+                    });
+                    self.deliver(
+                        user_id,
+                        GatewayMessage::Position {
+                            symbol: execution.symbol.clone(),
+                            position,
+                            timestamp: execution.timestamp,
+                        },
+                    );
+                }
+            }
+            _ => {
+                if let Some((user_id, message)) = private_message(event) {
+                    self.deliver(user_id, message);
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, user_id: u64, message: GatewayMessage) {
+        let Some(mut sessions) = self.sessions.get_mut(&user_id) else {
+            return;
+        };
+        sessions.retain(|(_, tx)| match tx.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                counter!("gateway.slow_disconnects").increment(1);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+/// The private message for an event that concerns a single user. Fills
+/// concern two users and are handled by [`OrderGateway::on_event`].
+fn private_message(event: &SystemEvent) -> Option<(u64, GatewayMessage)> {
+    let routed = match event {
+        SystemEvent::OrderPlaced(order) => {
+            (order.user_id, GatewayMessage::OrderPlaced(order.clone()))
+        }
+        SystemEvent::OrderRejected {
+            order_id,
+            user_id,
+            symbol,
+            reason,
+            timestamp,
+        } => (
+            *user_id,
+            GatewayMessage::Rejected {
+                order_id: *order_id,
+                symbol: symbol.clone(),
+                reason: *reason,
+                timestamp: *timestamp,
+            },
+        ),
+        SystemEvent::OrderCancelled {
+            order_id,
+            user_id,
+            symbol,
+            remaining_quantity,
+            timestamp,
+        } => (
+            *user_id,
+            GatewayMessage::Cancelled {
+                order_id: *order_id,
+                symbol: symbol.clone(),
+                remaining_quantity: *remaining_quantity,
+                timestamp: *timestamp,
+            },
+        ),
+        SystemEvent::OrderAmended {
+            order_id,
+            user_id,
+            symbol,
+            quantity,
+            timestamp,
+        } => (
+            *user_id,
+            GatewayMessage::Amended {
+                order_id: *order_id,
+                symbol: symbol.clone(),
+                quantity: *quantity,
+                timestamp: *timestamp,
+            },
+        ),
+        SystemEvent::OrderReplaced {
+            order_id,
+            user_id,
+            symbol,
+            price,
+            quantity,
+            timestamp,
+        } => (
+            *user_id,
+            GatewayMessage::Replaced {
+                order_id: *order_id,
+                symbol: symbol.clone(),
+                price: *price,
+                quantity: *quantity,
+                timestamp: *timestamp,
+            },
+        ),
+        SystemEvent::SelfTradePrevented(prevented) => (
+            prevented.user_id,
+            GatewayMessage::SelfTradePrevented(prevented.clone()),
+        ),
+        SystemEvent::PositionLiquidated(liquidation) => (
+            liquidation.user_id,
+            GatewayMessage::Liquidated(liquidation.clone()),
+        ),
+        SystemEvent::AccountUpdated {
+            user_id,
+            collateral,
+            margin_ratio,
+            timestamp,
+        } => (
+            *user_id,
+            GatewayMessage::Account {
+                collateral: *collateral,
+                margin_ratio: *margin_ratio,
+                timestamp: *timestamp,
+            },
+        ),
+        _ => return None,
+    };
+    Some(routed)
+}
+
+/// Authenticated WebSocket order entry.
+pub struct OrderGatewayServer {
+    gateway: Arc<OrderGateway>,
+    heartbeat_interval: Duration,
+}
+
+impl OrderGatewayServer {
+    pub fn new(gateway: Arc<OrderGateway>, heartbeat_interval: Duration) -> Self {
+        Self {
+            gateway,
+            heartbeat_interval,
+        }
+    }
+
+    /// Accepts connections until the listener fails.
+    pub async fn serve(self, addr: impl ToSocketAddrs) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!(
+            "[Gateway] WebSocket order entry listening on {}",
+            listener.local_addr()?
+        );
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                gauge!("gateway.connections").increment(1.0);
+                if let Err(e) = server.handle(stream).await {
+                    eprintln!("[Gateway] Connection {peer} failed: {e}");
+                }
+                gauge!("gateway.connections").decrement(1.0);
+            });
+        }
+    }
+
+    async fn handle(&self, stream: TcpStream) -> Result<(), WsError> {
+        let (mut sink, mut source) = tokio_tungstenite::accept_async(stream).await?.split();
+
+        let user_id = loop {
+            match source.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    let user_id = match serde_json::from_str(text.as_str()) {
+                        Ok(ClientMessage::Authenticate { token }) => {
+                            self.gateway.authenticate(&token)
+                        }
+                        _ => None,
+                    };
+                    let Some(user_id) = user_id else {
+                        let refused = GatewayMessage::Error {
+                            message: "authentication required".to_string(),
+                        };
+                        send_all(&mut sink, &[refused]).await?;
+                        return sink.close().await;
+                    };
+                    break user_id;
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+            }
+        };
+
+        let mut session = self.gateway.open_session(user_id);
+        send_all(&mut sink, &[GatewayMessage::Authenticated { user_id }]).await?;
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.heartbeat_interval,
+            self.heartbeat_interval,
+        );
+
+        let result = loop {
+            let sent = tokio::select! {
+                incoming = source.next() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        let reply = self.on_request(user_id, text.as_str());
+                        send_all(&mut sink, reply.as_slice()).await
+                    }
+                    Some(Ok(Message::Close(_))) | None => break Ok(()),
+                    // Pings are answered by tungstenite itself.
+                    Some(Ok(_)) => Ok(()),
+                    Some(Err(e)) => break Err(e),
+                },
+                private = session.messages.recv() => match private {
+                    Some(message) => send_all(&mut sink, &[message]).await,
+                    None => {
+                        let dropped = GatewayMessage::Error {
+                            message: "session fell behind and was closed".to_string(),
+                        };
+                        let _ = send_all(&mut sink, &[dropped]).await;
+                        break sink.close().await;
+                    }
+                },
+                _ = heartbeat.tick() => {
+                    send_all(&mut sink, &[GatewayMessage::Heartbeat { timestamp: now_ms() }]).await
+                }
+            };
+            if let Err(e) = sent {
+                break Err(e);
+            }
+        };
+
+        self.gateway.close_session(&session);
+        result
+    }
+
+    /// The direct reply to a request, if any. Cancels and amendments are
+    /// answered by Titan's events; only a refusal is reported here.
+    fn on_request(&self, user_id: u64, text: &str) -> Option<GatewayMessage> {
+        let request = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                return Some(GatewayMessage::Error {
+                    message: format!("invalid request: {e}"),
+                });
+            }
+        };
+        let timestamp = now_ms();
+        let command = match request {
+            ClientMessage::Ping => return Some(GatewayMessage::Pong),
+            ClientMessage::Authenticate { .. } => {
+                return Some(GatewayMessage::Error {
+                    message: "already authenticated".to_string(),
+                });
+            }
+            ClientMessage::Submit(order) => {
+                let client_order_id = order.order_id;
+                let symbol = order.symbol.clone();
+                return Some(match self.gateway.submit(user_id, order) {
+                    Ok(order_id) => GatewayMessage::Ack {
+                        client_order_id,
+                        order_id,
+                        symbol,
+                    },
+                    Err(reason) => GatewayMessage::Rejected {
+                        order_id: client_order_id,
+                        symbol,
+                        reason,
+                        timestamp,
+                    },
+                });
+            }
+            ClientMessage::Cancel {
+                symbol,
+                order_id,
+                user_id,
+            } => OrderCommand::Cancel {
+                symbol,
+                order_id,
+                user_id,
+                timestamp,
+            },
+            ClientMessage::Amend {
+                symbol,
+                order_id,
+                user_id,
+                quantity,
+            } => OrderCommand::Amend {
+                symbol,
+                order_id,
+                user_id,
+                quantity,
+                timestamp,
+            },
+            ClientMessage::Replace {
+                symbol,
+                order_id,
+                user_id,
+                price,
+                quantity,
+            } => OrderCommand::Replace {
+                symbol,
+                order_id,
+                user_id,
+                price,
+                quantity,
+                timestamp,
+            },
+        };
+        let reason = self.gateway.send(user_id, command.clone()).err()?;
+        command
+            .rejection(reason)
+            .and_then(|event| private_message(&event))
+            .map(|(_, message)| message)
+    }
+}
+
+type WsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+
+async fn send_all(sink: &mut WsSink, messages: &[GatewayMessage]) -> Result<(), WsError> {
+    for message in messages {
+        let text = serde_json::to_string(message).expect("gateway messages always serialize");
+        sink.send(Message::text(text)).await?;
+    }
+    Ok(())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel;
+
+    use super::*;
+    use crate::instruments::{Instrument, InstrumentRegistry};
+    use crate::types::{OrderType, TimeInForce};
+
+    fn registry() -> InstrumentRegistry {
+        let mut registry = InstrumentRegistry::new();
+        registry.register(Instrument {
+            symbol: "BTC-USDT".into(),
+            base_asset: "BTC".into(),
+            quote_asset: "USDT".into(),
+            price_scale: 0,
+            quantity_scale: 0,
+            tick_size: 1,
+            lot_size: 1,
+            min_notional: Decimal::ZERO,
+        });
+        registry
+    }
+
+    /// A gateway in front of a running engine, with token "alice" for user 7,
+    /// and the engine's event stream.
+    fn gateway() -> (OrderGateway, channel::Receiver<SystemEvent>) {
+        let (events, received) = channel::unbounded();
+        let engine = TitanEngine::start(&registry(), 1, None, events, None);
+        let sentinel = Sentinel::new(registry(), Decimal::ZERO, 10);
+        let tokens = HashMap::from([("alice".to_string(), 7)]);
+        let gateway = OrderGateway::new(Arc::new(engine), Arc::new(sentinel), tokens, 1_000);
+        (gateway, received)
+    }
+
+    fn order(user_id: u64) -> Order {
+        Order {
+            order_id: 1,
+            user_id,
+            symbol: "BTC-USDT".into(),
+            side: Side::Buy,
+            price: 100,
+            quantity: 1,
+            timestamp: 0,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            display_quantity: None,
+            self_trade_prevention: Default::default(),
+        }
+    }
+
+    fn execution() -> Execution {
+        Execution {
+            trade_id: 9,
+            symbol: "BTC-USDT".into(),
+            buy_order_id: 1_000,
+            sell_order_id: 1_001,
+            buyer_user_id: 7,
+            seller_user_id: 8,
+            aggressor_side: Side::Sell,
+            price: 100,
+            quantity: 2,
+            timestamp: 5,
+            maker_fee: Decimal::ONE,
+            taker_fee: Decimal::TWO,
+        }
+    }
+
+    #[test]
+    fn tokens_resolve_to_users() {
+        let (gateway, _) = gateway();
+        assert_eq!(gateway.authenticate("alice"), Some(7));
+        assert_eq!(gateway.authenticate("mallory"), None);
+    }
+
+    #[test]
+    fn orders_get_gateway_ids_and_stay_with_their_user() {
+        let (gateway, events) = gateway();
+        assert_eq!(gateway.submit(7, order(8)), Err(RejectReason::Unauthorized));
+        let mut unlisted = order(7);
+        unlisted.symbol = "ETH-USDT".into();
+        assert_eq!(
+            gateway.submit(7, unlisted),
+            Err(RejectReason::UnknownSymbol)
+        );
+        // The refused unlisted order still used up id 1_000.
+        assert_eq!(gateway.submit(7, order(7)), Ok(1_001));
+        assert_eq!(gateway.submit(7, order(7)), Ok(1_002));
+
+        let cancel = OrderCommand::Cancel {
+            symbol: "BTC-USDT".into(),
+            order_id: 1_000,
+            user_id: 8,
+            timestamp: 0,
+        };
+        assert_eq!(gateway.send(7, cancel), Err(RejectReason::Unauthorized));
+
+        let placed = events.recv().unwrap();
+        assert!(matches!(placed, SystemEvent::OrderPlaced(order) if order.order_id == 1_001));
+    }
+
+    #[test]
+    fn fills_reach_each_side_with_its_liquidity_and_fee() {
+        let (gateway, _) = gateway();
+        let mut buyer = gateway.open_session(7);
+        let mut seller = gateway.open_session(8);
+        gateway.on_event(&SystemEvent::OrderExecuted(execution()));
+
+        let GatewayMessage::Fill(fill) = buyer.messages.try_recv().unwrap() else {
+            panic!("expected a fill");
+        };
+        assert_eq!(
+            (fill.order_id, fill.side, fill.liquidity, fill.fee),
+            (1_000, Side::Buy, Liquidity::Maker, Decimal::ONE)
+        );
+        assert!(matches!(
+            buyer.messages.try_recv().unwrap(),
+            GatewayMessage::Position { position: None, .. }
+        ));
+        let GatewayMessage::Fill(fill) = seller.messages.try_recv().unwrap() else {
+            panic!("expected a fill");
+        };
+        assert_eq!(
+            (fill.order_id, fill.liquidity, fill.fee),
+            (1_001, Liquidity::Taker, Decimal::TWO)
+        );
+    }
+
+    #[test]
+    fn private_events_only_reach_their_user() {
+        let (gateway, _) = gateway();
+        let mut own = gateway.open_session(7);
+        let mut other = gateway.open_session(8);
+        gateway.on_event(&SystemEvent::OrderCancelled {
+            order_id: 1_000,
+            user_id: 7,
+            symbol: "BTC-USDT".into(),
+            remaining_quantity: 1,
+            timestamp: 5,
+        });
+        assert!(matches!(
+            own.messages.try_recv().unwrap(),
+            GatewayMessage::Cancelled {
+                order_id: 1_000,
+                ..
+            }
+        ));
+        assert!(other.messages.try_recv().is_err());
+
+        gateway.close_session(&own);
+        gateway.on_event(&SystemEvent::OrderPlaced(order(7)));
+        assert!(own.messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn requests_are_answered_directly_only_when_refused() {
+        let (gateway, _) = gateway();
+        let server = OrderGatewayServer::new(Arc::new(gateway), Duration::from_secs(1));
+        assert!(matches!(
+            server.on_request(7, r#"{"op":"ping"}"#),
+            Some(GatewayMessage::Pong)
+        ));
+        assert!(matches!(
+            server.on_request(7, "not json"),
+            Some(GatewayMessage::Error { .. })
+        ));
+        let cancel = r#"{"op":"cancel","symbol":"BTC-USDT","order_id":5,"user_id":8}"#;
+        assert!(matches!(
+            server.on_request(7, cancel),
+            Some(GatewayMessage::Rejected {
+                order_id: 5,
+                reason: RejectReason::Unauthorized,
+                ..
+            })
+        ));
+        let cancel = r#"{"op":"cancel","symbol":"BTC-USDT","order_id":5,"user_id":7}"#;
+        assert!(server.on_request(7, cancel).is_none());
+    }
+}
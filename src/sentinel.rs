@@ -0,0 +1,236 @@
+use dashmap::DashMap;
+use metrics::{counter, gauge};
+use rust_decimal::Decimal;
+
+use std::sync::Arc;
+
+use crate::instruments::InstrumentRegistry;
+use crate::titan::{OrderCommand, RiskCheck};
+use crate::types::{
+    Account, Execution, Order, OrderType, Position, PositionSide, RejectReason, Side, SystemEvent,
+};
+
+/// Tracks accounts and positions and keeps them in line with Titan fills.
+///
+/// Accounts live in a `DashMap` so price and fill processing can run on
+/// different tasks without a global lock.
+#[derive(Debug)]
+pub struct Sentinel {
+    accounts: DashMap<u64, Account>,
+    instruments: InstrumentRegistry,
+    maintenance_margin_ratio: Decimal,
+    /// Leverage assigned to positions opened by a fill.
+    default_leverage: u8,
+}
+
+impl Sentinel {
+    pub fn new(
+        instruments: InstrumentRegistry,
+        maintenance_margin_ratio: Decimal,
+        default_leverage: u8,
+    ) -> Self {
+        assert!(default_leverage > 0, "leverage must be non-zero");
+        Self {
+            accounts: DashMap::new(),
+            instruments,
+            maintenance_margin_ratio,
+            default_leverage,
+        }
+    }
+
+    pub fn add_account(&self, account: Account) {
+        self.accounts.insert(account.user_id, account);
+        gauge!("sentinel.accounts_total").set(self.accounts.len() as f64);
+    }
+
+    pub fn account(&self, user_id: u64) -> Option<Account> {
+        self.accounts.get(&user_id).map(|a| a.clone())
+    }
+
+    pub fn instruments(&self) -> &InstrumentRegistry {
+        &self.instruments
+    }
+
+    /// Checks that `order` could be margined from the user's free collateral,
+    /// i.e. collateral not already committed as initial margin. Market orders
+    /// carry no price and are not checked here.
+    pub fn check_margin(&self, order: &Order) -> Result<(), RejectReason> {
+        if order.order_type == OrderType::Market {
+            return Ok(());
+        }
+        let instrument = self
+            .instruments
+            .get(&order.symbol)
+            .ok_or(RejectReason::UnknownSymbol)?;
+        let account = self
+            .accounts
+            .get(&order.user_id)
+            .ok_or(RejectReason::InsufficientMargin)?;
+
+        let committed: Decimal = account.positions.iter().map(Position::initial_margin).sum();
+        let required = instrument
+            .notional(order.price, order.quantity)
+            .ok_or(RejectReason::InsufficientMargin)?
+            / Decimal::from(self.default_leverage);
+        if account.collateral - committed < required {
+            return Err(RejectReason::InsufficientMargin);
+        }
+        Ok(())
+    }
+
+    /// Applies both sides of a fill to the buyer's and seller's positions,
+    /// converting Titan's integer units with the instrument's scales, and
+    /// debits each side's fee from collateral. Users without an account are
+    /// skipped.
+    pub fn apply_execution(&self, execution: &Execution) -> Vec<SystemEvent> {
+        let Some(instrument) = self.instruments.get(&execution.symbol) else {
+            return Vec::new();
+        };
+        let price = instrument.price_to_decimal(execution.price);
+        let size = instrument.quantity_to_decimal(execution.quantity);
+
+        let mut events = Vec::new();
+        for (user_id, side) in [
+            (execution.buyer_user_id, Side::Buy),
+            (execution.seller_user_id, Side::Sell),
+        ] {
+            let Some(mut account) = self.accounts.get_mut(&user_id) else {
+                continue;
+            };
+            let fee = if side == execution.aggressor_side {
+                execution.taker_fee
+            } else {
+                execution.maker_fee
+            };
+            let fill = Fill {
+                symbol: &execution.symbol,
+                side,
+                price,
+                size,
+                fee,
+                timestamp: execution.timestamp,
+            };
+            events.extend(self.apply_fill(&mut account, fill));
+        }
+        events
+    }
+
+    fn apply_fill(&self, account: &mut Account, fill: Fill<'_>) -> Vec<SystemEvent> {
+        let fill_side = match fill.side {
+            Side::Buy => PositionSide::Long,
+            Side::Sell => PositionSide::Short,
+        };
+        let existing = account
+            .positions
+            .iter()
+            .position(|p| p.symbol == fill.symbol);
+
+        let mut events = Vec::new();
+        let mut opened = None;
+        let mut updated = None;
+        let mut realized = None;
+        match existing {
+            None => opened = Some(fill.size),
+            Some(index) if account.positions[index].side == fill_side => {
+                let position = &mut account.positions[index];
+                let total = position.size + fill.size;
+                position.entry_price =
+                    (position.entry_price * position.size + fill.price * fill.size) / total;
+                position.size = total;
+                position.liquidation_price = self.liquidation_price(position);
+                updated = Some(position.clone());
+            }
+            Some(index) => {
+                let position = &mut account.positions[index];
+                let closed = position.size.min(fill.size);
+                realized = Some(
+                    match position.side {
+                        PositionSide::Long => fill.price - position.entry_price,
+                        PositionSide::Short => position.entry_price - fill.price,
+                    } * closed,
+                );
+                position.size -= closed;
+                updated = Some(position.clone());
+                if position.size.is_zero() {
+                    account.positions.remove(index);
+                }
+                if fill.size > closed {
+                    opened = Some(fill.size - closed);
+                }
+            }
+        }
+
+        if let Some(position) = updated {
+            events.push(SystemEvent::PositionUpdated {
+                user_id: account.user_id,
+                position,
+                timestamp: fill.timestamp,
+            });
+        }
+
+        if realized.is_some() || !fill.fee.is_zero() {
+            account.collateral += realized.unwrap_or_default() - fill.fee;
+            events.push(SystemEvent::AccountUpdated {
+                user_id: account.user_id,
+                collateral: account.collateral,
+                margin_ratio: account.margin_ratio,
+                timestamp: fill.timestamp,
+            });
+        }
+
+        if let Some(size) = opened {
+            let mut position = Position {
+                symbol: fill.symbol.to_string(),
+                side: fill_side,
+                size,
+                entry_price: fill.price,
+                leverage: self.default_leverage,
+                liquidation_price: Decimal::ZERO,
+                unrealized_pnl: Decimal::ZERO,
+            };
+            position.liquidation_price = self.liquidation_price(&position);
+            account.positions.push(position.clone());
+            counter!("sentinel.positions_opened").increment(1);
+            events.push(SystemEvent::PositionOpened {
+                user_id: account.user_id,
+                position,
+                timestamp: fill.timestamp,
+            });
+        }
+        events
+    }
+
+    /// Price at which the position's margin falls to the maintenance ratio.
+    fn liquidation_price(&self, position: &Position) -> Decimal {
+        let margin = Decimal::ONE / Decimal::from(position.leverage);
+        match position.side {
+            PositionSide::Long => {
+                position.entry_price * (Decimal::ONE - margin + self.maintenance_margin_ratio)
+            }
+            PositionSide::Short => {
+                position.entry_price * (Decimal::ONE + margin - self.maintenance_margin_ratio)
+            }
+        }
+    }
+}
+
+/// One side of an execution, in decimal units.
+struct Fill<'a> {
+    symbol: &'a str,
+    side: Side,
+    price: Decimal,
+    size: Decimal,
+    /// Fee owed by this side; negative for a rebate.
+    fee: Decimal,
+    timestamp: u64,
+}
+
+impl RiskCheck for Arc<Sentinel> {
+    fn check(&mut self, command: &OrderCommand) -> Result<(), RejectReason> {
+        match command {
+            OrderCommand::Submit(order) => self.check_margin(order),
+            OrderCommand::PlaceStop(stop) => self.check_margin(&stop.order),
+            _ => Ok(()),
+        }
+    }
+}
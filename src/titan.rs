@@ -0,0 +1,3847 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{self, Sender};
+use metrics::{counter, gauge, histogram};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::disruptor::{Disruptor, DisruptorBuilder, EventHandler, Producer};
+use crate::fees::{FeeEngine, RollingVolume};
+use crate::instruments::{Instrument, InstrumentRegistry};
+use crate::market_data::{BookOrder, MarketData, MarketDataPublisher, OrderUpdate};
+use crate::oracle::OracleState;
+use crate::types::{
+    AllocationPolicy, Execution, InterruptionAction, Order, OrderType, PriceBands, PriceUpdate,
+    RejectReason, SelfTradeEvent, SelfTradePrevention, SessionSchedule, SessionState,
+    SessionTrigger, Side, StopOrder, SystemEvent, TimeInForce, TriggerPrice, VolatilityGuard,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderStatus {
+    Filled,
+    /// Unfilled quantity is resting on the book.
+    Resting,
+    /// Unfilled quantity was cancelled (market and IOC orders, by
+    /// self-trade prevention, or because the volatility guard halted the
+    /// book).
+    Cancelled,
+    Rejected(RejectReason),
+}
+
+/// Result of submitting a single order to an [`OrderBook`].
+#[derive(Debug)]
+pub struct SubmitOutcome {
+    pub status: OrderStatus,
+    pub executions: Vec<Execution>,
+    /// Good-till-date orders removed because they expired before this order.
+    pub expired: Vec<Order>,
+    pub self_trades: Vec<SelfTradeEvent>,
+    /// Unfilled quantity once matching finished. It rests if the status is
+    /// `Resting` and is gone otherwise.
+    pub remaining: u64,
+    /// Matching stopped because the next trade would have breached the
+    /// volatility guard; the book has moved to the guard's target state.
+    pub interrupted: bool,
+}
+
+/// Recent trade prices checked against the volatility guard.
+#[derive(Debug, Default)]
+struct VolatilityMonitor {
+    guard: Option<VolatilityGuard>,
+    /// (timestamp, price), oldest first.
+    trades: VecDeque<(u64, u64)>,
+}
+
+impl VolatilityMonitor {
+    fn breached(&self, price: u64, timestamp: u64) -> bool {
+        let Some(guard) = self.guard else {
+            return false;
+        };
+        self.trades
+            .iter()
+            .filter(|(at, _)| at + guard.window_ms >= timestamp)
+            .any(|&(_, traded)| {
+                u128::from(price.abs_diff(traded)) * 10_000
+                    > u128::from(traded) * u128::from(guard.max_move_bps)
+            })
+    }
+
+    fn record(&mut self, price: u64, timestamp: u64) {
+        let Some(guard) = self.guard else {
+            return;
+        };
+        self.trades.push_back((timestamp, price));
+        while self
+            .trades
+            .front()
+            .is_some_and(|(at, _)| at + guard.window_ms < timestamp)
+        {
+            self.trades.pop_front();
+        }
+    }
+}
+
+/// Price and volume at which a call auction would execute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uncross {
+    pub price: u64,
+    pub volume: u64,
+}
+
+/// A resting order and the part of it currently shown on the book.
+#[derive(Debug)]
+struct RestingOrder {
+    order: Order,
+    /// Displayed slice; equal to `order.quantity` unless the order is an iceberg.
+    visible: u64,
+}
+
+/// All resting orders at a single price, oldest first.
+#[derive(Debug, Default)]
+struct PriceLevel {
+    orders: VecDeque<RestingOrder>,
+    /// Remaining quantity including hidden iceberg reserve.
+    total_quantity: u64,
+    /// Quantity shown to market data.
+    visible_quantity: u64,
+    /// Order that opened this level by improving the best price. Under hybrid
+    /// allocation it is filled first by the next aggressive order only.
+    top_order: Option<u64>,
+}
+
+impl PriceLevel {
+    /// Appends `order` to the back of the queue, showing a fresh iceberg slice.
+    fn push(&mut self, order: Order) {
+        let visible = order
+            .display_quantity
+            .map_or(order.quantity, |display| display.min(order.quantity));
+        self.total_quantity += order.quantity;
+        self.visible_quantity += visible;
+        self.orders.push_back(RestingOrder { order, visible });
+    }
+
+    /// Fills `quantity` of the order at `position`, drawing on hidden reserve
+    /// once the displayed slice is used up.
+    fn take(&mut self, position: usize, quantity: u64) {
+        let resting = &mut self.orders[position];
+        let shown = quantity.min(resting.visible);
+        resting.order.quantity -= quantity;
+        resting.visible -= shown;
+        self.total_quantity -= quantity;
+        self.visible_quantity -= shown;
+    }
+}
+
+/// Price-time priority limit order book for a single symbol.
+///
+/// Bids and asks are kept in `BTreeMap`s keyed by price so the best level is
+/// always at one end of the map. Within a level orders are matched FIFO
+/// unless the instrument uses a pro-rata [`AllocationPolicy`].
+#[derive(Debug)]
+pub struct OrderBook {
+    instrument: Instrument,
+    bids: BTreeMap<u64, PriceLevel>,
+    asks: BTreeMap<u64, PriceLevel>,
+    /// order_id -> (side, price) of every resting order.
+    index: HashMap<u64, (Side, u64)>,
+    /// (expire_at, order_id) of resting good-till-date orders.
+    expiries: BTreeSet<(u64, u64)>,
+    /// Id of the most recent execution; trade ids are sequential per book
+    /// and carry on from [`resume_trade_ids`] after a restart.
+    ///
+    /// [`resume_trade_ids`]: OrderBook::resume_trade_ids
+    last_trade_id: u64,
+    /// Prices fees on each execution. Without one, trading is free.
+    fees: Option<FeeEngine>,
+    state: SessionState,
+    last_trade_price: Option<u64>,
+    /// Latest mark price, used as the band reference when configured.
+    mark_price: Option<u64>,
+    bands: PriceBands,
+    volatility: VolatilityMonitor,
+    allocation: AllocationPolicy,
+    /// Order-level changes since the last [`take_changes`], when recording.
+    ///
+    /// [`take_changes`]: OrderBook::take_changes
+    changes: Option<Vec<OrderUpdate>>,
+}
+
+impl OrderBook {
+    pub fn new(instrument: Instrument) -> Self {
+        Self {
+            instrument,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            index: HashMap::new(),
+            expiries: BTreeSet::new(),
+            last_trade_id: 0,
+            fees: None,
+            state: SessionState::Continuous,
+            last_trade_price: None,
+            mark_price: None,
+            bands: PriceBands::default(),
+            volatility: VolatilityMonitor::default(),
+            allocation: AllocationPolicy::default(),
+            changes: None,
+        }
+    }
+
+    pub fn with_fee_engine(mut self, fees: FeeEngine) -> Self {
+        self.fees = Some(fees);
+        self
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.instrument.symbol
+    }
+
+    pub fn instrument(&self) -> &Instrument {
+        &self.instrument
+    }
+
+    pub fn set_price_bands(&mut self, bands: PriceBands) {
+        self.bands = bands;
+        self.volatility = VolatilityMonitor {
+            guard: bands.volatility,
+            trades: VecDeque::new(),
+        };
+    }
+
+    pub fn price_bands(&self) -> &PriceBands {
+        &self.bands
+    }
+
+    pub fn set_allocation(&mut self, allocation: AllocationPolicy) {
+        self.allocation = allocation;
+    }
+
+    pub fn allocation(&self) -> AllocationPolicy {
+        self.allocation
+    }
+
+    /// Starts recording order-level changes for market data. The caller must
+    /// drain them with [`take_changes`] after every operation.
+    ///
+    /// [`take_changes`]: OrderBook::take_changes
+    pub fn record_changes(&mut self) {
+        self.changes.get_or_insert_with(Vec::new);
+    }
+
+    pub fn take_changes(&mut self) -> Vec<OrderUpdate> {
+        self.changes
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn emit(&mut self, update: OrderUpdate) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(update);
+        }
+    }
+
+    /// Publishes the displayed quantity a resting order is left with.
+    fn emit_resting(&mut self, resting: &RestingOrder) {
+        let order = &resting.order;
+        self.emit(if resting.visible > 0 {
+            OrderUpdate::Modify {
+                order_id: order.order_id,
+                side: order.side,
+                price: order.price,
+                quantity: resting.visible,
+            }
+        } else {
+            OrderUpdate::Delete {
+                order_id: order.order_id,
+                side: order.side,
+                price: order.price,
+            }
+        });
+    }
+
+    pub fn last_trade_id(&self) -> u64 {
+        self.last_trade_id
+    }
+
+    /// Continues trade ids after `last_trade_id`, e.g. the last one the
+    /// event log recorded for this symbol. Ids never move backwards.
+    pub fn resume_trade_ids(&mut self, last_trade_id: u64) {
+        self.last_trade_id = self.last_trade_id.max(last_trade_id);
+    }
+
+    /// Adds this symbol's rolling volumes, in scaled units, to those the
+    /// fee tiers are based on.
+    pub fn resume_fee_volumes(&self, volumes: &BTreeMap<u64, RollingVolume>) {
+        if let Some(fees) = &self.fees {
+            fees.resume(&self.instrument, volumes);
+        }
+    }
+
+    pub fn set_mark_price(&mut self, price: u64) {
+        self.mark_price = Some(price);
+    }
+
+    /// Price the static band is centred on, if one is known yet.
+    pub fn reference_price(&self) -> Option<u64> {
+        match self.bands.reference {
+            TriggerPrice::LastTrade => self.last_trade_price,
+            TriggerPrice::MarkPrice => self.mark_price,
+        }
+    }
+
+    /// Matches `order` against the opposite side of the book according to its
+    /// order type and time in force. Limit orders that may rest do so at
+    /// `order.price`; executions are priced at the resting order's level.
+    /// During a call phase orders only rest, even if they cross; while halted
+    /// or closed they are rejected. An order reusing the id of a resting order
+    /// is rejected.
+    pub fn submit(&mut self, mut order: Order) -> SubmitOutcome {
+        debug_assert_eq!(order.symbol, self.instrument.symbol);
+        counter!("titan.orders_processed").increment(1);
+
+        let expired = self.expire_orders(order.timestamp);
+
+        let prepared = if self.contains(order.order_id) {
+            Err(RejectReason::DuplicateOrderId)
+        } else {
+            self.admit(&mut order)
+        };
+        if let Err(reason) = prepared {
+            counter!("titan.orders_rejected", "reason" => format!("{reason:?}")).increment(1);
+            return SubmitOutcome {
+                status: OrderStatus::Rejected(reason),
+                executions: Vec::new(),
+                expired,
+                self_trades: Vec::new(),
+                remaining: order.quantity,
+                interrupted: false,
+            };
+        }
+
+        if self.state.is_call_phase() {
+            let remaining = order.quantity;
+            self.rest(order);
+            return SubmitOutcome {
+                status: OrderStatus::Resting,
+                executions: Vec::new(),
+                expired,
+                self_trades: Vec::new(),
+                remaining,
+                interrupted: false,
+            };
+        }
+
+        let mut self_trades = Vec::new();
+        let (executions, interrupted) = self.match_order(&mut order, &mut self_trades);
+        let taker_cancelled = self_trades.iter().any(|t| t.taker_quantity_cancelled > 0);
+        if interrupted {
+            counter!("titan.volatility_interruptions").increment(1);
+            if let Some(guard) = self.bands.volatility {
+                self.state = match guard.action {
+                    InterruptionAction::Halt => SessionState::Halted,
+                    InterruptionAction::Auction { .. } => SessionState::Auction,
+                };
+            }
+        }
+
+        let remaining = order.quantity;
+        let status = if order.quantity == 0 && !taker_cancelled {
+            OrderStatus::Filled
+        } else if order.quantity == 0 {
+            OrderStatus::Cancelled
+        } else if Self::can_rest(&order) && self.state != SessionState::Halted {
+            self.rest(order);
+            OrderStatus::Resting
+        } else {
+            OrderStatus::Cancelled
+        };
+
+        if let Some(spread) = self.spread() {
+            gauge!("titan.spread").set(spread as f64);
+        }
+
+        SubmitOutcome {
+            status,
+            executions,
+            expired,
+            self_trades,
+            remaining,
+            interrupted,
+        }
+    }
+
+    /// Removes a resting order from the book.
+    pub fn cancel(&mut self, order_id: u64) -> Result<Order, RejectReason> {
+        self.remove_resting(order_id)
+            .ok_or(RejectReason::UnknownOrder)
+    }
+
+    /// Cancels a resting order and resubmits it with a new price and
+    /// quantity. The replacement joins the back of the queue at its new
+    /// level and may trade immediately. It is checked exactly as a new order
+    /// first; if it would be rejected the original keeps resting untouched.
+    pub fn replace(
+        &mut self,
+        order_id: u64,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    ) -> Result<SubmitOutcome, RejectReason> {
+        let mut order = self
+            .order(order_id)
+            .ok_or(RejectReason::UnknownOrder)?
+            .clone();
+        order.price = price;
+        order.quantity = quantity;
+        order.timestamp = timestamp;
+        // The original rests on the other side from anything the
+        // replacement can cross, so it does not affect these checks.
+        self.admit(&mut order)?;
+        self.cancel(order_id)?;
+        Ok(self.submit(order))
+    }
+
+    /// Reduces the quantity of a resting order in place, keeping its
+    /// position in the queue. Returns the order as amended.
+    pub fn amend(&mut self, order_id: u64, quantity: u64) -> Result<Order, RejectReason> {
+        let &(side, price) = self
+            .index
+            .get(&order_id)
+            .ok_or(RejectReason::UnknownOrder)?;
+        let level = self
+            .side_mut(side)
+            .get_mut(&price)
+            .ok_or(RejectReason::UnknownOrder)?;
+        let resting = level
+            .orders
+            .iter_mut()
+            .find(|r| r.order.order_id == order_id)
+            .ok_or(RejectReason::UnknownOrder)?;
+        if quantity == 0 {
+            return Err(RejectReason::ZeroQuantity);
+        }
+        if quantity >= resting.order.quantity {
+            return Err(RejectReason::InvalidQuantity);
+        }
+        let visible = resting.visible.min(quantity);
+        let shown_changed = visible != resting.visible;
+        level.total_quantity -= resting.order.quantity - quantity;
+        level.visible_quantity -= resting.visible - visible;
+        resting.order.quantity = quantity;
+        resting.visible = visible;
+        let amended = resting.order.clone();
+        if shown_changed {
+            self.emit(OrderUpdate::Modify {
+                order_id,
+                side,
+                price,
+                quantity: visible,
+            });
+        }
+        Ok(amended)
+    }
+
+    /// Removes every resting good-till-date order whose expiry is at or
+    /// before `now`.
+    pub fn expire_orders(&mut self, now: u64) -> Vec<Order> {
+        let mut expired = Vec::new();
+        while let Some(&(expire_at, order_id)) = self.expiries.first() {
+            if expire_at > now {
+                break;
+            }
+            expired.extend(self.remove_resting(order_id));
+        }
+        expired
+    }
+
+    /// Runs the checks that decide whether `order` is accepted in the
+    /// current session state, repricing it if it slides.
+    fn admit(&self, order: &mut Order) -> Result<(), RejectReason> {
+        match self.state {
+            SessionState::Continuous => self.prepare(order),
+            SessionState::PreOpen | SessionState::Auction => self.prepare_for_auction(order),
+            SessionState::Halted | SessionState::Closed => self.check_open(),
+        }
+    }
+
+    /// Checks `order` against the instrument's tick size, lot size and
+    /// minimum notional. A notional too large to represent is an invalid
+    /// quantity.
+    fn validate(&self, order: &Order) -> Result<(), RejectReason> {
+        let instrument = &self.instrument;
+        if order.quantity == 0 {
+            return Err(RejectReason::ZeroQuantity);
+        }
+        if order.display_quantity == Some(0) {
+            return Err(RejectReason::InvalidQuantity);
+        }
+        if !instrument.is_on_lot(order.quantity)
+            || !order
+                .display_quantity
+                .is_none_or(|d| instrument.is_on_lot(d))
+        {
+            return Err(RejectReason::OffLot);
+        }
+        if order.order_type == OrderType::Market {
+            return Ok(());
+        }
+        if order.price == 0 || !instrument.is_on_tick(order.price) {
+            return Err(RejectReason::OffTick);
+        }
+        let notional = instrument
+            .notional(order.price, order.quantity)
+            .ok_or(RejectReason::InvalidQuantity)?;
+        if notional < instrument.min_notional {
+            return Err(RejectReason::BelowMinNotional);
+        }
+        if !self.within_band(order.price) {
+            return Err(RejectReason::PriceOutOfBand);
+        }
+        Ok(())
+    }
+
+    /// Whether `price` lies inside the static band. Without a band or a
+    /// reference price every price is accepted.
+    fn within_band(&self, price: u64) -> bool {
+        let (Some(band_bps), Some(reference)) =
+            (self.bands.static_band_bps, self.reference_price())
+        else {
+            return true;
+        };
+        u128::from(price.abs_diff(reference)) * 10_000
+            <= u128::from(reference) * u128::from(band_bps)
+    }
+
+    /// Validates `order`, applies time-in-force checks that must pass before
+    /// any matching, and reprices post-only-slide orders that would otherwise
+    /// cross.
+    fn prepare(&self, order: &mut Order) -> Result<(), RejectReason> {
+        self.validate(order)?;
+        Self::check_time_in_force(order)?;
+        let tick = self.instrument.tick_size;
+        match order.time_in_force {
+            TimeInForce::PostOnly if self.would_cross(order) => {
+                Err(RejectReason::PostOnlyWouldCross)
+            }
+            TimeInForce::PostOnlySlide => {
+                if self.would_cross(order) {
+                    order.price = match order.side {
+                        Side::Buy => self
+                            .best_ask()
+                            .map_or(order.price, |ask| ask.saturating_sub(tick)),
+                        Side::Sell => self.best_bid().map_or(order.price, |bid| bid + tick),
+                    };
+                }
+                if order.price == 0 {
+                    return Err(RejectReason::PostOnlyWouldCross);
+                }
+                Ok(())
+            }
+            TimeInForce::FillOrKill if self.fillable_quantity(order) < order.quantity => {
+                Err(RejectReason::FillOrKillUnfillable)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Time-in-force checks that do not depend on the book.
+    fn check_time_in_force(order: &Order) -> Result<(), RejectReason> {
+        match order.time_in_force {
+            TimeInForce::GoodTillDate(expire_at) if expire_at <= order.timestamp => {
+                Err(RejectReason::Expired)
+            }
+            TimeInForce::PostOnly | TimeInForce::PostOnlySlide
+                if order.order_type == OrderType::Market =>
+            {
+                Err(RejectReason::InvalidTimeInForce)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a stop before it is parked: the order it releases must pass the
+    /// same instrument, band and time-in-force checks as a new order, and the
+    /// stop price and trail must be on tick. Checks that depend on the book
+    /// when it is released, such as post-only or fill-or-kill, run then.
+    pub fn check_stop(&self, stop: &StopOrder) -> Result<(), RejectReason> {
+        self.check_open()?;
+        let order = &stop.order;
+        if self.contains(order.order_id) {
+            return Err(RejectReason::DuplicateOrderId);
+        }
+        self.validate(order)?;
+        let on_tick = |price: u64| price > 0 && self.instrument.is_on_tick(price);
+        if !on_tick(stop.stop_price) || !stop.trail.is_none_or(on_tick) {
+            return Err(RejectReason::OffTick);
+        }
+        Self::check_time_in_force(order)
+    }
+
+    /// Only orders that can wait for the uncross are accepted in an auction.
+    fn prepare_for_auction(&self, order: &Order) -> Result<(), RejectReason> {
+        self.validate(order)?;
+        match order.time_in_force {
+            TimeInForce::GoodTillDate(expire_at) if expire_at <= order.timestamp => {
+                Err(RejectReason::Expired)
+            }
+            _ if !Self::can_rest(order) => Err(RejectReason::NotAllowedInAuction),
+            TimeInForce::PostOnly | TimeInForce::PostOnlySlide => {
+                Err(RejectReason::NotAllowedInAuction)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// New orders and replacements are refused while halted or closed.
+    /// Cancels and quantity reductions are always accepted.
+    fn check_open(&self) -> Result<(), RejectReason> {
+        match self.state {
+            SessionState::Halted => Err(RejectReason::TradingHalted),
+            SessionState::Closed => Err(RejectReason::MarketClosed),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Moves the book to `state`. Leaving a call phase for continuous trading
+    /// or the close uncrosses the book; the resulting executions are
+    /// returned. A halt suspends a call phase without uncrossing.
+    pub fn set_state(&mut self, state: SessionState, timestamp: u64) -> Vec<Execution> {
+        let uncross = self.state.is_call_phase()
+            && matches!(state, SessionState::Continuous | SessionState::Closed);
+        self.state = state;
+        if uncross {
+            self.uncross(timestamp)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Price and volume the book would uncross at right now, if it is crossed.
+    ///
+    /// The price maximises executable volume; ties go to the smallest
+    /// imbalance, then the price closest to the last trade, then the lowest
+    /// price.
+    pub fn indicative_uncross(&self) -> Option<Uncross> {
+        let (best_bid, best_ask) = (self.best_bid()?, self.best_ask()?);
+        if best_bid < best_ask {
+            return None;
+        }
+
+        let candidates: BTreeSet<u64> = self
+            .bids
+            .range(best_ask..=best_bid)
+            .chain(self.asks.range(best_ask..=best_bid))
+            .map(|(&price, _)| price)
+            .collect();
+
+        candidates
+            .into_iter()
+            .map(|price| {
+                let demand: u64 = self
+                    .bids
+                    .range(price..)
+                    .map(|(_, l)| l.total_quantity)
+                    .sum();
+                let supply: u64 = self
+                    .asks
+                    .range(..=price)
+                    .map(|(_, l)| l.total_quantity)
+                    .sum();
+                let volume = demand.min(supply);
+                let imbalance = demand.abs_diff(supply);
+                let distance = self.last_trade_price.map_or(0, |last| price.abs_diff(last));
+                (Reverse(volume), imbalance, distance, price)
+            })
+            .min()
+            .map(|(Reverse(volume), _, _, price)| Uncross { price, volume })
+    }
+
+    /// Executes the auction at its uncross price. Orders are allocated by
+    /// price then time priority; the order that arrived later is recorded as
+    /// the aggressor of each execution.
+    fn uncross(&mut self, timestamp: u64) -> Vec<Execution> {
+        let Some(uncross) = self.indicative_uncross() else {
+            return Vec::new();
+        };
+
+        let mut executions = Vec::new();
+        let mut remaining = uncross.volume;
+        while remaining > 0 {
+            let (Some(&bid_price), Some(&ask_price)) =
+                (self.bids.keys().next_back(), self.asks.keys().next())
+            else {
+                break;
+            };
+            let (Some(mut bids), Some(mut asks)) =
+                (self.bids.remove(&bid_price), self.asks.remove(&ask_price))
+            else {
+                break;
+            };
+            let bid = &bids.orders[0].order;
+            let ask = &asks.orders[0].order;
+            let quantity = remaining.min(bid.quantity).min(ask.quantity);
+            let (taker, maker) = if (ask.timestamp, ask.order_id) > (bid.timestamp, bid.order_id) {
+                (ask, bid)
+            } else {
+                (bid, ask)
+            };
+            executions.push(self.trade(taker, maker, uncross.price, quantity, timestamp));
+
+            self.fill_resting(&mut bids, 0, quantity);
+            self.fill_resting(&mut asks, 0, quantity);
+            if !bids.orders.is_empty() {
+                self.bids.insert(bid_price, bids);
+            }
+            if !asks.orders.is_empty() {
+                self.asks.insert(ask_price, asks);
+            }
+            remaining -= quantity;
+        }
+
+        counter!("titan.auction_uncrosses").increment(1);
+        executions
+    }
+
+    fn can_rest(order: &Order) -> bool {
+        order.order_type == OrderType::Limit
+            && !matches!(
+                order.time_in_force,
+                TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+            )
+    }
+
+    /// Whether `order` may trade at `level_price`. Market orders cross any level.
+    fn crosses(order: &Order, level_price: u64) -> bool {
+        if order.order_type == OrderType::Market {
+            return true;
+        }
+        match order.side {
+            Side::Buy => level_price <= order.price,
+            Side::Sell => level_price >= order.price,
+        }
+    }
+
+    fn would_cross(&self, order: &Order) -> bool {
+        let best = match order.side {
+            Side::Buy => self.best_ask(),
+            Side::Sell => self.best_bid(),
+        };
+        best.is_some_and(|price| Self::crosses(order, price))
+    }
+
+    /// Quantity `order` could take from the book right now, capped at its size.
+    /// Orders it would be prevented from trading with, and levels the
+    /// volatility guard would block, are not counted.
+    fn fillable_quantity(&self, order: &Order) -> u64 {
+        let levels: &mut dyn Iterator<Item = (&u64, &PriceLevel)> = match order.side {
+            Side::Buy => &mut self.asks.iter(),
+            Side::Sell => &mut self.bids.iter().rev(),
+        };
+        let mut fillable = 0;
+        for (&price, level) in levels {
+            if fillable >= order.quantity
+                || !Self::crosses(order, price)
+                || self.volatility.breached(price, order.timestamp)
+            {
+                break;
+            }
+            fillable += if order.self_trade_prevention == SelfTradePrevention::None {
+                level.total_quantity
+            } else {
+                level
+                    .orders
+                    .iter()
+                    .filter(|r| r.order.user_id != order.user_id)
+                    .map(|r| r.order.quantity)
+                    .sum()
+            };
+        }
+        fillable.min(order.quantity)
+    }
+
+    fn rest(&mut self, order: Order) {
+        self.index.insert(order.order_id, (order.side, order.price));
+        if let TimeInForce::GoodTillDate(expire_at) = order.time_in_force {
+            self.expiries.insert((expire_at, order.order_id));
+        }
+        let improves = match order.side {
+            Side::Buy => self.best_bid().is_none_or(|bid| order.price > bid),
+            Side::Sell => self.best_ask().is_none_or(|ask| order.price < ask),
+        };
+        let (order_id, side, price) = (order.order_id, order.side, order.price);
+        let level = self.side_mut(side).entry(price).or_default();
+        if improves {
+            level.top_order = Some(order_id);
+        }
+        level.push(order);
+        let quantity = level.orders.back().map_or(0, |r| r.visible);
+        self.emit(OrderUpdate::Add {
+            order_id,
+            side,
+            price,
+            quantity,
+        });
+    }
+
+    /// Takes a resting order off the book, dropping its level if it empties.
+    fn remove_resting(&mut self, order_id: u64) -> Option<Order> {
+        let (side, price) = self.index.remove(&order_id)?;
+        let book_side = self.side_mut(side);
+        let level = book_side.get_mut(&price)?;
+        let position = level
+            .orders
+            .iter()
+            .position(|r| r.order.order_id == order_id)?;
+        let RestingOrder { order, visible } = level.orders.remove(position)?;
+        level.total_quantity -= order.quantity;
+        level.visible_quantity -= visible;
+        if level.orders.is_empty() {
+            book_side.remove(&price);
+        }
+        if let TimeInForce::GoodTillDate(expire_at) = order.time_in_force {
+            self.expiries.remove(&(expire_at, order_id));
+        }
+        self.emit(OrderUpdate::Delete {
+            order_id,
+            side,
+            price,
+        });
+        Some(order)
+    }
+
+    /// Matches `order` level by level. Also returns whether matching stopped
+    /// early because the next level would have breached the volatility guard.
+    fn match_order(
+        &mut self,
+        order: &mut Order,
+        self_trades: &mut Vec<SelfTradeEvent>,
+    ) -> (Vec<Execution>, bool) {
+        let contra = order.side.opposite();
+        let mut executions = Vec::new();
+
+        while order.quantity > 0 {
+            let best = match contra {
+                Side::Buy => self.bids.last_key_value(),
+                Side::Sell => self.asks.first_key_value(),
+            };
+            let Some((&level_price, _)) = best else { break };
+            if !Self::crosses(order, level_price) {
+                break;
+            }
+            if self.volatility.breached(level_price, order.timestamp) {
+                return (executions, true);
+            }
+
+            // The level is worked outside the map so the rest of the book
+            // stays accessible, and put back if anything is left.
+            let Some(mut level) = self.side_mut(contra).remove(&level_price) else {
+                break;
+            };
+            match self.allocation {
+                AllocationPolicy::Fifo => {
+                    self.fill_fifo(order, level_price, &mut level, &mut executions, self_trades)
+                }
+                policy => self.fill_pro_rata(
+                    order,
+                    level_price,
+                    &mut level,
+                    policy,
+                    &mut executions,
+                    self_trades,
+                ),
+            }
+            if !level.orders.is_empty() {
+                self.side_mut(contra).insert(level_price, level);
+            }
+        }
+
+        (executions, false)
+    }
+
+    /// Fills `order` from the front of `level` in time priority.
+    fn fill_fifo(
+        &mut self,
+        order: &mut Order,
+        price: u64,
+        level: &mut PriceLevel,
+        executions: &mut Vec<Execution>,
+        self_trades: &mut Vec<SelfTradeEvent>,
+    ) {
+        while order.quantity > 0 {
+            let Some(resting) = level.orders.front() else {
+                break;
+            };
+            if self_trade_blocked(order, &resting.order) {
+                self_trades.push(self.prevent_self_trade(order, level, 0));
+                continue;
+            }
+
+            let quantity = order.quantity.min(resting.visible);
+            order.quantity -= quantity;
+            let execution = self.trade(
+                order,
+                &level.orders[0].order,
+                price,
+                quantity,
+                order.timestamp,
+            );
+            executions.push(execution);
+            self.fill_resting(level, 0, quantity);
+        }
+    }
+
+    /// Shares the fill across `level` in proportion to displayed quantity,
+    /// after any top-of-book priority fill. Repeats while iceberg refreshes
+    /// keep quantity on the level.
+    fn fill_pro_rata(
+        &mut self,
+        order: &mut Order,
+        price: u64,
+        level: &mut PriceLevel,
+        policy: AllocationPolicy,
+        executions: &mut Vec<Execution>,
+        self_trades: &mut Vec<SelfTradeEvent>,
+    ) {
+        while order.quantity > 0 && !level.orders.is_empty() {
+            if let Some(position) = level
+                .orders
+                .iter()
+                .position(|r| self_trade_blocked(order, &r.order))
+            {
+                self_trades.push(self.prevent_self_trade(order, level, position));
+                continue;
+            }
+
+            let shares = allocate(level, order.quantity, policy, self.instrument.lot_size);
+            level.top_order = None;
+            for (position, quantity) in shares.into_iter().enumerate() {
+                if quantity == 0 {
+                    continue;
+                }
+                order.quantity -= quantity;
+                let maker = &level.orders[position].order;
+                executions.push(self.trade(order, maker, price, quantity, order.timestamp));
+                level.take(position, quantity);
+                self.emit_resting(&level.orders[position]);
+            }
+
+            // Each original position is visited once; refreshed icebergs
+            // are pushed behind them.
+            let mut position = 0;
+            for _ in 0..level.orders.len() {
+                if level.orders[position].visible == 0 {
+                    self.retire(level, position);
+                } else {
+                    position += 1;
+                }
+            }
+        }
+    }
+
+    /// Books one fill of `taker` against `maker` at `price`. The resting
+    /// orders are not touched.
+    fn trade(
+        &mut self,
+        taker: &Order,
+        maker: &Order,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    ) -> Execution {
+        let (buyer, seller) = match taker.side {
+            Side::Buy => (taker, maker),
+            Side::Sell => (maker, taker),
+        };
+        self.last_trade_id += 1;
+        let mut execution = Execution {
+            trade_id: self.last_trade_id,
+            symbol: taker.symbol.clone(),
+            buy_order_id: buyer.order_id,
+            sell_order_id: seller.order_id,
+            buyer_user_id: buyer.user_id,
+            seller_user_id: seller.user_id,
+            aggressor_side: taker.side,
+            price,
+            quantity,
+            timestamp,
+            maker_fee: Decimal::ZERO,
+            taker_fee: Decimal::ZERO,
+        };
+        if let Some(fees) = &self.fees {
+            fees.charge(&mut execution, &self.instrument);
+        }
+        self.emit(OrderUpdate::Trade {
+            trade_id: execution.trade_id,
+            buy_order_id: execution.buy_order_id,
+            sell_order_id: execution.sell_order_id,
+            aggressor_side: execution.aggressor_side,
+            price,
+            quantity,
+        });
+        self.last_trade_price = Some(price);
+        self.volatility.record(price, timestamp);
+
+        counter!("titan.executions_total").increment(1);
+        histogram!("titan.execution_price").record(price as f64);
+        histogram!("titan.execution_quantity").record(quantity as f64);
+        execution
+    }
+
+    /// Applies `order`'s self-trade prevention mode against the resting order
+    /// at `position` of `level`, which belongs to the same user.
+    fn prevent_self_trade(
+        &mut self,
+        order: &mut Order,
+        level: &mut PriceLevel,
+        position: usize,
+    ) -> SelfTradeEvent {
+        let resting = &mut level.orders[position];
+        let (taker_cancelled, maker_cancelled) = match order.self_trade_prevention {
+            SelfTradePrevention::None => (0, 0),
+            SelfTradePrevention::CancelNewest => (order.quantity, 0),
+            SelfTradePrevention::CancelOldest => (0, resting.order.quantity),
+            SelfTradePrevention::CancelBoth => (order.quantity, resting.order.quantity),
+            SelfTradePrevention::DecrementAndCancel => {
+                let quantity = order.quantity.min(resting.order.quantity);
+                (quantity, quantity)
+            }
+        };
+        order.quantity -= taker_cancelled;
+        resting.order.quantity -= maker_cancelled;
+        let visible = resting.visible.min(resting.order.quantity);
+        let shown_changed = visible != resting.visible;
+        level.total_quantity -= maker_cancelled;
+        level.visible_quantity -= resting.visible - visible;
+        resting.visible = visible;
+
+        let event = SelfTradeEvent {
+            user_id: order.user_id,
+            symbol: order.symbol.clone(),
+            taker_order_id: order.order_id,
+            maker_order_id: resting.order.order_id,
+            mode: order.self_trade_prevention,
+            taker_quantity_cancelled: taker_cancelled,
+            maker_quantity_cancelled: maker_cancelled,
+            timestamp: order.timestamp,
+        };
+        counter!("titan.self_trades_prevented").increment(1);
+
+        if shown_changed {
+            self.emit_resting(&level.orders[position]);
+        }
+        if level.orders[position].order.quantity == 0 {
+            self.retire(level, position);
+        }
+        event
+    }
+
+    /// Fills `quantity` of the order at `position`, publishes what it has
+    /// left on display and retires it once its displayed slice is used up.
+    fn fill_resting(&mut self, level: &mut PriceLevel, position: usize, quantity: u64) {
+        level.take(position, quantity);
+        self.emit_resting(&level.orders[position]);
+        if level.orders[position].visible == 0 {
+            self.retire(level, position);
+        }
+    }
+
+    /// Takes the order at `position` out of the queue once its displayed
+    /// slice is used up. An iceberg with hidden quantity left
+    /// rejoins the back of the queue with a fresh slice; anything else
+    /// leaves the book.
+    fn retire(&mut self, level: &mut PriceLevel, position: usize) {
+        let Some(resting) = level.orders.remove(position) else {
+            return;
+        };
+        if resting.order.quantity > 0 {
+            // Iceberg refresh: the next slice goes to the back of the queue.
+            level.total_quantity -= resting.order.quantity;
+            level.push(resting.order);
+            counter!("titan.iceberg_refreshes").increment(1);
+            if let Some(refreshed) = level.orders.back() {
+                self.emit(OrderUpdate::Add {
+                    order_id: refreshed.order.order_id,
+                    side: refreshed.order.side,
+                    price: refreshed.order.price,
+                    quantity: refreshed.visible,
+                });
+            }
+        } else {
+            if let TimeInForce::GoodTillDate(expire_at) = resting.order.time_in_force {
+                self.expiries.remove(&(expire_at, resting.order.order_id));
+            }
+            self.index.remove(&resting.order.order_id);
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeMap<u64, PriceLevel> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<u64> {
+        self.bids.keys().next_back().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<u64> {
+        self.asks.keys().next().copied()
+    }
+
+    pub fn spread(&self) -> Option<u64> {
+        Some(self.best_ask()?.saturating_sub(self.best_bid()?))
+    }
+
+    /// Aggregated `(price, quantity)` levels for one side, best price first.
+    /// Only the displayed slice of iceberg orders is included.
+    pub fn depth(&self, side: Side, levels: usize) -> Vec<(u64, u64)> {
+        let aggregate = |(price, level): (&u64, &PriceLevel)| (*price, level.visible_quantity);
+        match side {
+            Side::Buy => self.bids.iter().rev().take(levels).map(aggregate).collect(),
+            Side::Sell => self.asks.iter().take(levels).map(aggregate).collect(),
+        }
+    }
+
+    /// Displayed quantity at one price, zero if there is no such level.
+    pub fn level_quantity(&self, side: Side, price: u64) -> u64 {
+        let levels = match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        levels.get(&price).map_or(0, |level| level.visible_quantity)
+    }
+
+    /// Displayed part of every resting order on one side, in priority order.
+    pub fn visible_orders(&self, side: Side) -> impl Iterator<Item = BookOrder> + '_ {
+        let levels: Box<dyn Iterator<Item = &PriceLevel>> = match side {
+            Side::Buy => Box::new(self.bids.values().rev()),
+            Side::Sell => Box::new(self.asks.values()),
+        };
+        levels
+            .flat_map(|level| level.orders.iter())
+            .map(|resting| BookOrder {
+                order_id: resting.order.order_id,
+                price: resting.order.price,
+                quantity: resting.visible,
+            })
+    }
+
+    pub fn contains(&self, order_id: u64) -> bool {
+        self.index.contains_key(&order_id)
+    }
+
+    /// Looks up a resting order, including any hidden iceberg quantity.
+    pub fn order(&self, order_id: u64) -> Option<&Order> {
+        let (side, price) = self.index.get(&order_id)?;
+        let book_side = match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        book_side
+            .get(price)?
+            .orders
+            .iter()
+            .map(|r| &r.order)
+            .find(|o| o.order_id == order_id)
+    }
+
+    /// Number of resting orders on both sides.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+}
+
+/// Whether self-trade prevention stops `taker` from trading with `maker`:
+/// both belong to the same user and the taker has a prevention mode set.
+fn self_trade_blocked(taker: &Order, maker: &Order) -> bool {
+    taker.self_trade_prevention != SelfTradePrevention::None && taker.user_id == maker.user_id
+}
+
+/// Splits `quantity` across the orders of `level` for pro-rata matching and
+/// returns each order's share by queue position. Shares are rounded down to
+/// whole lots; rounding leftovers and shares below the minimum allocation
+/// are handed out in time priority, so every unit that can fill does.
+fn allocate(
+    level: &PriceLevel,
+    quantity: u64,
+    policy: AllocationPolicy,
+    lot_size: u64,
+) -> Vec<u64> {
+    let (min_allocation, top_priority) = match policy {
+        AllocationPolicy::Fifo => (u64::MAX, false),
+        AllocationPolicy::ProRata { min_allocation } => (min_allocation, false),
+        AllocationPolicy::Hybrid { min_allocation } => (min_allocation, true),
+    };
+    let mut shares = vec![0; level.orders.len()];
+    let mut remaining = quantity.min(level.visible_quantity);
+
+    let top = level
+        .top_order
+        .filter(|_| top_priority)
+        .and_then(|id| level.orders.iter().position(|r| r.order.order_id == id));
+    if let Some(position) = top {
+        shares[position] = remaining.min(level.orders[position].visible);
+        remaining -= shares[position];
+    }
+
+    let capacity =
+        |position: usize, shares: &[u64]| level.orders[position].visible - shares[position];
+    let total: u64 = (0..shares.len()).map(|p| capacity(p, &shares)).sum();
+    if total > 0 {
+        let pool = remaining;
+        for position in 0..shares.len() {
+            let exact =
+                u128::from(pool) * u128::from(capacity(position, &shares)) / u128::from(total);
+            let share = exact as u64 - exact as u64 % lot_size;
+            if share > 0 && share >= min_allocation {
+                shares[position] += share;
+                remaining -= share;
+            }
+        }
+    }
+
+    for position in 0..shares.len() {
+        let share = remaining.min(capacity(position, &shares));
+        shares[position] += share;
+        remaining -= share;
+    }
+    shares
+}
+
+/// Stops affected by one trigger price.
+#[derive(Debug, Default)]
+pub struct StopActivity {
+    /// Orders released into the book, in placement order.
+    pub released: Vec<Order>,
+    /// `(order_id, stop_price)` of trailing stops that moved and are still
+    /// armed.
+    pub trailed: Vec<(u64, u64)>,
+}
+
+/// Stop, stop-limit and trailing-stop orders for a single symbol.
+///
+/// Stops are few compared to resting orders, so triggers are evaluated with a
+/// linear scan in placement order. That order is also the order in which
+/// triggered stops are released, which keeps replay deterministic.
+#[derive(Debug)]
+pub struct StopBook {
+    instrument: Instrument,
+    stops: Vec<StopOrder>,
+}
+
+impl StopBook {
+    pub fn new(instrument: Instrument) -> Self {
+        Self {
+            instrument,
+            stops: Vec::new(),
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.instrument.symbol
+    }
+
+    pub fn place(&mut self, stop: StopOrder) {
+        debug_assert_eq!(stop.order.symbol, self.instrument.symbol);
+        self.stops.push(stop);
+    }
+
+    pub fn get(&self, order_id: u64) -> Option<&StopOrder> {
+        self.stops.iter().find(|s| s.order.order_id == order_id)
+    }
+
+    pub fn cancel(&mut self, order_id: u64) -> Result<StopOrder, RejectReason> {
+        let position = self
+            .stops
+            .iter()
+            .position(|s| s.order.order_id == order_id)
+            .ok_or(RejectReason::UnknownOrder)?;
+        Ok(self.stops.remove(position))
+    }
+
+    /// Feeds a trade price to stops triggered on [`TriggerPrice::LastTrade`].
+    pub fn on_execution(&mut self, execution: &Execution) -> StopActivity {
+        self.on_price(
+            TriggerPrice::LastTrade,
+            execution.price,
+            execution.timestamp,
+        )
+    }
+
+    /// Feeds a mark price to stops triggered on [`TriggerPrice::MarkPrice`].
+    pub fn on_mark_price(&mut self, update: &PriceUpdate) -> StopActivity {
+        debug_assert_eq!(update.symbol, self.instrument.symbol);
+        match self.instrument.price_from_decimal(update.mark_price) {
+            Some(price) => self.on_price(TriggerPrice::MarkPrice, price, update.timestamp),
+            None => StopActivity::default(),
+        }
+    }
+
+    /// Ratchets trailing stops against `price` and releases every stop on the
+    /// `trigger` source that it reaches. Released orders carry `timestamp`.
+    pub fn on_price(&mut self, trigger: TriggerPrice, price: u64, timestamp: u64) -> StopActivity {
+        let mut activity = StopActivity::default();
+        self.stops.retain_mut(|stop| {
+            if stop.trigger != trigger {
+                return true;
+            }
+            let mut trailed = false;
+            if let Some(trail) = stop.trail {
+                let stop_price = match stop.order.side {
+                    Side::Sell => stop.stop_price.max(price.saturating_sub(trail)),
+                    Side::Buy => stop.stop_price.min(price.saturating_add(trail)),
+                };
+                trailed = stop_price != stop.stop_price;
+                stop.stop_price = stop_price;
+            }
+            let triggered = match stop.order.side {
+                Side::Sell => price <= stop.stop_price,
+                Side::Buy => price >= stop.stop_price,
+            };
+            if triggered {
+                let mut order = stop.order.clone();
+                order.timestamp = timestamp;
+                activity.released.push(order);
+            } else if trailed {
+                activity
+                    .trailed
+                    .push((stop.order.order_id, stop.stop_price));
+            }
+            !triggered
+        });
+        if !activity.released.is_empty() {
+            counter!("titan.stops_triggered").increment(activity.released.len() as u64);
+        }
+        activity
+    }
+
+    pub fn len(&self) -> usize {
+        self.stops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stops.is_empty()
+    }
+}
+
+/// A request to Titan. Every command targets exactly one symbol, which
+/// decides the matching thread that processes it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OrderCommand {
+    Submit(Order),
+    Cancel {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+        timestamp: u64,
+    },
+    Replace {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    Amend {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    PlaceStop(StopOrder),
+    CancelStop {
+        symbol: String,
+        order_id: u64,
+        user_id: u64,
+        timestamp: u64,
+    },
+    MarkPrice(PriceUpdate),
+    /// Advances the market clock so scheduled transitions happen even while
+    /// no orders arrive.
+    Tick {
+        symbol: String,
+        timestamp: u64,
+    },
+    /// Administrative transition, e.g. a manual halt or resumption.
+    SetSessionState {
+        symbol: String,
+        state: SessionState,
+        timestamp: u64,
+    },
+    SetSessionSchedule {
+        symbol: String,
+        schedule: SessionSchedule,
+        timestamp: u64,
+    },
+    SetPriceBands {
+        symbol: String,
+        bands: PriceBands,
+        timestamp: u64,
+    },
+    SetAllocation {
+        symbol: String,
+        policy: AllocationPolicy,
+        timestamp: u64,
+    },
+    /// Publishes fresh depth and order snapshots on the market data stream,
+    /// e.g. after a consumer detected a sequence gap.
+    RequestSnapshot {
+        symbol: String,
+        timestamp: u64,
+    },
+}
+
+impl OrderCommand {
+    pub fn symbol(&self) -> &str {
+        match self {
+            OrderCommand::Submit(order) => &order.symbol,
+            OrderCommand::PlaceStop(stop) => &stop.order.symbol,
+            OrderCommand::MarkPrice(update) => &update.symbol,
+            OrderCommand::Cancel { symbol, .. }
+            | OrderCommand::Replace { symbol, .. }
+            | OrderCommand::Amend { symbol, .. }
+            | OrderCommand::CancelStop { symbol, .. }
+            | OrderCommand::Tick { symbol, .. }
+            | OrderCommand::SetSessionState { symbol, .. }
+            | OrderCommand::SetSessionSchedule { symbol, .. }
+            | OrderCommand::SetPriceBands { symbol, .. }
+            | OrderCommand::SetAllocation { symbol, .. }
+            | OrderCommand::RequestSnapshot { symbol, .. } => symbol,
+        }
+    }
+
+    fn symbol_mut(&mut self) -> &mut String {
+        match self {
+            OrderCommand::Submit(order) => &mut order.symbol,
+            OrderCommand::PlaceStop(stop) => &mut stop.order.symbol,
+            OrderCommand::MarkPrice(update) => &mut update.symbol,
+            OrderCommand::Cancel { symbol, .. }
+            | OrderCommand::Replace { symbol, .. }
+            | OrderCommand::Amend { symbol, .. }
+            | OrderCommand::CancelStop { symbol, .. }
+            | OrderCommand::Tick { symbol, .. }
+            | OrderCommand::SetSessionState { symbol, .. }
+            | OrderCommand::SetSessionSchedule { symbol, .. }
+            | OrderCommand::SetPriceBands { symbol, .. }
+            | OrderCommand::SetAllocation { symbol, .. }
+            | OrderCommand::RequestSnapshot { symbol, .. } => symbol,
+        }
+    }
+
+    /// Makes `self` a copy of `source`, reusing `self`'s symbol buffer. Once
+    /// a ring slot has held a symbol at least as long, copying a command into
+    /// it does not allocate; only a session timetable is cloned.
+    pub fn overwrite(&mut self, source: &OrderCommand) {
+        let mut symbol = std::mem::take(self.symbol_mut());
+        symbol.clear();
+        symbol.push_str(source.symbol());
+        *self = match source {
+            OrderCommand::Submit(order) => OrderCommand::Submit(Order { symbol, ..*order }),
+            OrderCommand::PlaceStop(stop) => OrderCommand::PlaceStop(StopOrder {
+                order: Order {
+                    symbol,
+                    ..stop.order
+                },
+                ..*stop
+            }),
+            OrderCommand::MarkPrice(update) => {
+                OrderCommand::MarkPrice(PriceUpdate { symbol, ..*update })
+            }
+            &OrderCommand::Cancel {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            } => OrderCommand::Cancel {
+                symbol,
+                order_id,
+                user_id,
+                timestamp,
+            },
+            &OrderCommand::Replace {
+                order_id,
+                user_id,
+                price,
+                quantity,
+                timestamp,
+                ..
+            } => OrderCommand::Replace {
+                symbol,
+                order_id,
+                user_id,
+                price,
+                quantity,
+                timestamp,
+            },
+            &OrderCommand::Amend {
+                order_id,
+                user_id,
+                quantity,
+                timestamp,
+                ..
+            } => OrderCommand::Amend {
+                symbol,
+                order_id,
+                user_id,
+                quantity,
+                timestamp,
+            },
+            &OrderCommand::CancelStop {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            } => OrderCommand::CancelStop {
+                symbol,
+                order_id,
+                user_id,
+                timestamp,
+            },
+            &OrderCommand::Tick { timestamp, .. } => OrderCommand::Tick { symbol, timestamp },
+            &OrderCommand::SetSessionState {
+                state, timestamp, ..
+            } => OrderCommand::SetSessionState {
+                symbol,
+                state,
+                timestamp,
+            },
+            OrderCommand::SetSessionSchedule {
+                schedule,
+                timestamp,
+                ..
+            } => OrderCommand::SetSessionSchedule {
+                symbol,
+                schedule: schedule.clone(),
+                timestamp: *timestamp,
+            },
+            &OrderCommand::SetPriceBands {
+                bands, timestamp, ..
+            } => OrderCommand::SetPriceBands {
+                symbol,
+                bands,
+                timestamp,
+            },
+            &OrderCommand::SetAllocation {
+                policy, timestamp, ..
+            } => OrderCommand::SetAllocation {
+                symbol,
+                policy,
+                timestamp,
+            },
+            &OrderCommand::RequestSnapshot { timestamp, .. } => {
+                OrderCommand::RequestSnapshot { symbol, timestamp }
+            }
+        };
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            OrderCommand::Submit(order) => order.timestamp,
+            OrderCommand::PlaceStop(stop) => stop.order.timestamp,
+            OrderCommand::MarkPrice(update) => update.timestamp,
+            OrderCommand::Cancel { timestamp, .. }
+            | OrderCommand::Replace { timestamp, .. }
+            | OrderCommand::Amend { timestamp, .. }
+            | OrderCommand::CancelStop { timestamp, .. }
+            | OrderCommand::Tick { timestamp, .. }
+            | OrderCommand::SetSessionState { timestamp, .. }
+            | OrderCommand::SetSessionSchedule { timestamp, .. }
+            | OrderCommand::SetPriceBands { timestamp, .. }
+            | OrderCommand::SetAllocation { timestamp, .. }
+            | OrderCommand::RequestSnapshot { timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// User the command acts for; `None` for market and admin commands.
+    pub fn user_id(&self) -> Option<u64> {
+        match self {
+            OrderCommand::Submit(order) => Some(order.user_id),
+            OrderCommand::PlaceStop(stop) => Some(stop.order.user_id),
+            OrderCommand::Cancel { user_id, .. }
+            | OrderCommand::Replace { user_id, .. }
+            | OrderCommand::Amend { user_id, .. }
+            | OrderCommand::CancelStop { user_id, .. } => Some(*user_id),
+            OrderCommand::MarkPrice(_)
+            | OrderCommand::Tick { .. }
+            | OrderCommand::SetSessionState { .. }
+            | OrderCommand::SetSessionSchedule { .. }
+            | OrderCommand::SetPriceBands { .. }
+            | OrderCommand::SetAllocation { .. }
+            | OrderCommand::RequestSnapshot { .. } => None,
+        }
+    }
+
+    /// The `OrderRejected` event for this command, if it is one that can be
+    /// rejected.
+    pub fn rejection(&self, reason: RejectReason) -> Option<SystemEvent> {
+        let (order_id, user_id, timestamp) = match self {
+            OrderCommand::Submit(order) => (order.order_id, order.user_id, order.timestamp),
+            OrderCommand::PlaceStop(stop) => (
+                stop.order.order_id,
+                stop.order.user_id,
+                stop.order.timestamp,
+            ),
+            OrderCommand::MarkPrice(_)
+            | OrderCommand::Tick { .. }
+            | OrderCommand::SetSessionState { .. }
+            | OrderCommand::SetSessionSchedule { .. }
+            | OrderCommand::SetPriceBands { .. }
+            | OrderCommand::SetAllocation { .. }
+            | OrderCommand::RequestSnapshot { .. } => return None,
+            OrderCommand::Cancel {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            }
+            | OrderCommand::Replace {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            }
+            | OrderCommand::Amend {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            }
+            | OrderCommand::CancelStop {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            } => (*order_id, *user_id, *timestamp),
+        };
+        Some(SystemEvent::OrderRejected {
+            order_id,
+            user_id,
+            symbol: self.symbol().to_string(),
+            reason,
+            timestamp,
+        })
+    }
+}
+
+/// Order book and stop book of one instrument.
+#[derive(Debug)]
+struct Market {
+    book: OrderBook,
+    stops: StopBook,
+    publisher: MarketDataPublisher,
+    schedule: SessionSchedule,
+    /// State the schedule last prescribed, so each scheduled transition is
+    /// applied once and administrative changes hold until the next one.
+    scheduled: Option<SessionState>,
+    /// When the running volatility auction ends.
+    resume_at: Option<u64>,
+    /// Last published indicative `(price, volume)` of the running auction.
+    indicative: Option<(Option<u64>, u64)>,
+}
+
+/// The books owned by one matching thread.
+///
+/// A shard is single-threaded and processes commands strictly in arrival
+/// order, so the events it produces are a deterministic function of its
+/// input. Stops released by a trade or mark price are submitted before the
+/// next command is taken.
+#[derive(Debug, Default)]
+pub struct MatchingShard {
+    markets: HashMap<String, Market>,
+    /// Receives L2 and L3 market data after every command, when set.
+    market_data: Option<Sender<MarketData>>,
+}
+
+impl MatchingShard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_instrument(&mut self, instrument: Instrument, fees: Option<FeeEngine>) {
+        let mut book = OrderBook::new(instrument.clone());
+        if let Some(fees) = fees {
+            book = book.with_fee_engine(fees);
+        }
+        if self.market_data.is_some() {
+            book.record_changes();
+        }
+        let stops = StopBook::new(instrument.clone());
+        let market = Market {
+            publisher: MarketDataPublisher::new(instrument.symbol.clone()),
+            book,
+            stops,
+            schedule: SessionSchedule::default(),
+            scheduled: None,
+            resume_at: None,
+            indicative: None,
+        };
+        self.markets.insert(instrument.symbol, market);
+    }
+
+    /// Publishes depth and order-by-order market data for every book, current
+    /// and future, to `sink`.
+    pub fn publish_market_data(&mut self, sink: Sender<MarketData>) {
+        for market in self.markets.values_mut() {
+            market.book.record_changes();
+        }
+        self.market_data = Some(sink);
+    }
+
+    pub fn book(&self, symbol: &str) -> Option<&OrderBook> {
+        self.markets.get(symbol).map(|m| &m.book)
+    }
+
+    /// Continues each book's trade ids after the last trade `state` recorded
+    /// for its symbol, so ids are not reused after a restart, and restores
+    /// the volumes its fee tiers are based on.
+    pub fn resume(&mut self, state: &OracleState) {
+        for (symbol, market) in &mut self.markets {
+            if let Some(book) = state.books.get(symbol) {
+                market.book.resume_trade_ids(book.last_trade_id);
+                market.book.resume_fee_volumes(&book.volumes);
+            }
+        }
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = &str> {
+        self.markets.keys().map(String::as_str)
+    }
+
+    /// Applies one command and returns the resulting events in the order they
+    /// happened.
+    pub fn process(&mut self, command: &OrderCommand) -> Vec<SystemEvent> {
+        let mut events = Vec::new();
+        let Some(market) = self.markets.get_mut(command.symbol()) else {
+            events.extend(command.rejection(RejectReason::UnknownSymbol));
+            return events;
+        };
+        let timestamp = command.timestamp();
+        market.resume_after_interruption(timestamp, &mut events);
+        market.follow_schedule(timestamp, &mut events);
+        if let Err(reason) = market.apply(command, &mut events) {
+            events.extend(command.rejection(reason));
+        }
+        market.publish_indicative(timestamp, &mut events);
+
+        if let Some(sink) = &self.market_data {
+            let changes = market.book.take_changes();
+            let mut messages = market.publisher.publish(&market.book, changes, timestamp);
+            if let OrderCommand::RequestSnapshot { .. } = command {
+                let publisher = &market.publisher;
+                messages.push(MarketData::DepthSnapshot(
+                    publisher.depth_snapshot(&market.book, timestamp),
+                ));
+                messages.push(MarketData::OrderSnapshot(
+                    publisher.order_snapshot(&market.book, timestamp),
+                ));
+            }
+            for message in messages {
+                let _ = sink.send(message);
+            }
+        }
+        events
+    }
+}
+
+impl Market {
+    fn apply(
+        &mut self,
+        command: &OrderCommand,
+        events: &mut Vec<SystemEvent>,
+    ) -> Result<(), RejectReason> {
+        match command {
+            OrderCommand::Submit(order) => {
+                // The book only knows its resting orders; a parked stop
+                // holds its id too.
+                if self.stops.get(order.order_id).is_some() {
+                    return Err(RejectReason::DuplicateOrderId);
+                }
+                self.run(order.clone(), events);
+            }
+            OrderCommand::Cancel {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            } => {
+                self.check_owner(*order_id, *user_id)?;
+                let order = self.book.cancel(*order_id)?;
+                events.push(cancelled(&order, *timestamp));
+            }
+            OrderCommand::Replace {
+                order_id,
+                user_id,
+                price,
+                quantity,
+                timestamp,
+                ..
+            } => {
+                self.check_owner(*order_id, *user_id)?;
+                let mut original = self
+                    .book
+                    .order(*order_id)
+                    .cloned()
+                    .ok_or(RejectReason::UnknownOrder)?;
+                let outcome = self
+                    .book
+                    .replace(*order_id, *price, *quantity, *timestamp)?;
+                // Only a replacement that was accepted and is still working,
+                // or filled, is reported as replaced. Otherwise the original
+                // is gone and the order is reported cancelled.
+                let status = outcome.status;
+                let remaining = outcome.remaining;
+                match status {
+                    OrderStatus::Rejected(reason) => {
+                        events.extend(command.rejection(reason));
+                        events.push(cancelled(&original, *timestamp));
+                        return Ok(());
+                    }
+                    OrderStatus::Resting | OrderStatus::Filled => {
+                        events.push(SystemEvent::OrderReplaced {
+                            order_id: *order_id,
+                            user_id: *user_id,
+                            symbol: self.book.symbol().to_string(),
+                            price: *price,
+                            quantity: *quantity,
+                            timestamp: *timestamp,
+                        })
+                    }
+                    OrderStatus::Cancelled => {}
+                }
+                let mut released = VecDeque::new();
+                self.record(outcome, *timestamp, events, &mut released);
+                if status == OrderStatus::Cancelled {
+                    original.quantity = remaining;
+                    events.push(cancelled(&original, *timestamp));
+                }
+                self.run_released(released, events);
+            }
+            OrderCommand::Amend {
+                order_id,
+                user_id,
+                quantity,
+                timestamp,
+                ..
+            } => {
+                self.check_owner(*order_id, *user_id)?;
+                let order = self.book.amend(*order_id, *quantity)?;
+                events.push(SystemEvent::OrderAmended {
+                    order_id: order.order_id,
+                    user_id: order.user_id,
+                    symbol: order.symbol,
+                    quantity: order.quantity,
+                    timestamp: *timestamp,
+                });
+            }
+            OrderCommand::PlaceStop(stop) => {
+                // Rejects ids resting in the book as well as invalid stops.
+                self.book.check_stop(stop)?;
+                if self.stops.get(stop.order.order_id).is_some() {
+                    return Err(RejectReason::DuplicateOrderId);
+                }
+                self.stops.place(stop.clone());
+                events.push(SystemEvent::StopOrderPlaced(stop.clone()));
+            }
+            OrderCommand::CancelStop {
+                order_id,
+                user_id,
+                timestamp,
+                ..
+            } => {
+                if self
+                    .stops
+                    .get(*order_id)
+                    .is_none_or(|s| s.order.user_id != *user_id)
+                {
+                    return Err(RejectReason::UnknownOrder);
+                }
+                let stop = self.stops.cancel(*order_id)?;
+                events.push(cancelled(&stop.order, *timestamp));
+            }
+            OrderCommand::MarkPrice(update) => {
+                // Anchoring the bands at a made-up price would reject every
+                // order, so an unusable mark price is dropped instead.
+                let Some(mark_price) = self.book.instrument().price_from_decimal(update.mark_price)
+                else {
+                    counter!("titan.mark_prices_ignored").increment(1);
+                    return Ok(());
+                };
+                self.book.set_mark_price(mark_price);
+                // Stops stay armed outside continuous trading.
+                if self.book.state() == SessionState::Continuous {
+                    let activity = self.stops.on_mark_price(update);
+                    let released = self.triggered(activity, mark_price, update.timestamp, events);
+                    self.run_released(released, events);
+                }
+            }
+            OrderCommand::Tick { .. } => {}
+            OrderCommand::SetSessionState {
+                state, timestamp, ..
+            } => self.transition(*state, SessionTrigger::Admin, *timestamp, events),
+            OrderCommand::SetSessionSchedule {
+                schedule,
+                timestamp,
+                ..
+            } => {
+                self.schedule = schedule.clone();
+                self.scheduled = None;
+                events.push(SystemEvent::SessionScheduleChanged {
+                    symbol: self.book.symbol().to_string(),
+                    schedule: schedule.clone(),
+                    timestamp: *timestamp,
+                });
+                self.follow_schedule(*timestamp, events);
+            }
+            OrderCommand::SetPriceBands {
+                bands, timestamp, ..
+            } => {
+                self.book.set_price_bands(*bands);
+                events.push(SystemEvent::PriceBandsChanged {
+                    symbol: self.book.symbol().to_string(),
+                    bands: *bands,
+                    timestamp: *timestamp,
+                });
+            }
+            OrderCommand::SetAllocation {
+                policy, timestamp, ..
+            } => {
+                self.book.set_allocation(*policy);
+                events.push(SystemEvent::AllocationChanged {
+                    symbol: self.book.symbol().to_string(),
+                    policy: *policy,
+                    timestamp: *timestamp,
+                });
+            }
+            // Handled by the shard, which owns the market data sink.
+            OrderCommand::RequestSnapshot { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Ends a volatility auction once its duration has passed.
+    fn resume_after_interruption(&mut self, timestamp: u64, events: &mut Vec<SystemEvent>) {
+        if self.resume_at.is_some_and(|at| at <= timestamp) {
+            self.transition(
+                SessionState::Continuous,
+                SessionTrigger::VolatilityInterruption,
+                timestamp,
+                events,
+            );
+        }
+    }
+
+    /// Applies the scheduled state when the timetable moves on. A halt is
+    /// only ever lifted by an administrator.
+    fn follow_schedule(&mut self, timestamp: u64, events: &mut Vec<SystemEvent>) {
+        let Some(scheduled) = self.schedule.state_at(timestamp) else {
+            return;
+        };
+        if self.scheduled == Some(scheduled) {
+            return;
+        }
+        self.scheduled = Some(scheduled);
+        if self.book.state() != SessionState::Halted {
+            self.transition(scheduled, SessionTrigger::Schedule, timestamp, events);
+        }
+    }
+
+    fn transition(
+        &mut self,
+        state: SessionState,
+        trigger: SessionTrigger,
+        timestamp: u64,
+        events: &mut Vec<SystemEvent>,
+    ) {
+        let from = self.book.state();
+        if from == state {
+            return;
+        }
+        let executions = self.book.set_state(state, timestamp);
+        self.indicative = None;
+        self.resume_at = None;
+        counter!("titan.session_transitions", "state" => format!("{state:?}")).increment(1);
+        events.push(SystemEvent::SessionStateChanged {
+            symbol: self.book.symbol().to_string(),
+            from,
+            to: state,
+            trigger,
+            timestamp,
+        });
+        if let Some(first) = executions.first() {
+            events.push(SystemEvent::AuctionUncrossed {
+                symbol: self.book.symbol().to_string(),
+                price: first.price,
+                volume: executions.iter().map(|e| e.quantity).sum(),
+                timestamp,
+            });
+        }
+        let mut released = VecDeque::new();
+        self.record_executions(executions, events, &mut released);
+        self.run_released(released, events);
+    }
+
+    /// Publishes the indicative uncross whenever it changes during an auction.
+    fn publish_indicative(&mut self, timestamp: u64, events: &mut Vec<SystemEvent>) {
+        if !self.book.state().is_call_phase() {
+            return;
+        }
+        let current = self
+            .book
+            .indicative_uncross()
+            .map_or((None, 0), |u| (Some(u.price), u.volume));
+        if self.indicative == Some(current) {
+            return;
+        }
+        self.indicative = Some(current);
+        events.push(SystemEvent::AuctionIndicative {
+            symbol: self.book.symbol().to_string(),
+            price: current.0,
+            volume: current.1,
+            timestamp,
+        });
+    }
+
+    /// Cancels and amendments are only accepted from the order's owner. A
+    /// foreign order id is reported as unknown so ids cannot be probed.
+    fn check_owner(&self, order_id: u64, user_id: u64) -> Result<(), RejectReason> {
+        match self.book.order(order_id) {
+            Some(order) if order.user_id == user_id => Ok(()),
+            _ => Err(RejectReason::UnknownOrder),
+        }
+    }
+
+    fn run(&mut self, order: Order, events: &mut Vec<SystemEvent>) {
+        self.run_released(VecDeque::from([order]), events);
+    }
+
+    /// Submits orders one at a time until no further stops are released.
+    fn run_released(&mut self, mut pending: VecDeque<Order>, events: &mut Vec<SystemEvent>) {
+        while let Some(mut order) = pending.pop_front() {
+            let timestamp = order.timestamp;
+            let outcome = self.book.submit(order.clone());
+            let (status, remaining) = (outcome.status, outcome.remaining);
+            match status {
+                OrderStatus::Rejected(reason) => {
+                    events.extend(OrderCommand::Submit(order.clone()).rejection(reason));
+                }
+                _ => events.push(SystemEvent::OrderPlaced(order.clone())),
+            }
+            self.record(outcome, timestamp, events, &mut pending);
+            // Whatever the book dropped unfilled, an immediate-or-cancel or
+            // market remainder or one cut off by a volatility halt, ends
+            // with a cancel so the order has a terminal event.
+            if status == OrderStatus::Cancelled && remaining > 0 {
+                order.quantity = remaining;
+                events.push(cancelled(&order, timestamp));
+            }
+        }
+    }
+
+    /// Turns a submit outcome into events and queues any stops its trades
+    /// release.
+    fn record(
+        &mut self,
+        outcome: SubmitOutcome,
+        timestamp: u64,
+        events: &mut Vec<SystemEvent>,
+        pending: &mut VecDeque<Order>,
+    ) {
+        for order in &outcome.expired {
+            events.push(cancelled(order, order.timestamp));
+        }
+        events.extend(
+            outcome
+                .self_trades
+                .into_iter()
+                .map(SystemEvent::SelfTradePrevented),
+        );
+        self.record_executions(outcome.executions, events, pending);
+        if outcome.interrupted {
+            self.interrupted(timestamp, events);
+        }
+    }
+
+    /// Reports the book's move out of continuous trading after the volatility
+    /// guard stopped a trade.
+    fn interrupted(&mut self, timestamp: u64, events: &mut Vec<SystemEvent>) {
+        let to = self.book.state();
+        self.indicative = None;
+        self.resume_at = match self.book.price_bands().volatility.map(|g| g.action) {
+            Some(InterruptionAction::Auction { duration_ms }) => Some(timestamp + duration_ms),
+            _ => None,
+        };
+        counter!("titan.session_transitions", "state" => format!("{to:?}")).increment(1);
+        events.push(SystemEvent::SessionStateChanged {
+            symbol: self.book.symbol().to_string(),
+            from: SessionState::Continuous,
+            to,
+            trigger: SessionTrigger::VolatilityInterruption,
+            timestamp,
+        });
+    }
+
+    fn record_executions(
+        &mut self,
+        executions: Vec<Execution>,
+        events: &mut Vec<SystemEvent>,
+        pending: &mut VecDeque<Order>,
+    ) {
+        for execution in executions {
+            let activity = self.stops.on_execution(&execution);
+            let (price, timestamp) = (execution.price, execution.timestamp);
+            events.push(SystemEvent::OrderExecuted(execution));
+            pending.extend(self.triggered(activity, price, timestamp, events));
+        }
+    }
+
+    /// Reports stops that moved or fired at `trigger_price` and returns the
+    /// orders to submit.
+    fn triggered(
+        &self,
+        activity: StopActivity,
+        trigger_price: u64,
+        timestamp: u64,
+        events: &mut Vec<SystemEvent>,
+    ) -> VecDeque<Order> {
+        for (order_id, stop_price) in activity.trailed {
+            events.push(SystemEvent::StopOrderTrailed {
+                order_id,
+                symbol: self.book.symbol().to_string(),
+                stop_price,
+                timestamp,
+            });
+        }
+        let released = activity.released;
+        for order in &released {
+            events.push(SystemEvent::StopOrderTriggered {
+                order_id: order.order_id,
+                symbol: order.symbol.clone(),
+                trigger_price,
+                timestamp: order.timestamp,
+            });
+        }
+        released.into()
+    }
+}
+
+fn cancelled(order: &Order, timestamp: u64) -> SystemEvent {
+    SystemEvent::OrderCancelled {
+        order_id: order.order_id,
+        user_id: order.user_id,
+        symbol: order.symbol.clone(),
+        remaining_quantity: order.quantity,
+        timestamp,
+    }
+}
+
+/// Multi-symbol front-end that shards instruments across matching threads.
+///
+/// Symbols are assigned to shards round-robin in sorted order, so the same
+/// registry and shard count always produce the same assignment. Each shard
+/// runs on its own thread, blocking on its command channel, and publishes
+/// events to the shared event channel.
+#[derive(Debug)]
+pub struct TitanEngine {
+    routes: HashMap<String, usize>,
+    shards: Vec<Sender<OrderCommand>>,
+    threads: Vec<JoinHandle<()>>,
+    events: Sender<SystemEvent>,
+}
+
+impl TitanEngine {
+    pub fn start(
+        instruments: &InstrumentRegistry,
+        shard_count: usize,
+        fees: Option<FeeEngine>,
+        events: Sender<SystemEvent>,
+        market_data: Option<Sender<MarketData>>,
+    ) -> Self {
+        Self::resume(
+            instruments,
+            shard_count,
+            fees,
+            events,
+            market_data,
+            &OracleState::default(),
+        )
+    }
+
+    /// Starts the engine where the event log recovered into `state` left
+    /// off.
+    pub fn resume(
+        instruments: &InstrumentRegistry,
+        shard_count: usize,
+        fees: Option<FeeEngine>,
+        events: Sender<SystemEvent>,
+        market_data: Option<Sender<MarketData>>,
+        state: &OracleState,
+    ) -> Self {
+        assert!(shard_count > 0, "at least one matching shard is required");
+
+        let mut symbols: Vec<&Instrument> = instruments.iter().collect();
+        symbols.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+
+        let mut matching: Vec<MatchingShard> =
+            (0..shard_count).map(|_| MatchingShard::new()).collect();
+        if let Some(sink) = market_data {
+            for shard in &mut matching {
+                shard.publish_market_data(sink.clone());
+            }
+        }
+        let mut routes = HashMap::new();
+        for (i, instrument) in symbols.into_iter().enumerate() {
+            let shard = i % shard_count;
+            matching[shard].add_instrument(instrument.clone(), fees.clone());
+            routes.insert(instrument.symbol.clone(), shard);
+        }
+        for shard in &mut matching {
+            shard.resume(state);
+        }
+
+        let mut shards = Vec::with_capacity(shard_count);
+        let mut threads = Vec::with_capacity(shard_count);
+        for (index, mut shard) in matching.into_iter().enumerate() {
+            let (tx, rx) = channel::unbounded::<OrderCommand>();
+            let events = events.clone();
+            let thread = thread::Builder::new()
+                .name(format!("titan-shard-{index}"))
+                .spawn(move || {
+                    println!(
+                        "[Titan] Matching shard {index} started ({} symbols)",
+                        shard.markets.len()
+                    );
+                    for command in rx {
+                        for event in shard.process(&command) {
+                            if events.send(event).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                })
+                .expect("failed to spawn matching thread");
+            shards.push(tx);
+            threads.push(thread);
+        }
+
+        Self {
+            routes,
+            shards,
+            threads,
+            events,
+        }
+    }
+
+    /// Index of the shard that owns `symbol`.
+    pub fn shard_of(&self, symbol: &str) -> Option<usize> {
+        self.routes.get(symbol).copied()
+    }
+
+    /// Routes `command` to the shard that owns its symbol. Commands for
+    /// unlisted symbols are rejected immediately.
+    pub fn send(&self, command: OrderCommand) -> Result<(), RejectReason> {
+        let Some(shard) = self.shard_of(command.symbol()) else {
+            if let Some(rejection) = command.rejection(RejectReason::UnknownSymbol) {
+                let _ = self.events.send(rejection);
+            }
+            return Err(RejectReason::UnknownSymbol);
+        };
+        self.shards[shard].send(command).map_err(|_| {
+            eprintln!("[Titan] Matching shard {shard} is not running");
+            counter!("titan.shard_unavailable").increment(1);
+            RejectReason::Unavailable
+        })
+    }
+
+    /// Stops accepting commands and waits for every shard to drain its queue.
+    pub fn shutdown(self) {
+        drop(self.shards);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Durable log of commands, written before they reach risk or matching.
+pub trait CommandJournal: Send + 'static {
+    fn append(&mut self, sequence: u64, command: &OrderCommand);
+}
+
+/// Pre-trade check run after journaling and before matching.
+pub trait RiskCheck: Send + 'static {
+    fn check(&mut self, command: &OrderCommand) -> Result<(), RejectReason>;
+}
+
+/// Symbol capacity reserved in every ring slot up front.
+const SLOT_SYMBOL_CAPACITY: usize = 32;
+
+/// One slot of the Titan ring. Slots are allocated once, with room for a
+/// symbol, and each published command is copied over the previous one in
+/// place.
+#[derive(Debug)]
+pub struct CommandSlot {
+    pub command: OrderCommand,
+    pub rejection: Option<RejectReason>,
+}
+
+impl Default for CommandSlot {
+    fn default() -> Self {
+        Self {
+            command: OrderCommand::Tick {
+                symbol: String::with_capacity(SLOT_SYMBOL_CAPACITY),
+                timestamp: 0,
+            },
+            rejection: None,
+        }
+    }
+}
+
+struct JournalStage<J>(J);
+
+impl<J: CommandJournal> EventHandler<CommandSlot> for JournalStage<J> {
+    fn on_event(&mut self, slot: &mut CommandSlot, sequence: u64) {
+        self.0.append(sequence, &slot.command);
+    }
+}
+
+struct RiskStage<R>(R);
+
+impl<R: RiskCheck> EventHandler<CommandSlot> for RiskStage<R> {
+    fn on_event(&mut self, slot: &mut CommandSlot, _sequence: u64) {
+        slot.rejection = self.0.check(&slot.command).err();
+    }
+}
+
+struct MatchStage {
+    shard: MatchingShard,
+    events: Sender<SystemEvent>,
+}
+
+impl EventHandler<CommandSlot> for MatchStage {
+    fn on_event(&mut self, slot: &mut CommandSlot, _sequence: u64) {
+        let events = match slot.rejection.take() {
+            Some(reason) => slot.command.rejection(reason).into_iter().collect(),
+            None => self.shard.process(&slot.command),
+        };
+        for event in events {
+            let _ = self.events.send(event);
+        }
+    }
+}
+
+/// LMAX-style front-end for one matching shard: commands are written into a
+/// pre-allocated ring and pass, in sequence order, through journaling, the
+/// risk pre-check and matching, each stage on its own thread.
+pub struct TitanPipeline {
+    producer: Producer<CommandSlot>,
+    disruptor: Disruptor<CommandSlot>,
+}
+
+impl TitanPipeline {
+    /// `capacity` is the number of ring slots and must be a power of two.
+    pub fn start(
+        shard: MatchingShard,
+        capacity: usize,
+        journal: impl CommandJournal,
+        risk: impl RiskCheck,
+        events: Sender<SystemEvent>,
+    ) -> Self {
+        let (producer, disruptor) = DisruptorBuilder::new(capacity)
+            .stage("titan-journal", JournalStage(journal))
+            .stage("titan-risk", RiskStage(risk))
+            .stage("titan-match", MatchStage { shard, events })
+            .start();
+        Self {
+            producer,
+            disruptor,
+        }
+    }
+
+    /// Copies `command` into the next ring slot, waiting while the ring is
+    /// full, and returns its sequence number. Once a stage thread has
+    /// panicked, commands are refused as unavailable.
+    pub fn publish(&mut self, command: &OrderCommand) -> Result<u64, RejectReason> {
+        self.producer
+            .publish(|slot| {
+                slot.command.overwrite(command);
+                slot.rejection = None;
+            })
+            .map_err(|_| {
+                counter!("titan.pipeline_unavailable").increment(1);
+                RejectReason::Unavailable
+            })
+    }
+
+    /// Number of commands that have been through matching.
+    pub fn processed(&self) -> u64 {
+        self.disruptor.processed()
+    }
+
+    /// Drains every published command and stops the stage threads.
+    pub fn shutdown(self) {
+        self.disruptor.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::sentinel::Sentinel;
+    use crate::types::{Account, DAY_MS, ScheduledTransition};
+
+    fn instrument() -> Instrument {
+        Instrument {
+            symbol: "BTC-USDT".into(),
+            base_asset: "BTC".into(),
+            quote_asset: "USDT".into(),
+            price_scale: 0,
+            quantity_scale: 0,
+            tick_size: 1,
+            lot_size: 1,
+            min_notional: Decimal::ZERO,
+        }
+    }
+
+    /// A good-till-cancel limit order from user `order_id`, timestamped with
+    /// its id.
+    fn limit(order_id: u64, side: Side, price: u64, quantity: u64) -> Order {
+        Order {
+            order_id,
+            user_id: order_id,
+            symbol: "BTC-USDT".into(),
+            side,
+            price,
+            quantity,
+            timestamp: order_id,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancel,
+            display_quantity: None,
+            self_trade_prevention: SelfTradePrevention::None,
+        }
+    }
+
+    /// `(resting order id, quantity)` of each fill.
+    fn fills(executions: &[Execution]) -> Vec<(u64, u64)> {
+        executions
+            .iter()
+            .map(|e| match e.aggressor_side {
+                Side::Buy => (e.sell_order_id, e.quantity),
+                Side::Sell => (e.buy_order_id, e.quantity),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_best_price_then_oldest_order() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 101, 5));
+        book.submit(limit(2, Side::Sell, 100, 5));
+        book.submit(limit(3, Side::Sell, 100, 5));
+
+        let outcome = book.submit(limit(4, Side::Buy, 101, 12));
+        assert_eq!(outcome.status, OrderStatus::Filled);
+        assert_eq!(fills(&outcome.executions), [(2, 5), (3, 5), (1, 2)]);
+        let prices: Vec<_> = outcome.executions.iter().map(|e| e.price).collect();
+        assert_eq!(prices, [100, 100, 101]);
+        assert_eq!(book.depth(Side::Sell, 5), [(101, 3)]);
+    }
+
+    #[test]
+    fn unfilled_remainder_rests_at_its_limit() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+
+        let outcome = book.submit(limit(2, Side::Buy, 100, 8));
+        assert_eq!(outcome.status, OrderStatus::Resting);
+        assert_eq!(outcome.remaining, 3);
+        assert_eq!(book.best_bid(), Some(100));
+        assert_eq!(book.best_ask(), None);
+
+        book.submit(limit(3, Side::Sell, 103, 1));
+        assert_eq!(book.spread(), Some(3));
+        assert_eq!(book.len(), 2);
+    }
+
+    #[test]
+    fn resting_orders_do_not_trade_through_the_limit() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Buy, 99, 5));
+        let outcome = book.submit(limit(2, Side::Sell, 100, 5));
+        assert!(outcome.executions.is_empty());
+        assert_eq!(book.depth(Side::Buy, 5), [(99, 5)]);
+        assert_eq!(book.depth(Side::Sell, 5), [(100, 5)]);
+    }
+
+    fn with_time_in_force(mut order: Order, time_in_force: TimeInForce) -> Order {
+        order.time_in_force = time_in_force;
+        order
+    }
+
+    #[test]
+    fn market_and_ioc_remainders_are_cancelled() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        book.submit(limit(2, Side::Sell, 102, 5));
+
+        let ioc = with_time_in_force(limit(3, Side::Buy, 101, 8), TimeInForce::ImmediateOrCancel);
+        let outcome = book.submit(ioc);
+        assert_eq!(outcome.status, OrderStatus::Cancelled);
+        assert_eq!(fills(&outcome.executions), [(1, 5)]);
+        assert_eq!(outcome.remaining, 3);
+        assert!(!book.contains(3));
+
+        let mut market = limit(4, Side::Buy, 0, 8);
+        market.order_type = OrderType::Market;
+        let outcome = book.submit(market);
+        assert_eq!(outcome.status, OrderStatus::Cancelled);
+        assert_eq!(fills(&outcome.executions), [(2, 5)]);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn dropped_remainders_end_with_a_cancel_event() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::Submit(limit(1, Side::Sell, 100, 5)));
+        let ioc = with_time_in_force(limit(2, Side::Buy, 100, 8), TimeInForce::ImmediateOrCancel);
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(ioc))[..],
+            [
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderExecuted(Execution { quantity: 5, .. }),
+                SystemEvent::OrderCancelled {
+                    order_id: 2,
+                    remaining_quantity: 3,
+                    ..
+                }
+            ]
+        ));
+
+        let mut market = limit(3, Side::Sell, 0, 2);
+        market.order_type = OrderType::Market;
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(market))[..],
+            [
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderCancelled {
+                    order_id: 3,
+                    remaining_quantity: 2,
+                    ..
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    fn fill_or_kill_leaves_the_book_untouched_unless_it_fills() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        book.submit(limit(2, Side::Sell, 101, 5));
+
+        let fok = with_time_in_force(limit(3, Side::Buy, 101, 11), TimeInForce::FillOrKill);
+        assert_eq!(
+            book.submit(fok).status,
+            OrderStatus::Rejected(RejectReason::FillOrKillUnfillable)
+        );
+        assert_eq!(book.depth(Side::Sell, 5), [(100, 5), (101, 5)]);
+
+        let fok = with_time_in_force(limit(4, Side::Buy, 101, 10), TimeInForce::FillOrKill);
+        assert_eq!(book.submit(fok).status, OrderStatus::Filled);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn post_only_never_takes_liquidity() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+
+        let post = with_time_in_force(limit(2, Side::Buy, 100, 1), TimeInForce::PostOnly);
+        assert_eq!(
+            book.submit(post).status,
+            OrderStatus::Rejected(RejectReason::PostOnlyWouldCross)
+        );
+
+        let slide = with_time_in_force(limit(3, Side::Buy, 105, 1), TimeInForce::PostOnlySlide);
+        let outcome = book.submit(slide);
+        assert_eq!(outcome.status, OrderStatus::Resting);
+        assert!(outcome.executions.is_empty());
+        assert_eq!(book.best_bid(), Some(99));
+    }
+
+    #[test]
+    fn good_till_date_orders_expire() {
+        let mut book = OrderBook::new(instrument());
+        let gtd = with_time_in_force(limit(1, Side::Sell, 100, 5), TimeInForce::GoodTillDate(20));
+        assert_eq!(book.submit(gtd).status, OrderStatus::Resting);
+
+        let outcome = book.submit(limit(21, Side::Buy, 100, 5));
+        assert_eq!(outcome.expired.len(), 1);
+        assert_eq!(outcome.expired[0].order_id, 1);
+        assert!(outcome.executions.is_empty());
+
+        let late = with_time_in_force(limit(30, Side::Sell, 100, 5), TimeInForce::GoodTillDate(25));
+        assert_eq!(
+            book.submit(late).status,
+            OrderStatus::Rejected(RejectReason::Expired)
+        );
+    }
+
+    #[test]
+    fn resting_order_ids_cannot_be_reused() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        assert_eq!(
+            book.submit(limit(1, Side::Buy, 100, 5)).status,
+            OrderStatus::Rejected(RejectReason::DuplicateOrderId)
+        );
+        assert_eq!(book.depth(Side::Sell, 5), [(100, 5)]);
+    }
+
+    #[test]
+    fn cancel_removes_a_resting_order_once() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        assert_eq!(book.cancel(1).unwrap().quantity, 5);
+        assert_eq!(book.cancel(1).unwrap_err(), RejectReason::UnknownOrder);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn amend_reduces_in_place_and_keeps_priority() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        book.submit(limit(2, Side::Sell, 100, 5));
+        assert_eq!(book.amend(1, 6).unwrap_err(), RejectReason::InvalidQuantity);
+        assert_eq!(book.amend(1, 0).unwrap_err(), RejectReason::ZeroQuantity);
+        assert_eq!(book.amend(1, 2).unwrap().quantity, 2);
+        assert_eq!(book.depth(Side::Sell, 1), [(100, 7)]);
+
+        let outcome = book.submit(limit(3, Side::Buy, 100, 3));
+        assert_eq!(fills(&outcome.executions), [(1, 2), (2, 1)]);
+    }
+
+    #[test]
+    fn replace_moves_the_order_to_the_back_of_the_queue() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        book.submit(limit(2, Side::Sell, 100, 5));
+
+        let outcome = book.replace(1, 100, 6, 10).unwrap();
+        assert_eq!(outcome.status, OrderStatus::Resting);
+        assert_eq!(book.order(1).unwrap().quantity, 6);
+
+        let outcome = book.submit(limit(3, Side::Buy, 100, 6));
+        assert_eq!(fills(&outcome.executions), [(2, 5), (1, 1)]);
+    }
+
+    #[test]
+    fn replace_can_trade_at_its_new_price() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Buy, 99, 5));
+        book.submit(limit(2, Side::Sell, 100, 3));
+
+        let outcome = book.replace(1, 100, 5, 10).unwrap();
+        assert_eq!(outcome.status, OrderStatus::Resting);
+        assert_eq!(fills(&outcome.executions), [(2, 3)]);
+        assert_eq!(book.depth(Side::Buy, 5), [(100, 2)]);
+    }
+
+    #[test]
+    fn invalid_replacement_leaves_the_original_resting() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        book.submit(limit(2, Side::Sell, 100, 5));
+
+        assert_eq!(
+            book.replace(1, 100, 0, 10).unwrap_err(),
+            RejectReason::ZeroQuantity
+        );
+        assert_eq!(
+            book.replace(9, 100, 5, 10).unwrap_err(),
+            RejectReason::UnknownOrder
+        );
+        assert_eq!(book.order(1).unwrap().quantity, 5);
+        let outcome = book.submit(limit(3, Side::Buy, 100, 1));
+        assert_eq!(fills(&outcome.executions), [(1, 1)]);
+    }
+
+    fn shard() -> MatchingShard {
+        let mut shard = MatchingShard::new();
+        shard.add_instrument(instrument(), None);
+        shard
+    }
+
+    fn stop(order_id: u64, side: Side, stop_price: u64, trail: Option<u64>) -> StopOrder {
+        let mut order = limit(order_id, side, 0, 1);
+        order.order_type = OrderType::Market;
+        StopOrder {
+            order,
+            stop_price,
+            trigger: TriggerPrice::LastTrade,
+            trail,
+        }
+    }
+
+    #[test]
+    fn stops_trigger_when_the_price_reaches_them() {
+        let mut stops = StopBook::new(instrument());
+        stops.place(stop(1, Side::Sell, 90, None));
+        stops.place(stop(2, Side::Buy, 110, None));
+
+        assert!(
+            stops
+                .on_price(TriggerPrice::LastTrade, 100, 5)
+                .released
+                .is_empty()
+        );
+        assert!(
+            stops
+                .on_price(TriggerPrice::MarkPrice, 80, 6)
+                .released
+                .is_empty()
+        );
+        let released = stops.on_price(TriggerPrice::LastTrade, 90, 7).released;
+        assert_eq!(released.len(), 1);
+        assert_eq!((released[0].order_id, released[0].timestamp), (1, 7));
+        let released = stops.on_price(TriggerPrice::LastTrade, 111, 8).released;
+        assert_eq!(released[0].order_id, 2);
+        assert!(stops.is_empty());
+    }
+
+    #[test]
+    fn trailing_stops_only_move_in_the_orders_favour() {
+        let mut stops = StopBook::new(instrument());
+        stops.place(stop(1, Side::Sell, 90, Some(10)));
+
+        let activity = stops.on_price(TriggerPrice::LastTrade, 120, 5);
+        assert_eq!(activity.trailed, [(1, 110)]);
+        let activity = stops.on_price(TriggerPrice::LastTrade, 115, 6);
+        assert!(activity.trailed.is_empty() && activity.released.is_empty());
+        assert_eq!(stops.get(1).unwrap().stop_price, 110);
+
+        let activity = stops.on_price(TriggerPrice::LastTrade, 110, 7);
+        assert_eq!(activity.released.len(), 1);
+        assert!(activity.trailed.is_empty());
+    }
+
+    #[test]
+    fn stops_are_validated_when_placed() {
+        let book = OrderBook::new(instrument());
+        assert_eq!(
+            book.check_stop(&stop(1, Side::Sell, 0, None)),
+            Err(RejectReason::OffTick)
+        );
+        assert_eq!(
+            book.check_stop(&stop(1, Side::Sell, 90, Some(0))),
+            Err(RejectReason::OffTick)
+        );
+        let mut empty = stop(1, Side::Sell, 90, None);
+        empty.order.quantity = 0;
+        assert_eq!(book.check_stop(&empty), Err(RejectReason::ZeroQuantity));
+        assert_eq!(book.check_stop(&stop(1, Side::Sell, 90, None)), Ok(()));
+    }
+
+    #[test]
+    fn trades_release_stops_into_the_book() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::PlaceStop(stop(1, Side::Buy, 100, None)));
+        shard.process(&OrderCommand::Submit(limit(2, Side::Sell, 100, 1)));
+        shard.process(&OrderCommand::Submit(limit(3, Side::Sell, 101, 1)));
+
+        let events = shard.process(&OrderCommand::Submit(limit(4, Side::Buy, 100, 1)));
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderExecuted(_),
+                SystemEvent::StopOrderTriggered {
+                    order_id: 1,
+                    trigger_price: 100,
+                    ..
+                },
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderExecuted(Execution {
+                    buy_order_id: 1,
+                    price: 101,
+                    ..
+                }),
+            ]
+        ));
+        assert!(shard.book("BTC-USDT").unwrap().is_empty());
+    }
+
+    #[test]
+    fn stops_and_resting_orders_share_order_ids() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::PlaceStop(stop(1, Side::Buy, 110, None)));
+        shard.process(&OrderCommand::Submit(limit(2, Side::Sell, 100, 1)));
+
+        let events = shard.process(&OrderCommand::Submit(limit(1, Side::Sell, 105, 1)));
+        assert!(matches!(
+            events[..],
+            [SystemEvent::OrderRejected {
+                order_id: 1,
+                reason: RejectReason::DuplicateOrderId,
+                ..
+            }]
+        ));
+        let events = shard.process(&OrderCommand::PlaceStop(stop(2, Side::Buy, 110, None)));
+        assert!(matches!(
+            events[..],
+            [SystemEvent::OrderRejected {
+                order_id: 2,
+                reason: RejectReason::DuplicateOrderId,
+                ..
+            }]
+        ));
+        assert_eq!(
+            shard.book("BTC-USDT").unwrap().depth(Side::Sell, 5),
+            [(100, 1)]
+        );
+    }
+
+    fn iceberg(order_id: u64, side: Side, price: u64, quantity: u64, display: u64) -> Order {
+        let mut order = limit(order_id, side, price, quantity);
+        order.display_quantity = Some(display);
+        order
+    }
+
+    #[test]
+    fn icebergs_show_one_slice_at_a_time() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(iceberg(1, Side::Sell, 100, 10, 3));
+        book.submit(limit(2, Side::Sell, 100, 2));
+        assert_eq!(book.depth(Side::Sell, 1), [(100, 5)]);
+
+        let outcome = book.submit(limit(3, Side::Buy, 100, 4));
+        assert_eq!(fills(&outcome.executions), [(1, 3), (2, 1)]);
+        // The refreshed slice joins the back of the queue.
+        assert_eq!(book.depth(Side::Sell, 1), [(100, 4)]);
+        let outcome = book.submit(limit(4, Side::Buy, 100, 8));
+        assert_eq!(fills(&outcome.executions), [(2, 1), (1, 3), (1, 3), (1, 1)]);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn hidden_quantity_counts_towards_fill_or_kill() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(iceberg(1, Side::Sell, 100, 10, 3));
+        let fok = with_time_in_force(limit(2, Side::Buy, 100, 10), TimeInForce::FillOrKill);
+        assert_eq!(book.submit(fok).status, OrderStatus::Filled);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn zero_display_quantity_is_rejected() {
+        let mut book = OrderBook::new(instrument());
+        assert_eq!(
+            book.submit(iceberg(1, Side::Sell, 100, 10, 0)).status,
+            OrderStatus::Rejected(RejectReason::InvalidQuantity)
+        );
+    }
+
+    fn from_user(mut order: Order, user_id: u64, mode: SelfTradePrevention) -> Order {
+        order.user_id = user_id;
+        order.self_trade_prevention = mode;
+        order
+    }
+
+    /// A book with user 7's sell of 5 ahead of user 8's sell of 5, both at 100.
+    fn book_with_own_order() -> OrderBook {
+        let mut book = OrderBook::new(instrument());
+        book.submit(from_user(
+            limit(1, Side::Sell, 100, 5),
+            7,
+            SelfTradePrevention::None,
+        ));
+        book.submit(from_user(
+            limit(2, Side::Sell, 100, 5),
+            8,
+            SelfTradePrevention::None,
+        ));
+        book
+    }
+
+    #[test]
+    fn cancel_newest_stops_the_incoming_order() {
+        let mut book = book_with_own_order();
+        let taker = from_user(
+            limit(3, Side::Buy, 100, 3),
+            7,
+            SelfTradePrevention::CancelNewest,
+        );
+        let outcome = book.submit(taker);
+        assert_eq!(outcome.status, OrderStatus::Cancelled);
+        assert!(outcome.executions.is_empty());
+        assert_eq!(outcome.self_trades.len(), 1);
+        assert_eq!(outcome.self_trades[0].taker_quantity_cancelled, 3);
+        assert_eq!(book.depth(Side::Sell, 1), [(100, 10)]);
+    }
+
+    #[test]
+    fn cancel_oldest_removes_the_resting_order_and_keeps_matching() {
+        let mut book = book_with_own_order();
+        let taker = from_user(
+            limit(3, Side::Buy, 100, 3),
+            7,
+            SelfTradePrevention::CancelOldest,
+        );
+        let outcome = book.submit(taker);
+        assert_eq!(outcome.status, OrderStatus::Filled);
+        assert_eq!(fills(&outcome.executions), [(2, 3)]);
+        assert_eq!(outcome.self_trades[0].maker_quantity_cancelled, 5);
+        assert!(!book.contains(1));
+    }
+
+    #[test]
+    fn cancel_both_removes_both_orders() {
+        let mut book = book_with_own_order();
+        let taker = from_user(
+            limit(3, Side::Buy, 100, 3),
+            7,
+            SelfTradePrevention::CancelBoth,
+        );
+        let outcome = book.submit(taker);
+        assert_eq!(outcome.status, OrderStatus::Cancelled);
+        assert!(outcome.executions.is_empty());
+        assert!(!book.contains(1));
+        assert_eq!(book.depth(Side::Sell, 1), [(100, 5)]);
+    }
+
+    #[test]
+    fn decrement_and_cancel_reduces_both_sides() {
+        let mut book = book_with_own_order();
+        let taker = from_user(
+            limit(3, Side::Buy, 100, 3),
+            7,
+            SelfTradePrevention::DecrementAndCancel,
+        );
+        let outcome = book.submit(taker);
+        assert_eq!(outcome.status, OrderStatus::Cancelled);
+        let prevented = &outcome.self_trades[0];
+        assert_eq!(
+            (
+                prevented.taker_quantity_cancelled,
+                prevented.maker_quantity_cancelled
+            ),
+            (3, 3)
+        );
+        assert_eq!(book.order(1).unwrap().quantity, 2);
+    }
+
+    #[test]
+    fn self_trades_are_allowed_without_a_mode() {
+        let mut book = book_with_own_order();
+        let taker = from_user(limit(3, Side::Buy, 100, 3), 7, SelfTradePrevention::None);
+        let outcome = book.submit(taker);
+        assert_eq!(fills(&outcome.executions), [(1, 3)]);
+        assert!(outcome.self_trades.is_empty());
+    }
+
+    #[test]
+    fn orders_off_the_instrument_grid_are_rejected() {
+        let mut instrument = instrument();
+        instrument.tick_size = 5;
+        instrument.lot_size = 10;
+        instrument.min_notional = Decimal::from(2_000);
+        let mut book = OrderBook::new(instrument);
+        let status = |book: &mut OrderBook, price, quantity| {
+            book.submit(limit(1, Side::Buy, price, quantity)).status
+        };
+        assert_eq!(
+            status(&mut book, 101, 10),
+            OrderStatus::Rejected(RejectReason::OffTick)
+        );
+        assert_eq!(
+            status(&mut book, 100, 15),
+            OrderStatus::Rejected(RejectReason::OffLot)
+        );
+        assert_eq!(
+            status(&mut book, 100, 10),
+            OrderStatus::Rejected(RejectReason::BelowMinNotional)
+        );
+        assert_eq!(status(&mut book, 100, 20), OrderStatus::Resting);
+    }
+
+    #[test]
+    fn unrepresentable_notionals_are_rejected() {
+        let mut instrument = instrument();
+        instrument.price_scale = 2;
+        instrument.quantity_scale = 3;
+        let mut book = OrderBook::new(instrument);
+        let outcome = book.submit(limit(1, Side::Buy, u64::MAX, u64::MAX));
+        assert_eq!(
+            outcome.status,
+            OrderStatus::Rejected(RejectReason::InvalidQuantity)
+        );
+        assert_eq!(book.best_bid(), None);
+    }
+
+    #[test]
+    fn refused_commands_are_reported_as_rejections() {
+        let mut shard = shard();
+        let mut unlisted = limit(1, Side::Buy, 100, 1);
+        unlisted.symbol = "ETH-USDT".into();
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(unlisted))[..],
+            [SystemEvent::OrderRejected {
+                order_id: 1,
+                reason: RejectReason::UnknownSymbol,
+                ..
+            }]
+        ));
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(limit(2, Side::Buy, 100, 0)))[..],
+            [SystemEvent::OrderRejected {
+                order_id: 2,
+                user_id: 2,
+                reason: RejectReason::ZeroQuantity,
+                ..
+            }]
+        ));
+
+        shard.process(&OrderCommand::Submit(limit(3, Side::Buy, 100, 1)));
+        let cancel = OrderCommand::Cancel {
+            symbol: "BTC-USDT".into(),
+            order_id: 3,
+            user_id: 4,
+            timestamp: 5,
+        };
+        assert!(matches!(
+            shard.process(&cancel)[..],
+            [SystemEvent::OrderRejected {
+                order_id: 3,
+                user_id: 4,
+                reason: RejectReason::UnknownOrder,
+                ..
+            }]
+        ));
+        assert!(shard.book("BTC-USDT").unwrap().contains(3));
+    }
+
+    #[test]
+    fn commands_without_an_order_are_not_rejected() {
+        let mut shard = shard();
+        let tick = OrderCommand::Tick {
+            symbol: "ETH-USDT".into(),
+            timestamp: 1,
+        };
+        assert!(shard.process(&tick).is_empty());
+    }
+
+    #[test]
+    fn executions_identify_both_sides_of_the_trade() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Buy, 100, 5));
+        let outcome = book.submit(limit(2, Side::Sell, 99, 2));
+        let execution = &outcome.executions[0];
+        assert_eq!(execution.symbol, "BTC-USDT");
+        assert_eq!((execution.buy_order_id, execution.sell_order_id), (1, 2));
+        assert_eq!(execution.aggressor_side, Side::Sell);
+        assert_eq!(execution.price, 100);
+        assert_eq!(execution.timestamp, 2);
+        assert_eq!(
+            (execution.maker_user_id(), execution.taker_user_id()),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn trade_ids_are_sequential_and_resume_after_a_restart() {
+        let mut book = OrderBook::new(instrument());
+        book.submit(limit(1, Side::Sell, 100, 5));
+        let outcome = book.submit(limit(2, Side::Buy, 100, 2));
+        let outcome_again = book.submit(limit(3, Side::Buy, 100, 2));
+        assert_eq!(outcome.executions[0].trade_id, 1);
+        assert_eq!(outcome_again.executions[0].trade_id, 2);
+        book.resume_trade_ids(1);
+        assert_eq!(book.last_trade_id(), 2);
+
+        let mut state = OracleState::default();
+        let logged = crate::oracle::BookState {
+            last_trade_id: 41,
+            ..Default::default()
+        };
+        state.books.insert("BTC-USDT".into(), logged);
+        let mut shard = shard();
+        shard.resume(&state);
+        shard.process(&OrderCommand::Submit(limit(1, Side::Sell, 100, 1)));
+        let events = shard.process(&OrderCommand::Submit(limit(2, Side::Buy, 100, 1)));
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderExecuted(Execution { trade_id: 42, .. })
+            ]
+        ));
+    }
+
+    fn replace(order_id: u64, price: u64, quantity: u64, timestamp: u64) -> OrderCommand {
+        OrderCommand::Replace {
+            symbol: "BTC-USDT".into(),
+            order_id,
+            user_id: order_id,
+            price,
+            quantity,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn accepted_replaces_are_reported_as_replaced() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::Submit(limit(1, Side::Buy, 99, 5)));
+        assert!(matches!(
+            shard.process(&replace(1, 98, 4, 10))[..],
+            [SystemEvent::OrderReplaced {
+                order_id: 1,
+                price: 98,
+                quantity: 4,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn refused_replaces_keep_the_original() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::Submit(limit(1, Side::Sell, 100, 5)));
+        let post_only = with_time_in_force(limit(2, Side::Buy, 99, 5), TimeInForce::PostOnly);
+        shard.process(&OrderCommand::Submit(post_only));
+
+        assert!(matches!(
+            shard.process(&replace(2, 100, 5, 10))[..],
+            [SystemEvent::OrderRejected {
+                order_id: 2,
+                reason: RejectReason::PostOnlyWouldCross,
+                ..
+            }]
+        ));
+        assert_eq!(shard.book("BTC-USDT").unwrap().order(2).unwrap().price, 99);
+    }
+
+    #[test]
+    fn replacements_that_stop_working_are_reported_cancelled() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::Submit(from_user(
+            limit(1, Side::Sell, 100, 5),
+            7,
+            SelfTradePrevention::None,
+        )));
+        shard.process(&OrderCommand::Submit(from_user(
+            limit(2, Side::Buy, 99, 5),
+            7,
+            SelfTradePrevention::CancelNewest,
+        )));
+
+        let replace = OrderCommand::Replace {
+            symbol: "BTC-USDT".into(),
+            order_id: 2,
+            user_id: 7,
+            price: 100,
+            quantity: 5,
+            timestamp: 10,
+        };
+        // Self-trade prevention already took the quantity off.
+        assert!(matches!(
+            shard.process(&replace)[..],
+            [
+                SystemEvent::SelfTradePrevented(_),
+                SystemEvent::OrderCancelled {
+                    order_id: 2,
+                    remaining_quantity: 0,
+                    ..
+                },
+            ]
+        ));
+        assert!(!shard.book("BTC-USDT").unwrap().contains(2));
+    }
+
+    #[test]
+    fn engine_routes_symbols_to_fixed_shards() {
+        let mut registry = InstrumentRegistry::new();
+        for symbol in ["BTC-USDT", "ETH-USDT", "SOL-USDT"] {
+            registry.register(Instrument {
+                symbol: symbol.into(),
+                ..instrument()
+            });
+        }
+        let (events, received) = channel::unbounded();
+        let engine = TitanEngine::start(&registry, 2, None, events, None);
+        assert_eq!(engine.shard_of("BTC-USDT"), Some(0));
+        assert_eq!(engine.shard_of("ETH-USDT"), Some(1));
+        assert_eq!(engine.shard_of("SOL-USDT"), Some(0));
+
+        let mut unlisted = limit(9, Side::Buy, 100, 1);
+        unlisted.symbol = "XRP-USDT".into();
+        assert_eq!(
+            engine.send(OrderCommand::Submit(unlisted)),
+            Err(RejectReason::UnknownSymbol)
+        );
+        engine
+            .send(OrderCommand::Submit(limit(1, Side::Sell, 100, 5)))
+            .unwrap();
+        engine
+            .send(OrderCommand::Submit(limit(2, Side::Buy, 100, 2)))
+            .unwrap();
+        engine.shutdown();
+
+        let events: Vec<_> = received.try_iter().collect();
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::OrderRejected {
+                    reason: RejectReason::UnknownSymbol,
+                    ..
+                },
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderExecuted(_),
+            ]
+        ));
+    }
+
+    #[test]
+    fn stopped_shards_are_reported_unavailable() {
+        let mut registry = InstrumentRegistry::new();
+        registry.register(instrument());
+        let (events, received) = channel::unbounded();
+        let engine = TitanEngine::start(&registry, 1, None, events, None);
+        // The shard stops once nobody listens for its events.
+        drop(received);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let mut order_id = 0;
+        let outcome = loop {
+            order_id += 1;
+            let outcome = engine.send(OrderCommand::Submit(limit(order_id, Side::Buy, 100, 1)));
+            if outcome.is_err() || std::time::Instant::now() > deadline {
+                break outcome;
+            }
+            thread::yield_now();
+        };
+        assert_eq!(outcome, Err(RejectReason::Unavailable));
+        engine.shutdown();
+    }
+
+    #[test]
+    fn ring_slots_are_overwritten_in_place() {
+        let mut slot = CommandSlot::default();
+        let buffer = slot.command.symbol().as_ptr();
+        slot.command
+            .overwrite(&OrderCommand::Submit(limit(1, Side::Buy, 100, 5)));
+        assert!(matches!(&slot.command, OrderCommand::Submit(order) if order.order_id == 1));
+        assert_eq!(slot.command.symbol(), "BTC-USDT");
+        assert_eq!(slot.command.symbol().as_ptr(), buffer);
+
+        slot.command.overwrite(&replace(1, 101, 4, 2));
+        assert!(matches!(
+            slot.command,
+            OrderCommand::Replace {
+                price: 101,
+                quantity: 4,
+                ..
+            }
+        ));
+        assert_eq!(slot.command.symbol().as_ptr(), buffer);
+    }
+
+    /// Journals command sequences into a shared list.
+    struct SequenceJournal(Arc<Mutex<Vec<u64>>>);
+
+    impl CommandJournal for SequenceJournal {
+        fn append(&mut self, sequence: u64, _command: &OrderCommand) {
+            self.0.lock().unwrap().push(sequence);
+        }
+    }
+
+    /// Refuses orders larger than 10.
+    struct MaxQuantity;
+
+    impl RiskCheck for MaxQuantity {
+        fn check(&mut self, command: &OrderCommand) -> Result<(), RejectReason> {
+            match command {
+                OrderCommand::Submit(order) if order.quantity > 10 => {
+                    Err(RejectReason::InsufficientMargin)
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline_journals_checks_and_matches_in_order() {
+        let journaled = Arc::new(Mutex::new(Vec::new()));
+        let (events, received) = channel::unbounded();
+        let mut pipeline = TitanPipeline::start(
+            shard(),
+            4,
+            SequenceJournal(Arc::clone(&journaled)),
+            MaxQuantity,
+            events,
+        );
+        for order_id in 1..=20 {
+            let quantity = if order_id == 6 { 100 } else { 1 };
+            let order = limit(order_id, Side::Sell, 100 + order_id, quantity);
+            assert_eq!(
+                pipeline.publish(&OrderCommand::Submit(order)),
+                Ok(order_id - 1)
+            );
+        }
+        pipeline.shutdown();
+
+        assert_eq!(*journaled.lock().unwrap(), (0..20).collect::<Vec<_>>());
+        let events: Vec<_> = received.try_iter().collect();
+        assert_eq!(events.len(), 20);
+        for (index, event) in events.iter().enumerate() {
+            match event {
+                SystemEvent::OrderRejected {
+                    order_id: 6,
+                    reason: RejectReason::InsufficientMargin,
+                    ..
+                } => assert_eq!(index, 5),
+                SystemEvent::OrderPlaced(order) => assert_eq!(order.order_id, index as u64 + 1),
+                other => panic!("unexpected event {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn margin_checks_refuse_overflowing_notionals() {
+        let mut registry = InstrumentRegistry::new();
+        registry.register(instrument());
+        let sentinel = Arc::new(Sentinel::new(registry, Decimal::ZERO, 10));
+        for user_id in [1, 2] {
+            sentinel.add_account(Account {
+                user_id,
+                collateral: Decimal::from(1_000_000),
+                unrealized_pnl: Decimal::ZERO,
+                margin_ratio: Decimal::ZERO,
+                positions: Vec::new(),
+            });
+        }
+        let (events, received) = channel::unbounded();
+        let mut pipeline = TitanPipeline::start(
+            shard(),
+            4,
+            SequenceJournal(Arc::default()),
+            sentinel,
+            events,
+        );
+        let huge = limit(1, Side::Buy, u64::MAX, u64::MAX);
+        pipeline.publish(&OrderCommand::Submit(huge)).unwrap();
+        pipeline
+            .publish(&OrderCommand::Submit(limit(2, Side::Buy, 100, 5)))
+            .unwrap();
+        pipeline.shutdown();
+
+        let events: Vec<_> = received.try_iter().collect();
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::OrderRejected {
+                    order_id: 1,
+                    reason: RejectReason::InsufficientMargin,
+                    ..
+                },
+                SystemEvent::OrderPlaced(_)
+            ]
+        ));
+    }
+
+    /// Panics on the first command it checks.
+    struct BrokenRisk;
+
+    impl RiskCheck for BrokenRisk {
+        fn check(&mut self, _command: &OrderCommand) -> Result<(), RejectReason> {
+            panic!("risk check failed");
+        }
+    }
+
+    #[test]
+    fn a_dead_stage_makes_the_pipeline_unavailable() {
+        let (events, _received) = channel::unbounded();
+        let mut pipeline = TitanPipeline::start(
+            shard(),
+            2,
+            SequenceJournal(Arc::default()),
+            BrokenRisk,
+            events,
+        );
+        let results: Vec<_> = (1..=4)
+            .map(|order_id| {
+                pipeline.publish(&OrderCommand::Submit(limit(order_id, Side::Buy, 100, 1)))
+            })
+            .collect();
+        assert_eq!(results.last(), Some(&Err(RejectReason::Unavailable)));
+        pipeline.shutdown();
+    }
+
+    /// A book in pre-open holding bids of 5 at 102 and 101 and asks of 4 at
+    /// 99 and 100.
+    fn crossed_pre_open_book() -> OrderBook {
+        let mut book = OrderBook::new(instrument());
+        book.set_state(SessionState::PreOpen, 0);
+        book.submit(limit(1, Side::Buy, 102, 5));
+        book.submit(limit(2, Side::Buy, 101, 5));
+        book.submit(limit(3, Side::Sell, 99, 4));
+        book.submit(limit(4, Side::Sell, 100, 4));
+        book
+    }
+
+    #[test]
+    fn call_phases_collect_orders_without_matching() {
+        let book = crossed_pre_open_book();
+        assert_eq!(book.len(), 4);
+        assert_eq!(book.best_bid(), Some(102));
+        assert_eq!(book.best_ask(), Some(99));
+    }
+
+    #[test]
+    fn indicative_price_maximises_volume_then_minimises_imbalance() {
+        let book = crossed_pre_open_book();
+        // 8 can trade at 100 or 101 with an imbalance of 2; the lower price
+        // wins the tie.
+        assert_eq!(
+            book.indicative_uncross(),
+            Some(Uncross {
+                price: 100,
+                volume: 8
+            })
+        );
+    }
+
+    #[test]
+    fn leaving_the_call_phase_uncrosses_at_one_price() {
+        let mut book = crossed_pre_open_book();
+        let executions = book.set_state(SessionState::Continuous, 50);
+        assert!(
+            executions
+                .iter()
+                .all(|e| e.price == 100 && e.timestamp == 50)
+        );
+        assert_eq!(executions.iter().map(|e| e.quantity).sum::<u64>(), 8);
+        assert_eq!(book.depth(Side::Buy, 5), [(101, 2)]);
+        assert!(book.best_ask().is_none());
+    }
+
+    #[test]
+    fn orders_that_cannot_wait_are_refused_in_an_auction() {
+        let mut book = crossed_pre_open_book();
+        let mut market = limit(5, Side::Buy, 0, 1);
+        market.order_type = OrderType::Market;
+        let ioc = with_time_in_force(limit(6, Side::Buy, 100, 1), TimeInForce::ImmediateOrCancel);
+        let post = with_time_in_force(limit(7, Side::Buy, 90, 1), TimeInForce::PostOnly);
+        for order in [market, ioc, post] {
+            assert_eq!(
+                book.submit(order).status,
+                OrderStatus::Rejected(RejectReason::NotAllowedInAuction)
+            );
+        }
+    }
+
+    #[test]
+    fn scheduled_auctions_publish_indicative_prices_and_uncross() {
+        let mut shard = shard();
+        let schedule = SessionSchedule {
+            transitions: vec![
+                ScheduledTransition {
+                    at: 0,
+                    state: SessionState::PreOpen,
+                },
+                ScheduledTransition {
+                    at: 100,
+                    state: SessionState::Continuous,
+                },
+            ],
+        };
+        let events = shard.process(&OrderCommand::SetSessionSchedule {
+            symbol: "BTC-USDT".into(),
+            schedule,
+            timestamp: 1,
+        });
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::SessionScheduleChanged { .. },
+                SystemEvent::SessionStateChanged {
+                    to: SessionState::PreOpen,
+                    trigger: SessionTrigger::Schedule,
+                    ..
+                },
+                SystemEvent::AuctionIndicative {
+                    price: None,
+                    volume: 0,
+                    ..
+                },
+            ]
+        ));
+
+        shard.process(&OrderCommand::Submit(limit(10, Side::Buy, 101, 5)));
+        let events = shard.process(&OrderCommand::Submit(limit(11, Side::Sell, 100, 3)));
+        assert!(matches!(
+            events.last(),
+            Some(SystemEvent::AuctionIndicative {
+                price: Some(_),
+                volume: 3,
+                ..
+            })
+        ));
+
+        let events = shard.process(&OrderCommand::Tick {
+            symbol: "BTC-USDT".into(),
+            timestamp: 150,
+        });
+        assert!(matches!(
+            events[..2],
+            [
+                SystemEvent::SessionStateChanged {
+                    to: SessionState::Continuous,
+                    ..
+                },
+                SystemEvent::AuctionUncrossed { volume: 3, .. },
+            ]
+        ));
+        assert_eq!(
+            shard.book("BTC-USDT").unwrap().depth(Side::Buy, 5),
+            [(101, 2)]
+        );
+    }
+
+    fn set_state(state: SessionState, timestamp: u64) -> OrderCommand {
+        OrderCommand::SetSessionState {
+            symbol: "BTC-USDT".into(),
+            state,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn halted_and_closed_books_only_accept_reductions() {
+        let mut shard = shard();
+        shard.process(&OrderCommand::Submit(limit(1, Side::Buy, 100, 5)));
+        assert!(matches!(
+            shard.process(&set_state(SessionState::Halted, 5))[..],
+            [SystemEvent::SessionStateChanged {
+                from: SessionState::Continuous,
+                to: SessionState::Halted,
+                trigger: SessionTrigger::Admin,
+                ..
+            }]
+        ));
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(limit(2, Side::Sell, 100, 5)))[..],
+            [SystemEvent::OrderRejected {
+                reason: RejectReason::TradingHalted,
+                ..
+            }]
+        ));
+        let amend = OrderCommand::Amend {
+            symbol: "BTC-USDT".into(),
+            order_id: 1,
+            user_id: 1,
+            quantity: 3,
+            timestamp: 6,
+        };
+        assert!(matches!(
+            shard.process(&amend)[..],
+            [SystemEvent::OrderAmended { quantity: 3, .. }]
+        ));
+
+        shard.process(&set_state(SessionState::Closed, 7));
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(limit(3, Side::Sell, 100, 5)))[..],
+            [SystemEvent::OrderRejected {
+                reason: RejectReason::MarketClosed,
+                ..
+            }]
+        ));
+        let cancel = OrderCommand::Cancel {
+            symbol: "BTC-USDT".into(),
+            order_id: 1,
+            user_id: 1,
+            timestamp: 8,
+        };
+        assert!(matches!(
+            shard.process(&cancel)[..],
+            [SystemEvent::OrderCancelled { order_id: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn the_schedule_does_not_lift_a_halt() {
+        let mut shard = shard();
+        shard.process(&set_state(SessionState::Halted, 5));
+        let events = shard.process(&OrderCommand::SetSessionSchedule {
+            symbol: "BTC-USDT".into(),
+            schedule: SessionSchedule {
+                transitions: vec![ScheduledTransition {
+                    at: 0,
+                    state: SessionState::Continuous,
+                }],
+            },
+            timestamp: 6,
+        });
+        assert!(matches!(
+            events[..],
+            [SystemEvent::SessionScheduleChanged { .. }]
+        ));
+        assert_eq!(
+            shard.book("BTC-USDT").unwrap().state(),
+            SessionState::Halted
+        );
+
+        shard.process(&set_state(SessionState::Continuous, 7));
+        assert_eq!(
+            shard.book("BTC-USDT").unwrap().state(),
+            SessionState::Continuous
+        );
+    }
+
+    #[test]
+    fn schedules_wrap_around_midnight() {
+        let schedule = SessionSchedule {
+            transitions: vec![
+                ScheduledTransition {
+                    at: 1_000,
+                    state: SessionState::Continuous,
+                },
+                ScheduledTransition {
+                    at: 2_000,
+                    state: SessionState::Closed,
+                },
+            ],
+        };
+        assert_eq!(schedule.state_at(500), Some(SessionState::Closed));
+        assert_eq!(schedule.state_at(1_500), Some(SessionState::Continuous));
+        assert_eq!(
+            schedule.state_at(DAY_MS + 2_500),
+            Some(SessionState::Closed)
+        );
+        assert_eq!(SessionSchedule::default().state_at(0), None);
+    }
+
+    #[test]
+    fn a_repeated_state_is_not_reported() {
+        let mut shard = shard();
+        assert!(
+            shard
+                .process(&set_state(SessionState::Continuous, 1))
+                .is_empty()
+        );
+    }
+
+    fn set_bands(bands: PriceBands) -> OrderCommand {
+        OrderCommand::SetPriceBands {
+            symbol: "BTC-USDT".into(),
+            bands,
+            timestamp: 0,
+        }
+    }
+
+    /// A 10% static band and a guard against 5% moves within a second.
+    fn guarded(action: InterruptionAction) -> PriceBands {
+        PriceBands {
+            static_band_bps: Some(1_000),
+            reference: TriggerPrice::LastTrade,
+            volatility: Some(VolatilityGuard {
+                max_move_bps: 500,
+                window_ms: 1_000,
+                action,
+            }),
+        }
+    }
+
+    /// A shard with `bands` set that last traded at 100.
+    fn traded_at_100(bands: PriceBands) -> MatchingShard {
+        let mut shard = shard();
+        shard.process(&set_bands(bands));
+        shard.process(&OrderCommand::Submit(limit(1, Side::Sell, 100, 1)));
+        shard.process(&OrderCommand::Submit(limit(2, Side::Buy, 100, 1)));
+        shard
+    }
+
+    #[test]
+    fn limit_prices_outside_the_static_band_are_rejected() {
+        let mut shard = traded_at_100(guarded(InterruptionAction::Halt));
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(limit(3, Side::Sell, 111, 1)))[..],
+            [SystemEvent::OrderRejected {
+                reason: RejectReason::PriceOutOfBand,
+                ..
+            }]
+        ));
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(limit(4, Side::Sell, 110, 1)))[..],
+            [SystemEvent::OrderPlaced(_)]
+        ));
+    }
+
+    #[test]
+    fn volatility_auctions_resume_after_their_duration() {
+        let mut shard = traded_at_100(guarded(InterruptionAction::Auction { duration_ms: 100 }));
+        shard.process(&OrderCommand::Submit(limit(4, Side::Sell, 102, 1)));
+        shard.process(&OrderCommand::Submit(limit(5, Side::Sell, 108, 1)));
+
+        // The trade at 102 is within 5%; the one at 108 is not.
+        let events = shard.process(&OrderCommand::Submit(limit(6, Side::Buy, 110, 3)));
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::OrderExecuted(Execution { price: 102, .. }),
+                SystemEvent::SessionStateChanged {
+                    to: SessionState::Auction,
+                    trigger: SessionTrigger::VolatilityInterruption,
+                    ..
+                },
+                SystemEvent::AuctionIndicative { .. },
+            ]
+        ));
+        assert_eq!(
+            shard.book("BTC-USDT").unwrap().order(6).unwrap().quantity,
+            2
+        );
+
+        let events = shard.process(&OrderCommand::Tick {
+            symbol: "BTC-USDT".into(),
+            timestamp: 106,
+        });
+        assert!(matches!(
+            events[..2],
+            [
+                SystemEvent::SessionStateChanged {
+                    to: SessionState::Continuous,
+                    trigger: SessionTrigger::VolatilityInterruption,
+                    ..
+                },
+                SystemEvent::AuctionUncrossed {
+                    price: 108,
+                    volume: 1,
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn a_volatility_halt_cancels_the_remainder() {
+        let mut shard = traded_at_100(guarded(InterruptionAction::Halt));
+        shard.process(&OrderCommand::Submit(limit(4, Side::Sell, 108, 1)));
+
+        let events = shard.process(&OrderCommand::Submit(limit(5, Side::Buy, 108, 3)));
+        assert!(matches!(
+            events[..],
+            [
+                SystemEvent::OrderPlaced(_),
+                SystemEvent::SessionStateChanged {
+                    to: SessionState::Halted,
+                    ..
+                },
+                SystemEvent::OrderCancelled {
+                    order_id: 5,
+                    remaining_quantity: 3,
+                    ..
+                },
+            ]
+        ));
+        let book = shard.book("BTC-USDT").unwrap();
+        assert_eq!(book.state(), SessionState::Halted);
+        assert!(!book.contains(5));
+    }
+
+    #[test]
+    fn bands_can_follow_the_mark_price() {
+        let mut shard = shard();
+        shard.process(&set_bands(PriceBands {
+            static_band_bps: Some(1_000),
+            reference: TriggerPrice::MarkPrice,
+            volatility: None,
+        }));
+        let mark = |price: &str| {
+            OrderCommand::MarkPrice(PriceUpdate {
+                symbol: "BTC-USDT".into(),
+                mark_price: price.parse().unwrap(),
+                timestamp: 1,
+            })
+        };
+        shard.process(&mark("200"));
+        // A mark price that does not fit the instrument is ignored.
+        assert!(shard.process(&mark("-5")).is_empty());
+        assert_eq!(shard.book("BTC-USDT").unwrap().reference_price(), Some(200));
+        assert!(matches!(
+            shard.process(&OrderCommand::Submit(limit(1, Side::Buy, 100, 1)))[..],
+            [SystemEvent::OrderRejected {
+                reason: RejectReason::PriceOutOfBand,
+                ..
+            }]
+        ));
+    }
+
+    /// A book using `policy` with sells of 10, 30 and 60 at 100, in that
+    /// order.
+    fn allocated_book(policy: AllocationPolicy) -> OrderBook {
+        let mut book = OrderBook::new(instrument());
+        book.set_allocation(policy);
+        book.submit(limit(1, Side::Sell, 100, 10));
+        book.submit(limit(2, Side::Sell, 100, 30));
+        book.submit(limit(3, Side::Sell, 100, 60));
+        book
+    }
+
+    #[test]
+    fn pro_rata_shares_by_resting_quantity() {
+        let mut book = allocated_book(AllocationPolicy::ProRata { min_allocation: 0 });
+        let outcome = book.submit(limit(4, Side::Buy, 100, 20));
+        assert_eq!(fills(&outcome.executions), [(1, 2), (2, 6), (3, 12)]);
+    }
+
+    #[test]
+    fn pro_rata_leftovers_go_in_time_priority() {
+        let mut book = allocated_book(AllocationPolicy::ProRata { min_allocation: 2 });
+        // Shares are 1, 3 and 6; order 1's is below the minimum, so its unit
+        // is handed out again from the front of the queue.
+        let outcome = book.submit(limit(4, Side::Buy, 100, 10));
+        assert_eq!(fills(&outcome.executions), [(1, 1), (2, 3), (3, 6)]);
+    }
+
+    #[test]
+    fn hybrid_fills_the_order_that_set_the_level_first() {
+        let mut book = allocated_book(AllocationPolicy::Hybrid { min_allocation: 0 });
+        let outcome = book.submit(limit(4, Side::Buy, 100, 19));
+        assert_eq!(fills(&outcome.executions), [(1, 10), (2, 3), (3, 6)]);
+
+        // The priority is used up; the rest is shared pro-rata.
+        let outcome = book.submit(limit(5, Side::Buy, 100, 81));
+        assert_eq!(fills(&outcome.executions), [(2, 27), (3, 54)]);
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn allocation_changes_are_reported() {
+        let mut shard = shard();
+        let policy = AllocationPolicy::ProRata { min_allocation: 1 };
+        let events = shard.process(&OrderCommand::SetAllocation {
+            symbol: "BTC-USDT".into(),
+            policy,
+            timestamp: 1,
+        });
+        assert!(matches!(
+            events[..],
+            [SystemEvent::AllocationChanged { policy: p, .. }] if p == policy
+        ));
+        assert_eq!(shard.book("BTC-USDT").unwrap().allocation(), policy);
+    }
+}
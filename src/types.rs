@@ -1,18 +1,55 @@
+use std::fmt;
+
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
+/// Milliseconds per day. Timestamps are milliseconds since the Unix epoch.
+pub const DAY_MS: u64 = 86_400_000;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Side {
     Buy,
     Sell,
 }
 
+impl Side {
+    pub fn opposite(self) -> Side {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PositionSide {
     Long,
     Short,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OrderType {
+    /// Takes whatever liquidity is available; `price` is ignored and any
+    /// unfilled remainder is cancelled.
+    Market,
+    #[default]
+    Limit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancel,
+    /// Rests until an order timestamp at or after this value is processed.
+    GoodTillDate(u64),
+    ImmediateOrCancel,
+    FillOrKill,
+    /// Rejected if any part would match on arrival.
+    PostOnly,
+    /// Repriced one tick behind the opposite best instead of crossing.
+    PostOnlySlide,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order {
     pub order_id: u64,
@@ -22,15 +59,286 @@ pub struct Order {
     pub price: u64,
     pub quantity: u64,
     pub timestamp: u64,
+    #[serde(default)]
+    pub order_type: OrderType,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    /// Iceberg slice size. When set only this much of the resting quantity is
+    /// shown on the book; the hidden remainder replenishes it as it fills.
+    #[serde(default)]
+    pub display_quantity: Option<u64>,
+    #[serde(default)]
+    pub self_trade_prevention: SelfTradePrevention,
+}
+
+/// What happens when an incoming order would match a resting order from the
+/// same `user_id`. The incoming (taker) order's mode applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    /// Self-matching is allowed.
+    #[default]
+    None,
+    /// Cancel the remainder of the incoming order.
+    CancelNewest,
+    /// Cancel the resting order and keep matching.
+    CancelOldest,
+    /// Cancel both orders.
+    CancelBoth,
+    /// Reduce both orders by the smaller remaining quantity, cancelling
+    /// whichever reaches zero.
+    DecrementAndCancel,
+}
+
+/// Price source a stop order is triggered against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TriggerPrice {
+    /// Price of the latest `Execution` in the symbol.
+    #[default]
+    LastTrade,
+    /// Mark price published by the price feed in `PriceUpdate`.
+    MarkPrice,
 }
 
+/// Conditional order held outside the visible book until its trigger is hit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopOrder {
+    /// Order released into the book once triggered. A market order makes this
+    /// a plain stop, a limit order a stop-limit.
+    pub order: Order,
+    /// Sell stops trigger at or below this price, buy stops at or above it.
+    pub stop_price: u64,
+    pub trigger: TriggerPrice,
+    /// When set, `stop_price` trails the trigger price by this distance and
+    /// only ever moves in the order's favour.
+    pub trail: Option<u64>,
+}
+
+/// Trading session state of one instrument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SessionState {
+    /// Trading suspended; only cancels and quantity reductions are accepted.
+    Halted,
+    /// Orders are collected for the opening auction without matching.
+    PreOpen,
+    #[default]
+    Continuous,
+    /// Orders are collected for an intraday or closing auction.
+    Auction,
+    /// Outside trading hours; only cancels and quantity reductions are
+    /// accepted.
+    Closed,
+}
+
+impl SessionState {
+    /// Whether orders rest without matching until the book is uncrossed.
+    pub fn is_call_phase(self) -> bool {
+        matches!(self, SessionState::PreOpen | SessionState::Auction)
+    }
+}
+
+/// What moved an instrument to a new session state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionTrigger {
+    Admin,
+    Schedule,
+    /// A trade would have moved the price further than the volatility guard
+    /// allows, or the resulting volatility auction ran its course.
+    VolatilityInterruption,
+}
+
+/// Daily session timetable, e.g. pre-open, continuous trading, a closing
+/// auction and the close. Each state holds until the next transition.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSchedule {
+    pub transitions: Vec<ScheduledTransition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledTransition {
+    /// Milliseconds after UTC midnight.
+    pub at: u64,
+    pub state: SessionState,
+}
+
+impl SessionSchedule {
+    /// State the timetable prescribes at `timestamp`, or `None` if it is
+    /// empty. Before the day's first transition the previous day's last one
+    /// still applies.
+    pub fn state_at(&self, timestamp: u64) -> Option<SessionState> {
+        let time_of_day = timestamp % DAY_MS;
+        self.transitions
+            .iter()
+            .filter(|t| t.at <= time_of_day)
+            .max_by_key(|t| t.at)
+            .or_else(|| self.transitions.iter().max_by_key(|t| t.at))
+            .map(|t| t.state)
+    }
+}
+
+/// Price protection for one instrument. Percentages are in basis points.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceBands {
+    /// Limit orders priced further than this from the reference price are
+    /// rejected.
+    pub static_band_bps: Option<u64>,
+    pub reference: TriggerPrice,
+    pub volatility: Option<VolatilityGuard>,
+}
+
+/// Interrupts continuous trading before a trade that would move the price
+/// more than `max_move_bps` from any trade in the last `window_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolatilityGuard {
+    pub max_move_bps: u64,
+    pub window_ms: u64,
+    pub action: InterruptionAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InterruptionAction {
+    /// Halt until an administrator resumes trading.
+    Halt,
+    /// Collect orders for `duration_ms`, then uncross and resume.
+    Auction { duration_ms: u64 },
+}
+
+/// How an aggressive order's quantity is shared among the resting orders at
+/// one price level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AllocationPolicy {
+    /// Strict time priority.
+    #[default]
+    Fifo,
+    /// In proportion to displayed quantity. Shares smaller than
+    /// `min_allocation` are not made; what is left goes out in time priority.
+    ProRata { min_allocation: u64 },
+    /// Pro-rata, after first filling the order that set a new best price.
+    Hybrid { min_allocation: u64 },
+}
+
+/// Why an order, cancel or amendment was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    ZeroQuantity,
+    /// Price is zero or not a multiple of the instrument tick size.
+    OffTick,
+    /// Quantity is not a multiple of the instrument lot size.
+    OffLot,
+    /// Price * quantity is below the instrument minimum notional.
+    BelowMinNotional,
+    UnknownSymbol,
+    /// No resting order with the given id.
+    UnknownOrder,
+    /// Iceberg display quantity is zero, or an amendment does not reduce the
+    /// resting quantity.
+    InvalidQuantity,
+    /// Order type and time in force cannot be combined.
+    InvalidTimeInForce,
+    /// Post-only order would have taken liquidity.
+    PostOnlyWouldCross,
+    /// Limit price is outside the instrument's static price band.
+    PriceOutOfBand,
+    /// Fill-or-kill order could not be filled in full.
+    FillOrKillUnfillable,
+    /// Good-till-date order arrived after its expiry.
+    Expired,
+    InsufficientMargin,
+    /// The instrument is outside its trading session.
+    MarketClosed,
+    /// Trading in the instrument is suspended.
+    TradingHalted,
+    /// Order cannot wait for an auction uncross, e.g. market, IOC, FOK or
+    /// post-only orders.
+    NotAllowedInAuction,
+    RateLimited,
+    /// The message names a user other than the one the session authenticated
+    /// as.
+    Unauthorized,
+    /// An order with the same id is already resting on the book.
+    DuplicateOrderId,
+    /// The matching thread for the symbol is not running.
+    Unavailable,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            RejectReason::ZeroQuantity => "quantity must be non-zero",
+            RejectReason::OffTick => "price is not a multiple of the tick size",
+            RejectReason::OffLot => "quantity is not a multiple of the lot size",
+            RejectReason::BelowMinNotional => "order value is below the minimum notional",
+            RejectReason::UnknownSymbol => "unknown symbol",
+            RejectReason::UnknownOrder => "unknown order",
+            RejectReason::InvalidQuantity => "invalid quantity",
+            RejectReason::InvalidTimeInForce => "time in force not allowed for order type",
+            RejectReason::PostOnlyWouldCross => "post-only order would cross",
+            RejectReason::PriceOutOfBand => "price outside the permitted band",
+            RejectReason::FillOrKillUnfillable => "fill-or-kill order cannot be filled",
+            RejectReason::Expired => "order has expired",
+            RejectReason::InsufficientMargin => "insufficient margin",
+            RejectReason::MarketClosed => "market closed",
+            RejectReason::TradingHalted => "trading halted",
+            RejectReason::NotAllowedInAuction => "order type not accepted during an auction",
+            RejectReason::RateLimited => "rate limited",
+            RejectReason::Unauthorized => "not authorized for this user",
+            RejectReason::DuplicateOrderId => "duplicate order id",
+            RejectReason::Unavailable => "matching engine unavailable",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Execution {
+    /// Sequential per symbol and continued across restarts from the Oracle
+    /// log; `(symbol, trade_id)` identifies a trade.
+    pub trade_id: u64,
+    pub symbol: String,
     pub buy_order_id: u64,
     pub sell_order_id: u64,
+    pub buyer_user_id: u64,
+    pub seller_user_id: u64,
+    /// Side of the incoming order that took liquidity.
+    pub aggressor_side: Side,
     pub price: u64,
     pub quantity: u64,
     pub timestamp: u64,
+    /// Fee charged to the resting order's user, in quote asset units.
+    /// Negative values are rebates.
+    pub maker_fee: Decimal,
+    /// Fee charged to the aggressor's user, in quote asset units.
+    pub taker_fee: Decimal,
+}
+
+impl Execution {
+    pub fn maker_user_id(&self) -> u64 {
+        match self.aggressor_side {
+            Side::Buy => self.seller_user_id,
+            Side::Sell => self.buyer_user_id,
+        }
+    }
+
+    pub fn taker_user_id(&self) -> u64 {
+        match self.aggressor_side {
+            Side::Buy => self.buyer_user_id,
+            Side::Sell => self.seller_user_id,
+        }
+    }
+}
+
+/// A match between two orders of the same user that was prevented.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfTradeEvent {
+    pub user_id: u64,
+    pub symbol: String,
+    pub taker_order_id: u64,
+    pub maker_order_id: u64,
+    pub mode: SelfTradePrevention,
+    pub taker_quantity_cancelled: u64,
+    pub maker_quantity_cancelled: u64,
+    pub timestamp: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -100,11 +408,101 @@ pub struct LiquidationEvent {
 pub enum SystemEvent {
     OrderPlaced(Order),
     OrderExecuted(Execution),
+    OrderRejected {
+        order_id: u64,
+        user_id: u64,
+        symbol: String,
+        reason: RejectReason,
+        timestamp: u64,
+    },
+    OrderCancelled {
+        order_id: u64,
+        user_id: u64,
+        symbol: String,
+        remaining_quantity: u64,
+        timestamp: u64,
+    },
+    OrderReplaced {
+        order_id: u64,
+        user_id: u64,
+        symbol: String,
+        price: u64,
+        quantity: u64,
+        timestamp: u64,
+    },
+    OrderAmended {
+        order_id: u64,
+        user_id: u64,
+        symbol: String,
+        quantity: u64,
+        timestamp: u64,
+    },
+    SelfTradePrevented(SelfTradeEvent),
+    /// Price and volume the auction would uncross at if it ended now.
+    AuctionIndicative {
+        symbol: String,
+        price: Option<u64>,
+        volume: u64,
+        timestamp: u64,
+    },
+    AuctionUncrossed {
+        symbol: String,
+        price: u64,
+        volume: u64,
+        timestamp: u64,
+    },
+    SessionStateChanged {
+        symbol: String,
+        from: SessionState,
+        to: SessionState,
+        trigger: SessionTrigger,
+        timestamp: u64,
+    },
+    /// An administrator replaced the instrument's session timetable.
+    SessionScheduleChanged {
+        symbol: String,
+        schedule: SessionSchedule,
+        timestamp: u64,
+    },
+    /// An administrator replaced the instrument's price bands.
+    PriceBandsChanged {
+        symbol: String,
+        bands: PriceBands,
+        timestamp: u64,
+    },
+    /// An administrator changed how the instrument allocates fills within a
+    /// price level.
+    AllocationChanged {
+        symbol: String,
+        policy: AllocationPolicy,
+        timestamp: u64,
+    },
+    StopOrderPlaced(StopOrder),
+    StopOrderTriggered {
+        order_id: u64,
+        symbol: String,
+        trigger_price: u64,
+        timestamp: u64,
+    },
+    /// A trailing stop's stop price followed the market.
+    StopOrderTrailed {
+        order_id: u64,
+        symbol: String,
+        stop_price: u64,
+        timestamp: u64,
+    },
     PositionOpened {
         user_id: u64,
         position: Position,
         timestamp: u64,
     },
+    /// An open position changed size after a fill. A size of zero means it
+    /// was closed.
+    PositionUpdated {
+        user_id: u64,
+        position: Position,
+        timestamp: u64,
+    },
     PositionLiquidated(LiquidationEvent),
     PriceUpdate {
         symbol: String,
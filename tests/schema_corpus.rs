@@ -0,0 +1,154 @@
+//! Stored events from every schema version must keep decoding.
+//!
+//! `tests/corpus/v<N>.jsonl` holds events exactly as schema version `N`
+//! stored them, one per line; line `i` is stored under sequence `i`. Files
+//! are append-only: when `SCHEMA_VERSION` is bumped, add the new version's
+//! file and leave the old ones untouched.
+
+use std::fs;
+use std::path::PathBuf;
+
+use trading_systems::oracle::{EventEnvelope, OracleError, Producer, SCHEMA_VERSION, Upcasters};
+use trading_systems::types::SystemEvent;
+
+fn corpus(version: u32) -> Vec<(u64, String)> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/corpus")
+        .join(format!("v{version}.jsonl"));
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("no corpus for schema version {version} at {path:?}: {e}"));
+    (1..).zip(text.lines().map(str::to_string)).collect()
+}
+
+fn decode(version: u32) -> Vec<EventEnvelope> {
+    let upcasters = Upcasters::default();
+    corpus(version)
+        .into_iter()
+        .map(|(sequence, line)| {
+            upcasters
+                .decode(sequence, line.as_bytes())
+                .unwrap_or_else(|e| panic!("v{version} event {sequence}: {e}"))
+        })
+        .collect()
+}
+
+/// Matching is exhaustive, so a new variant stops this file compiling until
+/// it is listed here and counted in `VARIANTS`.
+fn variant(event: &SystemEvent) -> &'static str {
+    match event {
+        SystemEvent::OrderPlaced(_) => "OrderPlaced",
+        SystemEvent::OrderExecuted(_) => "OrderExecuted",
+        SystemEvent::OrderRejected { .. } => "OrderRejected",
+        SystemEvent::OrderCancelled { .. } => "OrderCancelled",
+        SystemEvent::OrderReplaced { .. } => "OrderReplaced",
+        SystemEvent::OrderAmended { .. } => "OrderAmended",
+        SystemEvent::SelfTradePrevented(_) => "SelfTradePrevented",
+        SystemEvent::AuctionIndicative { .. } => "AuctionIndicative",
+        SystemEvent::AuctionUncrossed { .. } => "AuctionUncrossed",
+        SystemEvent::SessionStateChanged { .. } => "SessionStateChanged",
+        SystemEvent::SessionScheduleChanged { .. } => "SessionScheduleChanged",
+        SystemEvent::PriceBandsChanged { .. } => "PriceBandsChanged",
+        SystemEvent::AllocationChanged { .. } => "AllocationChanged",
+        SystemEvent::StopOrderPlaced(_) => "StopOrderPlaced",
+        SystemEvent::StopOrderTriggered { .. } => "StopOrderTriggered",
+        SystemEvent::StopOrderTrailed { .. } => "StopOrderTrailed",
+        SystemEvent::PositionOpened { .. } => "PositionOpened",
+        SystemEvent::PositionUpdated { .. } => "PositionUpdated",
+        SystemEvent::PositionLiquidated(_) => "PositionLiquidated",
+        SystemEvent::PriceUpdate { .. } => "PriceUpdate",
+        SystemEvent::AccountUpdated { .. } => "AccountUpdated",
+    }
+}
+
+const VARIANTS: usize = 21;
+
+/// Events each corpus version appends for the variants it introduced, after
+/// restating the previous version's events.
+const ADDED: [usize; SCHEMA_VERSION as usize + 1] = [16, 0, 2, 3];
+
+#[test]
+fn every_version_decodes() {
+    for version in 0..=SCHEMA_VERSION {
+        for (envelope, sequence) in decode(version).iter().zip(1..) {
+            assert_eq!(envelope.sequence, sequence);
+            assert_eq!(envelope.schema_version, version);
+        }
+    }
+}
+
+#[test]
+fn current_corpus_covers_every_event() {
+    let mut seen: Vec<_> = decode(SCHEMA_VERSION)
+        .iter()
+        .map(|envelope| variant(&envelope.event))
+        .collect();
+    seen.sort_unstable();
+    seen.dedup();
+    assert_eq!(seen.len(), VARIANTS, "corpus covers only {seen:?}");
+}
+
+#[test]
+fn bare_events_are_enveloped() {
+    for envelope in decode(0) {
+        let producer = match envelope.event {
+            SystemEvent::PositionOpened { .. }
+            | SystemEvent::PositionLiquidated(_)
+            | SystemEvent::PriceUpdate { .. }
+            | SystemEvent::AccountUpdated { .. } => Producer::Sentinel,
+            _ => Producer::Titan,
+        };
+        assert_eq!(envelope.producer, producer);
+        assert_eq!(envelope.stream_sequence, 0);
+        assert_eq!(envelope.correlation_id, envelope.sequence);
+        assert_eq!(envelope.causation_id, None);
+    }
+}
+
+#[test]
+fn upcast_events_match_current_encoding() {
+    for version in 1..=SCHEMA_VERSION {
+        let old = decode(version - 1);
+        let current = decode(version);
+        assert_eq!(old.len() + ADDED[version as usize], current.len());
+        for (old, current) in old.iter().zip(&current) {
+            assert_eq!(
+                serde_json::to_value(&old.event).unwrap(),
+                serde_json::to_value(&current.event).unwrap(),
+            );
+        }
+    }
+}
+
+#[test]
+fn liquidation_traces_to_price_update() {
+    let events = decode(1);
+    let liquidation = events
+        .iter()
+        .find(|envelope| matches!(envelope.event, SystemEvent::PositionLiquidated(_)))
+        .unwrap();
+    let cause = &events[liquidation.causation_id.unwrap() as usize - 1];
+    assert!(matches!(cause.event, SystemEvent::PriceUpdate { .. }));
+    assert_eq!(liquidation.correlation_id, cause.sequence);
+}
+
+#[test]
+fn missing_upcaster_is_reported() {
+    let (sequence, line) = corpus(0).remove(0);
+    let result = Upcasters::empty().decode(sequence, line.as_bytes());
+    assert!(matches!(
+        result,
+        Err(OracleError::UnsupportedVersion { version: 0, .. })
+    ));
+}
+
+#[test]
+fn newer_versions_are_refused() {
+    let (sequence, line) = corpus(SCHEMA_VERSION).remove(0);
+    let mut value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    value["schema_version"] = (SCHEMA_VERSION + 1).into();
+    let result = Upcasters::default().decode(sequence, value.to_string().as_bytes());
+    assert!(matches!(
+        result,
+        Err(OracleError::UnsupportedVersion { version, .. }) if version == SCHEMA_VERSION + 1
+    ));
+}